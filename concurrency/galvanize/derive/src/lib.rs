@@ -0,0 +1,65 @@
+// `#[derive(PCM)]` for galvanize: a struct whose fields are all PCMs is
+// their product, composed field by field and valid when every field is.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Index};
+
+#[proc_macro_derive(PCM)]
+pub fn derive_pcm(input: TokenStream) -> TokenStream {
+  let input = parse_macro_input!(input as DeriveInput);
+  let fields = match &input.data {
+    Data::Struct(data) => &data.fields,
+    _ => {
+      return syn::Error::new(Span::call_site(), "PCM can only be derived for structs")
+        .to_compile_error()
+        .into();
+    }
+  };
+
+  let name = &input.ident;
+  let mut generics = input.generics.clone();
+  let (_, ty_generics, _) = input.generics.split_for_impl();
+  {
+    // Every field must be a PCM, and the struct itself comparable and
+    // printable (the derives the user wrote only hold under their bounds)
+    let clauses = &mut generics.make_where_clause().predicates;
+    clauses.push(parse_quote!(
+      #name #ty_generics: ::core::clone::Clone + ::core::cmp::PartialEq + ::core::fmt::Debug
+    ));
+    for field in fields.iter() {
+      let ty = &field.ty;
+      clauses.push(parse_quote!(#ty: ::galvanize::PCM));
+    }
+  }
+  let (impl_generics, _, where_clause) = generics.split_for_impl();
+
+  let members: Vec<_> = match fields {
+    Fields::Named(named) => named.named.iter().map(|f| {
+      let ident = f.ident.as_ref().unwrap();
+      quote!(#ident)
+    }).collect(),
+    _ => (0..fields.len()).map(|i| {
+      let index = Index::from(i);
+      quote!(#index)
+    }).collect(),
+  };
+
+  let expanded = quote! {
+    impl #impl_generics ::galvanize::PCM for #name #ty_generics #where_clause {
+      fn unit() -> Self {
+        #name { #( #members: ::galvanize::PCM::unit(), )* }
+      }
+
+      fn op(&self, other: &Self) -> Self {
+        #name { #( #members: ::galvanize::PCM::op(&self.#members, &other.#members), )* }
+      }
+
+      fn valid(&self) -> bool {
+        true #( && ::galvanize::PCM::valid(&self.#members) )*
+      }
+    }
+  };
+  expanded.into()
+}
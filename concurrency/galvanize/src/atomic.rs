@@ -0,0 +1,106 @@
+// Atomics that own ghost state, tied to their value by an invariant.
+//
+// Operations other than a plain `load` take the token along with the
+// value and hand both to ghost code, which runs as part of the same
+// atomic step and gives a token back (the `atomic!` blocks of the
+// sketch). In debug builds the invariant is checked against the new
+// value after every one of them.
+//
+// The token sits behind a mutex held for the whole operation, so these
+// are for checking protocols, not for speed.
+
+use std::sync::atomic as std_atomic;
+use std::sync::Mutex;
+
+pub use std::sync::atomic::Ordering;
+
+use crate::pcm::PCM;
+use crate::token::Token;
+
+macro_rules! atomic {
+  ($name:ident, $ty:ty) => {
+    pub struct $name<P: PCM> {
+      value: std_atomic::$name,
+      // `None` only while ghost code runs
+      ghost: Mutex<Option<Token<P>>>,
+      inv: fn($ty, &Token<P>) -> bool,
+    }
+
+    impl<P: PCM> $name<P> {
+      pub fn new(value: $ty, token: Token<P>, inv: fn($ty, &Token<P>) -> bool) -> $name<P> {
+        assert!(inv(value, &token), "{:?} doesn't match {:?}", value, token);
+        $name{ value: std_atomic::$name::new(value), ghost: Mutex::new(Some(token)), inv }
+      }
+
+      // Reads without touching the ghost state.
+      pub fn load(&self, order: Ordering) -> $ty {
+        self.value.load(order)
+      }
+
+      pub fn load_with<G>(&self, order: Ordering,
+        ghost: impl FnOnce($ty, Token<P>) -> (Token<P>, G)) -> ($ty, G)
+      {
+        self.with_ghost(|token| {
+          let value = self.value.load(order);
+          let (token, g) = ghost(value, token);
+          (token, (value, g))
+        })
+      }
+
+      // The ghost code gets the old and the new value.
+      pub fn store_with<G>(&self, value: $ty, order: Ordering,
+        ghost: impl FnOnce($ty, $ty, Token<P>) -> (Token<P>, G)) -> G
+      {
+        self.with_ghost(|token| ghost(self.value.swap(value, order), value, token))
+      }
+
+      pub fn compare_exchange_with<G>(&self, current: $ty, new: $ty, success: Ordering, failure: Ordering,
+        ghost: impl FnOnce(Result<$ty, $ty>, Token<P>) -> (Token<P>, G)) -> (Result<$ty, $ty>, G)
+      {
+        self.with_ghost(|token| {
+          let res = self.value.compare_exchange(current, new, success, failure);
+          let (token, g) = ghost(res, token);
+          (token, (res, g))
+        })
+      }
+
+      fn with_ghost<R>(&self, f: impl FnOnce(Token<P>) -> (Token<P>, R)) -> R {
+        let mut slot = self.ghost.lock().expect("a ghost step panicked");
+        let (token, r) = f(slot.take().unwrap());
+        if cfg!(debug_assertions) {
+          // Only changed under `ghost`, so this is the value we left
+          let value = self.value.load(Ordering::Relaxed);
+          assert!((self.inv)(value, &token), "{:?} doesn't match {:?}", value, token);
+        }
+        *slot = Some(token);
+        r
+      }
+    }
+  };
+}
+
+atomic!(AtomicBool, bool);
+atomic!(AtomicU32, u32);
+
+impl<P: PCM> AtomicU32<P> {
+  // The ghost code gets the old and the new value.
+  pub fn fetch_add_with<G>(&self, n: u32, order: Ordering,
+    ghost: impl FnOnce(u32, u32, Token<P>) -> (Token<P>, G)) -> (u32, G)
+  {
+    self.with_ghost(|token| {
+      let old = self.value.fetch_add(n, order);
+      let (token, g) = ghost(old, old.wrapping_add(n), token);
+      (token, (old, g))
+    })
+  }
+
+  pub fn fetch_sub_with<G>(&self, n: u32, order: Ordering,
+    ghost: impl FnOnce(u32, u32, Token<P>) -> (Token<P>, G)) -> (u32, G)
+  {
+    self.with_ghost(|token| {
+      let old = self.value.fetch_sub(n, order);
+      let (token, g) = ghost(old, old.wrapping_sub(n), token);
+      (token, (old, g))
+    })
+  }
+}
@@ -0,0 +1,60 @@
+// A replacement for `UnsafeCell` that isn't unsafe: borrowing its
+// contents takes the permission token `VerifiedCell::new` hands out (or a
+// shared borrow of it), and panics on any other token.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::pcm::Excl;
+use crate::token::{Loc, Shared, Token};
+
+// The ghost value of a cell. It only names the cell, the contents aren't
+// tracked.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct VerifiedCellValue(u64);
+
+pub struct VerifiedCell<T> {
+  value: UnsafeCell<T>,
+  id: VerifiedCellValue,
+  // Instance of the permission, a token holding `id` from anywhere else
+  // is forged
+  loc: Loc,
+}
+
+unsafe impl<T: Send> Send for VerifiedCell<T> {}
+unsafe impl<T: Send + Sync> Sync for VerifiedCell<T> {}
+
+impl<T> VerifiedCell<T> {
+  pub fn new(t: T) -> (VerifiedCell<T>, Token<Excl<VerifiedCellValue>>) {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let id = VerifiedCellValue(NEXT.fetch_add(1, Ordering::Relaxed));
+    let perm = Token::new(Excl::Own(id));
+    (VerifiedCell{ value: UnsafeCell::new(t), id, loc: perm.loc() }, perm)
+  }
+
+  pub fn id(&self) -> VerifiedCellValue {
+    self.id
+  }
+
+  pub fn borrow<'a>(&'a self, perm: Shared<'a, Excl<VerifiedCellValue>>) -> &'a T {
+    self.check(perm.loc(), perm.value());
+    // A shared borrow of the permission means nobody owns it
+    unsafe { &*self.value.get() }
+  }
+
+  #[allow(clippy::mut_from_ref)]
+  pub fn borrow_mut<'a>(&'a self, perm: &'a mut Token<Excl<VerifiedCellValue>>) -> &'a mut T {
+    self.check(perm.loc(), perm.value());
+    // Tokens are never duplicated, so we have the only one
+    unsafe { &mut *self.value.get() }
+  }
+
+  pub fn into_inner(self) -> T {
+    self.value.into_inner()
+  }
+
+  fn check(&self, loc: Loc, perm: &Excl<VerifiedCellValue>) {
+    assert!(loc == self.loc && *perm == Excl::Own(self.id),
+      "{:?} at {:?} isn't the permission of {:?}", perm, loc, self.id);
+  }
+}
@@ -0,0 +1,21 @@
+// A runtime version of the ghost-state library rwlock_verified.rs (in
+// concurrency/rwlock) is written against: PCMs and their extensions,
+// ghost tokens, and atomics and cells guarded by them.
+//
+// Nothing here is verified. Tokens are real values and each step a
+// verifier would prove sound is checked as it runs instead, so a broken
+// protocol panics on an execution that breaks it. Token steps are always
+// checked (`VerifiedCell` relies on them), the invariants of atomics and
+// stores only in debug builds.
+
+pub mod atomic;
+mod cell;
+mod pcm;
+mod store;
+mod token;
+
+pub use cell::{VerifiedCell, VerifiedCellValue};
+pub use galvanize_derive::PCM;
+pub use pcm::{Excl, Multiset, PCM};
+pub use store::GhostTokenStore;
+pub use token::{Loc, PCMExtension, Shared, Token};
@@ -0,0 +1,135 @@
+// Partial commutative monoids, the algebra ghost state lives in.
+//
+// `op` is total here: composing two things that can't coexist (two
+// exclusive owners) gives an element that isn't `valid`, which is where
+// a verifier would have rejected the program.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+
+pub trait PCM: Clone + PartialEq + Debug {
+  // The identity of `op`, owning it tells you nothing.
+  fn unit() -> Self;
+
+  // Must be associative and commutative.
+  fn op(&self, other: &Self) -> Self;
+
+  fn valid(&self) -> bool;
+}
+
+impl PCM for () {
+  fn unit() {}
+
+  fn op(&self, _other: &()) {}
+
+  fn valid(&self) -> bool {
+    true
+  }
+}
+
+// Counts that add up, like the fungible handles in RWLock.dfy (`nat`).
+impl PCM for u64 {
+  fn unit() -> u64 {
+    0
+  }
+
+  fn op(&self, other: &u64) -> u64 {
+    self + other
+  }
+
+  fn valid(&self) -> bool {
+    true
+  }
+}
+
+// At most one owner, what `Option` fields are in the Dafny extensions:
+// `Own` composes with nothing but `Unit`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Excl<T> {
+  Unit,
+  Own(T),
+  // Two owners, never valid
+  Conflict,
+}
+
+impl<T> Excl<T> {
+  pub fn own(&self) -> Option<&T> {
+    match self {
+      Excl::Own(t) => Some(t),
+      _ => None,
+    }
+  }
+}
+
+impl<T: Clone + PartialEq + Debug> PCM for Excl<T> {
+  fn unit() -> Excl<T> {
+    Excl::Unit
+  }
+
+  fn op(&self, other: &Excl<T>) -> Excl<T> {
+    match (self, other) {
+      (Excl::Unit, x) | (x, Excl::Unit) => x.clone(),
+      _ => Excl::Conflict,
+    }
+  }
+
+  fn valid(&self) -> bool {
+    *self != Excl::Conflict
+  }
+}
+
+// Counts per key that add up, the `Base -> nat` functions of RWLock.dfy.
+// Keys with a count of 0 are left out so equal multisets compare equal.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Multiset<K: Ord>(BTreeMap<K, u64>);
+
+impl<K: Ord + Clone> Multiset<K> {
+  pub fn new() -> Multiset<K> {
+    Multiset(BTreeMap::new())
+  }
+
+  pub fn singleton(k: K) -> Multiset<K> {
+    Multiset(BTreeMap::from([(k, 1)]))
+  }
+
+  pub fn count(&self, k: &K) -> u64 {
+    self.0.get(k).copied().unwrap_or(0)
+  }
+
+  pub fn len(&self) -> u64 {
+    self.0.values().sum()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  // Every key with its count.
+  pub fn iter(&self) -> impl Iterator<Item = (&K, u64)> {
+    self.0.iter().map(|(k, n)| (k, *n))
+  }
+}
+
+impl<K: Ord + Clone> Default for Multiset<K> {
+  fn default() -> Multiset<K> {
+    Multiset::new()
+  }
+}
+
+impl<K: Ord + Clone + Debug> PCM for Multiset<K> {
+  fn unit() -> Multiset<K> {
+    Multiset::new()
+  }
+
+  fn op(&self, other: &Multiset<K>) -> Multiset<K> {
+    let mut sum = self.0.clone();
+    for (k, n) in other.0.iter() {
+      *sum.entry(k.clone()).or_insert(0) += n;
+    }
+    Multiset(sum)
+  }
+
+  fn valid(&self) -> bool {
+    true
+  }
+}
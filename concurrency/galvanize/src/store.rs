@@ -0,0 +1,32 @@
+// Ghost state shared between threads behind an invariant, like the
+// `central_token` of the sketch: whoever opens the store has its token
+// for the duration of `open`, and must put back one that satisfies the
+// invariant (checked in debug builds).
+
+use std::sync::Mutex;
+
+use crate::pcm::PCM;
+use crate::token::Token;
+
+pub struct GhostTokenStore<P: PCM> {
+  // `None` only while open
+  token: Mutex<Option<Token<P>>>,
+  inv: fn(&Token<P>) -> bool,
+}
+
+impl<P: PCM> GhostTokenStore<P> {
+  pub fn new(token: Token<P>, inv: fn(&Token<P>) -> bool) -> GhostTokenStore<P> {
+    assert!(inv(&token), "{:?} breaks the invariant", token);
+    GhostTokenStore{ token: Mutex::new(Some(token)), inv }
+  }
+
+  pub fn open<G>(&self, ghost: impl FnOnce(Token<P>) -> (Token<P>, G)) -> G {
+    let mut slot = self.token.lock().expect("a ghost step panicked");
+    let (token, g) = ghost(slot.take().unwrap());
+    if cfg!(debug_assertions) {
+      assert!((self.inv)(&token), "{:?} breaks the invariant", token);
+    }
+    *slot = Some(token);
+    g
+  }
+}
@@ -0,0 +1,256 @@
+// Ghost tokens, owned pieces of the state of a PCM instance.
+//
+// A verifier tracks tokens statically and erases them; here they are
+// values. Every instance remembers what each of its live tokens holds, so
+// a step can be checked against everything else in the instance (the
+// frame `p` of the `_preserves` lemmas in RWLock.dfy) when it runs.
+//
+// Dropping a token doesn't take it out of its instance: ghost state can
+// be forgotten but it still counts, a leaked reader handle keeps writers
+// out forever.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::pcm::PCM;
+
+// A PCM whose instances stand for some state of a base PCM, and move it
+// in and out of their tokens (the monoid extensions of the Dafny side).
+pub trait PCMExtension: PCM + Send + 'static {
+  type Base: PCM + Send + 'static;
+
+  // Holds for the whole state of an instance (`Inv`).
+  fn invariant(&self) -> bool;
+
+  // The base state the whole state stands for (`Interp`), only asked
+  // when `invariant` holds.
+  fn interp(&self) -> Self::Base;
+
+  // The base state owning `self` lets you borrow shared, what
+  // `borrow_shared_handle` proves for reader handles.
+  fn lends(&self) -> Option<Self::Base> {
+    None
+  }
+}
+
+// Names an instance.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Loc(u64);
+
+impl Loc {
+  fn fresh() -> Loc {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    Loc(NEXT.fetch_add(1, Ordering::Relaxed))
+  }
+}
+
+struct Instance<P> {
+  loc: Loc,
+  tokens: Mutex<Tokens<P>>,
+  // The `Arc<Instance<P::Base>>` an extension instance extends
+  base: Option<Box<dyn Any + Send + Sync>>,
+}
+
+struct Tokens<P> {
+  next: u64,
+  live: BTreeMap<u64, P>,
+}
+
+impl<P: PCM> Instance<P> {
+  fn new(base: Option<Box<dyn Any + Send + Sync>>) -> Arc<Instance<P>> {
+    Arc::new(Instance{
+      loc: Loc::fresh(),
+      tokens: Mutex::new(Tokens{ next: 0, live: BTreeMap::new() }),
+      base,
+    })
+  }
+
+  fn tokens(&self) -> MutexGuard<'_, Tokens<P>> {
+    self.tokens.lock().expect("a ghost step panicked")
+  }
+
+  fn mint(self: &Arc<Instance<P>>, tokens: &mut Tokens<P>, value: P) -> Token<P> {
+    let id = tokens.next;
+    tokens.next += 1;
+    tokens.live.insert(id, value.clone());
+    Token{ inst: self.clone(), id, value }
+  }
+}
+
+impl<P: PCM> Tokens<P> {
+  // Everything in the instance but token `id`.
+  fn frame(&self, id: u64) -> P {
+    self.live.iter().filter(|(i, _)| **i != id).fold(P::unit(), |acc, (_, v)| acc.op(v))
+  }
+
+  fn total(&self) -> P {
+    self.live.values().fold(P::unit(), |acc, v| acc.op(v))
+  }
+}
+
+pub struct Token<P: PCM> {
+  inst: Arc<Instance<P>>,
+  id: u64,
+  value: P,
+}
+
+// A shared borrow of some token's value, good for as long as that token
+// (or the one lending it) is borrowed.
+pub struct Shared<'a, P> {
+  loc: Loc,
+  value: P,
+  token: PhantomData<&'a ()>,
+}
+
+impl<'a, P> Shared<'a, P> {
+  pub fn loc(&self) -> Loc {
+    self.loc
+  }
+
+  pub fn value(&self) -> &P {
+    &self.value
+  }
+}
+
+impl<P: PCM> Token<P> {
+  // A fresh instance, all of which is in the returned token.
+  pub fn new(value: P) -> Token<P> {
+    assert!(value.valid(), "{:?} isn't valid", value);
+    let inst = Instance::new(None);
+    let mut tokens = inst.tokens();
+    inst.mint(&mut tokens, value)
+  }
+
+  pub fn loc(&self) -> Loc {
+    self.inst.loc
+  }
+
+  pub fn value(&self) -> &P {
+    &self.value
+  }
+
+  pub fn share(&self) -> Shared<'_, P> {
+    Shared{ loc: self.loc(), value: self.value.clone(), token: PhantomData }
+  }
+
+  // Splits into tokens holding `a` and `b`, which must add up to what
+  // this one holds.
+  pub fn split(self, a: P, b: P) -> (Token<P>, Token<P>) {
+    assert_eq!(a.op(&b), self.value, "split doesn't add up");
+    let mut tokens = self.inst.tokens();
+    tokens.live.remove(&self.id);
+    let a = self.inst.mint(&mut tokens, a);
+    let b = self.inst.mint(&mut tokens, b);
+    (a, b)
+  }
+
+  pub fn join(self, other: Token<P>) -> Token<P> {
+    assert_eq!(self.loc(), other.loc(), "joining tokens of different instances");
+    let value = self.value.op(&other.value);
+    assert!(value.valid(), "{:?} and {:?} can't both exist", self.value, other.value);
+    let mut tokens = self.inst.tokens();
+    tokens.live.remove(&other.id);
+    tokens.live.insert(self.id, value.clone());
+    drop(tokens);
+    Token{ value, ..self }
+  }
+}
+
+// What a step moves between an extension instance and its base.
+enum Moved<'a, B> {
+  Nothing,
+  Out(&'a B),
+  In(&'a B),
+}
+
+impl<P: PCMExtension> Token<P> {
+  // Puts `base` into a fresh instance, in state `init`.
+  pub fn extend(base: Token<P::Base>, init: P) -> Token<P> {
+    assert!(init.valid() && init.invariant(), "{:?} breaks the invariant", init);
+    assert_eq!(init.interp(), base.value, "{:?} doesn't stand for the base", init);
+    base.inst.tokens().live.remove(&base.id);
+    let inst = Instance::new(Some(Box::new(base.inst)));
+    let mut tokens = inst.tokens();
+    inst.mint(&mut tokens, init)
+  }
+
+  // Changes what this token holds to `to`, without touching the base.
+  pub fn update(mut self, to: P) -> Token<P> {
+    self.step(to, Moved::Nothing);
+    self
+  }
+
+  // Changes what this token holds to `to`, taking `base` out.
+  pub fn withdraw(mut self, to: P, base: P::Base) -> (Token<P>, Token<P::Base>) {
+    self.step(to, Moved::Out(&base));
+    let inst = self.base();
+    let mut tokens = inst.tokens();
+    let base = inst.mint(&mut tokens, base);
+    (self, base)
+  }
+
+  // Changes what this token holds to `to`, putting `base` in.
+  pub fn deposit(mut self, base: Token<P::Base>, to: P) -> Token<P> {
+    assert_eq!(base.loc(), self.base().loc, "depositing into the wrong instance");
+    self.step(to, Moved::In(&base.value));
+    base.inst.tokens().live.remove(&base.id);
+    self
+  }
+
+  // The base state this token lends.
+  pub fn borrow(&self) -> Shared<'_, P::Base> {
+    let value = match self.value.lends() {
+      Some(value) => value,
+      None => panic!("{:?} lends nothing", self.value),
+    };
+    let inst = self.base();
+    let outside = inst.tokens().total();
+    assert!(outside.op(&value).valid(), "{:?} is lent and owned by someone at once", value);
+    Shared{ loc: inst.loc, value, token: PhantomData }
+  }
+
+  fn base(&self) -> Arc<Instance<P::Base>> {
+    match self.inst.base.as_ref().and_then(|b| b.downcast_ref::<Arc<Instance<P::Base>>>()) {
+      Some(inst) => inst.clone(),
+      None => panic!("{:?} doesn't extend anything", self.loc()),
+    }
+  }
+
+  // Checks `self.value -> to` keeps the invariant given everything else
+  // in the instance, and moves exactly `moved` in or out of the base.
+  fn step(&mut self, to: P, moved: Moved<P::Base>) {
+    let mut tokens = self.inst.tokens();
+    let frame = tokens.frame(self.id);
+    let before = frame.op(&self.value);
+    let after = frame.op(&to);
+    assert!(after.valid() && after.invariant(),
+      "{:?} -> {:?} breaks the invariant, leaving {:?}", self.value, to, after);
+    let (old, new) = (before.interp(), after.interp());
+    match moved {
+      Moved::Nothing => assert_eq!(old, new, "{:?} -> {:?} changes the base", self.value, to),
+      Moved::In(base) => assert_eq!(old.op(base), new, "{:?} -> {:?} doesn't take in {:?}", self.value, to, base),
+      Moved::Out(base) => {
+        assert_eq!(old, new.op(base), "{:?} -> {:?} doesn't give out {:?}", self.value, to, base);
+        // Nobody may own what someone else borrows
+        let lenders = tokens.live.iter().filter(|(i, _)| **i != self.id).map(|(_, v)| v);
+        for lender in lenders.chain(Some(&to)) {
+          if let Some(lent) = lender.lends() {
+            assert!(lent.op(base).valid(), "giving out {:?} lent to {:?}", base, lender);
+          }
+        }
+      }
+    }
+    tokens.live.insert(self.id, to.clone());
+    self.value = to;
+  }
+}
+
+impl<P: PCM> fmt::Debug for Token<P> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Token").field("loc", &self.loc()).field("value", &self.value).finish()
+  }
+}
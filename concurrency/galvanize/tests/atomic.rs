@@ -0,0 +1,107 @@
+use std::sync::Arc;
+use std::thread;
+
+use galvanize::atomic::{AtomicBool, AtomicU32, Ordering};
+use galvanize::{Excl, GhostTokenStore, PCMExtension, Token, PCM};
+
+// Ghost copy of an atomic's value
+#[derive(PCM, Clone, PartialEq, Debug)]
+struct Value(Excl<u32>);
+
+impl PCMExtension for Value {
+  type Base = ();
+
+  fn invariant(&self) -> bool {
+    matches!(self.0, Excl::Own(_))
+  }
+
+  fn interp(&self) {}
+}
+
+fn value(n: u32) -> Token<Value> {
+  Token::extend(Token::new(()), Value(Excl::Own(n)))
+}
+
+fn set(t: Token<Value>, n: u32) -> Token<Value> {
+  t.update(Value(Excl::Own(n)))
+}
+
+fn value_inv(n: u32, t: &Token<Value>) -> bool {
+  t.value().0 == Excl::Own(n)
+}
+
+fn flag_inv(b: bool, t: &Token<Value>) -> bool {
+  t.value().0 == Excl::Own(b as u32)
+}
+
+#[test]
+fn ghost_follows_value() {
+  let counter = Arc::new(AtomicU32::new(0, value(0), value_inv));
+  let threads: Vec<_> = (0..4).map(|_| {
+    let counter = counter.clone();
+    thread::spawn(move || {
+      for _ in 0..100 {
+        counter.fetch_add_with(1, Ordering::SeqCst, |_, new, t| (set(t, new), ()));
+      }
+    })
+  }).collect();
+  for t in threads {
+    t.join().unwrap();
+  }
+  let (n, seen) = counter.load_with(Ordering::SeqCst, |_, t| {
+    let seen = t.value().clone();
+    (t, seen)
+  });
+  assert_eq!((n, seen), (400, Value(Excl::Own(400))));
+  counter.fetch_sub_with(400, Ordering::SeqCst, |_, new, t| (set(t, new), ()));
+  assert_eq!(counter.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn compare_exchange() {
+  let flag = AtomicBool::new(false, value(0), flag_inv);
+  let (res, ()) = flag.compare_exchange_with(false, true, Ordering::SeqCst, Ordering::SeqCst, |res, t| {
+    assert_eq!(res, Ok(false));
+    (set(t, 1), ())
+  });
+  assert_eq!(res, Ok(false));
+  let (res, ()) = flag.compare_exchange_with(false, true, Ordering::SeqCst, Ordering::SeqCst, |_, t| (t, ()));
+  assert_eq!(res, Err(true));
+  flag.store_with(false, Ordering::SeqCst, |old, new, t| {
+    assert_eq!((old, new), (true, false));
+    (set(t, 0), ())
+  });
+  assert!(!flag.load(Ordering::SeqCst));
+}
+
+#[test]
+#[cfg_attr(debug_assertions, should_panic(expected = "doesn't match"))]
+fn invariant_checked() {
+  let counter = AtomicU32::new(0, value(0), value_inv);
+  // Forgets to account for the increment
+  counter.fetch_add_with(1, Ordering::SeqCst, |_, _, t| (t, ()));
+}
+
+#[test]
+#[should_panic(expected = "doesn't match")]
+fn invariant_checked_on_creation() {
+  AtomicU32::new(1, value(0), value_inv);
+}
+
+#[test]
+fn store() {
+  let store = GhostTokenStore::new(Token::new(2u64), |t| *t.value() % 2 == 0);
+  let seen = store.open(|t| {
+    let (a, b) = t.split(1, 1);
+    let seen = *a.value();
+    (a.join(b), seen)
+  });
+  assert_eq!(seen, 1);
+}
+
+#[test]
+#[cfg_attr(debug_assertions, should_panic(expected = "breaks the invariant"))]
+fn store_invariant_checked() {
+  let store = GhostTokenStore::new(Token::new(2u64), |t| *t.value() % 2 == 0);
+  store.open(|_| (Token::new(3), ()));
+}
@@ -0,0 +1,57 @@
+use galvanize::{Excl, Multiset, PCM};
+
+#[derive(PCM, Clone, PartialEq, Debug)]
+struct Handles<K: Ord> {
+  central: Excl<bool>,
+  pending: u64,
+  taken: Multiset<K>,
+}
+
+#[derive(PCM, Clone, PartialEq, Debug)]
+struct Pair(Excl<u32>, u64);
+
+fn handles(central: Option<bool>, pending: u64, taken: &[u32]) -> Handles<u32> {
+  let mut m = Handles{ central: central.map_or(Excl::Unit, Excl::Own), ..PCM::unit() };
+  m.pending = pending;
+  for k in taken {
+    m.taken = m.taken.op(&Multiset::singleton(*k));
+  }
+  m
+}
+
+#[test]
+fn excl() {
+  let own = Excl::Own(1);
+  assert_eq!(own.op(&Excl::Unit), own);
+  assert_eq!(Excl::Unit.op(&own), own);
+  assert!(!own.op(&own).valid());
+  assert!(!own.op(&Excl::Own(2)).op(&Excl::Unit).valid());
+  assert_eq!(own.own(), Some(&1));
+}
+
+#[test]
+fn multiset() {
+  let m = Multiset::singleton('a').op(&Multiset::singleton('b')).op(&Multiset::singleton('a'));
+  assert_eq!(m.count(&'a'), 2);
+  assert_eq!(m.count(&'c'), 0);
+  assert_eq!(m.len(), 3);
+  assert_eq!(m, Multiset::singleton('b').op(&Multiset::singleton('a')).op(&Multiset::singleton('a')));
+  assert!(Multiset::<char>::unit().is_empty());
+}
+
+#[test]
+fn derived_product() {
+  let a = handles(Some(false), 1, &[7]);
+  let b = handles(None, 2, &[7, 8]);
+  assert_eq!(a.op(&b), handles(Some(false), 3, &[7, 7, 8]));
+  assert_eq!(a.op(&b), b.op(&a));
+  assert_eq!(a.op(&Handles::unit()), a);
+  assert!(a.op(&b).valid());
+  // Two centrals
+  assert!(!a.op(&a).valid());
+
+  let p = Pair(Excl::Own(1), 1);
+  assert_eq!(p.op(&Pair::unit()), p);
+  assert_eq!(p.op(&Pair(Excl::Unit, 2)), Pair(Excl::Own(1), 3));
+  assert!(!p.op(&p).valid());
+}
@@ -0,0 +1,149 @@
+use galvanize::{Excl, Multiset, PCMExtension, Token, VerifiedCell, PCM};
+
+// A mutex around a base `Excl<u32>`, whose invariant doesn't forbid
+// lending out a value that was taken, so the checks of the library itself
+// are what catches that.
+#[derive(PCM, Clone, PartialEq, Debug)]
+struct Mutex {
+  // What's checked in, `None` while held
+  central: Excl<Option<u32>>,
+  held: Excl<()>,
+  lent: Multiset<u32>,
+}
+
+impl PCMExtension for Mutex {
+  type Base = Excl<u32>;
+
+  fn invariant(&self) -> bool {
+    match &self.central {
+      Excl::Own(v) => v.is_none() == (self.held == Excl::Own(())),
+      _ => false,
+    }
+  }
+
+  fn interp(&self) -> Excl<u32> {
+    match self.central {
+      Excl::Own(Some(v)) => Excl::Own(v),
+      _ => Excl::Unit,
+    }
+  }
+
+  fn lends(&self) -> Option<Excl<u32>> {
+    self.lent.iter().next().map(|(v, _)| Excl::Own(*v))
+  }
+}
+
+fn central(v: Option<u32>) -> Mutex {
+  Mutex{ central: Excl::Own(v), ..PCM::unit() }
+}
+
+fn held() -> Mutex {
+  Mutex{ held: Excl::Own(()), ..PCM::unit() }
+}
+
+fn lent(v: u32) -> Mutex {
+  Mutex{ lent: Multiset::singleton(v), ..PCM::unit() }
+}
+
+fn mutex() -> Token<Mutex> {
+  Token::extend(Token::new(Excl::Own(5)), central(Some(5)))
+}
+
+fn acquire(central_token: Token<Mutex>) -> (Token<Mutex>, Token<Excl<u32>>) {
+  let (t, base) = central_token.withdraw(central(None).op(&held()), Excl::Own(5));
+  (t, base)
+}
+
+#[test]
+fn split_join() {
+  let t = Token::new(7u64);
+  let (a, b) = t.split(3, 4);
+  assert_eq!(a.loc(), b.loc());
+  assert_eq!(*a.join(b).value(), 7);
+}
+
+#[test]
+#[should_panic(expected = "split doesn't add up")]
+fn split_adds_up() {
+  Token::new(7u64).split(3, 3);
+}
+
+#[test]
+#[should_panic(expected = "different instances")]
+fn join_one_instance() {
+  Token::new(1u64).join(Token::new(1u64));
+}
+
+#[test]
+fn withdraw_deposit() {
+  let t = mutex();
+  let (t, base) = acquire(t);
+  assert_eq!(*base.value(), Excl::Own(5));
+  let (t, handle) = t.split(central(None), held());
+  let t = t.join(handle).deposit(base, central(Some(5)));
+  assert_eq!(*t.value(), central(Some(5)));
+  // And again
+  acquire(t);
+}
+
+#[test]
+#[should_panic(expected = "doesn't give out")]
+fn withdraw_twice() {
+  let (t, _base) = acquire(mutex());
+  t.withdraw(central(None).op(&held()), Excl::Own(5));
+}
+
+#[test]
+#[should_panic(expected = "breaks the invariant")]
+fn update_keeps_invariant() {
+  let (t, _handle) = mutex().split(central(Some(5)), Mutex::unit());
+  t.update(central(None));
+}
+
+#[test]
+#[should_panic(expected = "changes the base")]
+fn update_keeps_base() {
+  let (t, _base) = acquire(mutex());
+  let (t, handle) = t.split(central(None), held());
+  t.join(handle).update(central(Some(5)));
+}
+
+#[test]
+#[should_panic(expected = "lent to")]
+fn withdraw_lent() {
+  let t = mutex().update(central(Some(5)).op(&lent(5)));
+  let (t, reader) = t.split(central(Some(5)), lent(5));
+  assert_eq!(*reader.borrow().value(), Excl::Own(5));
+  acquire(t);
+}
+
+#[test]
+#[should_panic(expected = "owned by someone")]
+fn borrow_withdrawn() {
+  let (t, _base) = acquire(mutex());
+  let (_t, reader) = t.update(central(None).op(&held()).op(&lent(5))).split(central(None).op(&held()), lent(5));
+  reader.borrow();
+}
+
+#[test]
+fn cell() {
+  let (cell, mut perm) = VerifiedCell::new(1);
+  *cell.borrow_mut(&mut perm) += 1;
+  assert_eq!(*cell.borrow(perm.share()), 2);
+}
+
+#[test]
+#[should_panic(expected = "isn't the permission")]
+fn cell_other_permission() {
+  let (cell, _) = VerifiedCell::new(1);
+  let (_, mut perm) = VerifiedCell::new(1);
+  cell.borrow_mut(&mut perm);
+}
+
+#[test]
+#[should_panic(expected = "isn't the permission")]
+fn cell_forged_permission() {
+  let (cell, _) = VerifiedCell::new(1);
+  let mut perm = Token::new(Excl::Own(cell.id()));
+  cell.borrow_mut(&mut perm);
+}
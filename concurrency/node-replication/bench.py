@@ -33,8 +33,8 @@ CORES_PER_NODE = count_cores_per_numa_node()
 NODES = count_numa_nodes()
 MAX_THREADS = NODES * CORES_PER_NODE
 
-NR_BENCHES = ['dafny_nr', 'rust_nr']
-OTHER_BENCHES = ['dafny_rwlock', 'shfllock', 'mcs', 'cpp_shared_mutex']
+NR_BENCHES = ['dafny_nr', 'rust_nr', 'rust_nr_partitioned']
+OTHER_BENCHES = ['dafny_rwlock', 'shfllock', 'mcs', 'cpp_shared_mutex', 'rust_rwlock', 'rust_numa_rwlock']
 #READS_PCT = [100, 95, 50, 0, 90]
 READS_PCT = [100, 90, 0]
 
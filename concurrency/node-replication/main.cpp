@@ -468,7 +468,7 @@ struct dafny_nr_monitor{
 // - Rust NR Benchmarking -
 
 struct rust_nr_monitor{
-  nr_rust_helper helper;
+  nr_rust_helper<rust_nr_single_log> helper;
 
   rust_nr_monitor(size_t n_threads)
     : helper{n_threads}
@@ -482,21 +482,44 @@ struct rust_nr_monitor{
 
   uint64_t read(uint8_t thread_id, uint32_t core_id, void* context, uint64_t key) {
     size_t replica_token = (size_t) context;
-#if USE_COUNTER
-    #error "NYI"
-#else
-    helper.get_node(core_id)->ReplicaResolve(replica_token, key);
-#endif
-    return 0;
+    return helper.get_node(core_id)->execute(replica_token, key, 0);
   }
 
   void update(uint8_t thread_id, uint32_t core_id, void* context, uint64_t key, uint64_t value) {
     size_t replica_token = (size_t)context;
-#if USE_COUNTER
-  #error "NYI"
-#else
-    bool ok = helper.get_node(core_id)->ReplicaMap(replica_token, key, value);
-#endif
+    helper.get_node(core_id)->execute_mut(replica_token, key, value);
+  }
+
+  void finish_up(uint8_t thread_id, uint32_t core_id, void* context) {
+    auto replica_token = (size_t)context;
+    helper.get_node(core_id)->execute(replica_token, 0x0, 0);
+  }
+};
+
+// - Rust NR with address-range partitioned logs Benchmarking -
+
+#if !USE_COUNTER
+struct rust_nr_partitioned_monitor{
+  nr_rust_helper<rust_nr_partitioned_logs> helper;
+
+  rust_nr_partitioned_monitor(size_t n_threads)
+    : helper{n_threads}
+  {
+    helper.init_nr();
+  }
+
+  void* create_thread_context(uint8_t thread_id, uint32_t core_id) {
+    return (void*)helper.register_thread(core_id);
+  }
+
+  uint64_t read(uint8_t thread_id, uint32_t core_id, void* context, uint64_t key) {
+    size_t replica_token = (size_t) context;
+    return helper.get_node(core_id)->ReplicaResolve(replica_token, key);
+  }
+
+  void update(uint8_t thread_id, uint32_t core_id, void* context, uint64_t key, uint64_t value) {
+    size_t replica_token = (size_t)context;
+    helper.get_node(core_id)->ReplicaMap(replica_token, key, value);
   }
 
   void finish_up(uint8_t thread_id, uint32_t core_id, void* context) {
@@ -504,6 +527,37 @@ struct rust_nr_monitor{
     helper.get_node(core_id)->ReplicaResolve(replica_token, 0x0);
   }
 };
+#endif
+
+// - Rust RWLock (one reader count, or one per NUMA node) Benchmarking -
+
+#if !USE_COUNTER
+struct rust_rwlock_monitor {
+  RwLockedVSpace* vspace;
+
+  rust_rwlock_monitor(size_t n_threads, bool numa = false)
+    : vspace{createRwLockedVSpace(numa)}
+  {}
+
+  void* create_thread_context(uint8_t thread_id, uint32_t core_id) { return nullptr; }
+
+  uint64_t read(uint8_t thread_id, uint32_t core_id, void* thread_context, uint64_t key) {
+    return vspace->resolveWrapped(key);
+  }
+
+  void update(uint8_t thread_id, uint32_t core_id, void* thread_context, uint64_t key, uint64_t value) {
+    vspace->mapGenericWrapped(key, key, 4096);
+  }
+
+  void finish_up(uint8_t thread_id, uint32_t core_id, void* thread_context) {}
+};
+
+struct rust_numa_rwlock_monitor : rust_rwlock_monitor {
+  rust_numa_rwlock_monitor(size_t n_threads)
+    : rust_rwlock_monitor{n_threads, true}
+  {}
+};
+#endif
 
 template <typename Monitor>
 void bench(benchmark_state& state, Monitor& monitor)
@@ -607,10 +661,14 @@ int main(int argc, char* argv[]) {
     run_id
   };
 
+// The monitor goes out of scope before we exit so its destructor runs
+// (e.g., to dump recorded histories).
 #define BENCHMARK(test_name) \
   if (bench_name == #test_name) { \
-    test_name ## _monitor monitor{n_threads}; \
-    bench(state, monitor); \
+    { \
+      test_name ## _monitor monitor{n_threads}; \
+      bench(state, monitor); \
+    } \
     exit(0); \
   }
 
@@ -618,6 +676,11 @@ int main(int argc, char* argv[]) {
   BENCHMARK(dafny_rwlock);
   BENCHMARK(dafny_nr);
   BENCHMARK(rust_nr);
+#if !USE_COUNTER
+  BENCHMARK(rust_nr_partitioned);
+  BENCHMARK(rust_rwlock);
+  BENCHMARK(rust_numa_rwlock);
+#endif
   BENCHMARK(mcs);
   BENCHMARK(shfllock);
 
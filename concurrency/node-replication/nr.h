@@ -23,6 +23,7 @@
 #include <condition_variable>
 
 #include <memory>
+#include <cstdlib>
 
 using LinearExtern::lseq;
 
@@ -130,13 +131,58 @@ class nr_helper {
   //rw->ReplicaResolve(tkn, 0x2000);
 */
 
+// Rust NR instances driven by `rust_nr` and `rust_nr_partitioned`;
+// see `vspace/src/nr_wrapper.rs` and `vspace/src/partitioned.rs`.
+struct rust_nr_single_log {
+#ifdef USE_COUNTER
+  using log_type = CounterLogWrapper;
+  using replica_type = CounterReplicaWrapper;
+
+  static log_type& create_log() { return createCounterLog(); }
+  static replica_type* create_replica(log_type& log) {
+    return createCounterReplica(log);
+  }
+#else
+  using log_type = LogWrapper;
+  using replica_type = ReplicaWrapper;
+
+  static log_type& create_log() { return createLog(); }
+  static replica_type* create_replica(log_type& log) {
+    return createReplica(log);
+  }
+#endif
+};
+
+#ifndef USE_COUNTER
+struct rust_nr_partitioned_logs {
+  using log_type = PartitionedLogWrapper;
+  using replica_type = PartitionedReplicaWrapper;
+
+  /// Number of logs, overridden with the RUST_NR_LOGS environment variable.
+  static size_t n_logs() {
+    const char* n = getenv("RUST_NR_LOGS");
+    return n ? atoi(n) : 4;
+  }
+
+  static log_type& create_log() { return createPartitionedLog(n_logs()); }
+  static replica_type* create_replica(log_type& log) {
+    return createPartitionedReplica(log);
+  }
+};
+#endif
+
+template <typename Instance>
 class nr_rust_helper {
   uint32_t n_threads_per_replica;
-  LogWrapper& log;
+  typename Instance::log_type& log;
   std::mutex init_mutex;
   size_t nodes_init;
-  std::vector<ReplicaWrapper*> nodes;
+  std::vector<typename Instance::replica_type*> nodes;
   std::condition_variable all_nodes_init;
+  // If RUST_NR_HISTORY is set, all operations get recorded and dumped
+  // to that file for `check_history` (see `vspace/src/history.rs`).
+  const char* history_path;
+  History* history;
 
  public:
   static size_t num_replicas() {
@@ -145,11 +191,13 @@ class nr_rust_helper {
 
   nr_rust_helper(size_t n_threads)
     : n_threads_per_replica{static_cast<uint32_t>(n_threads / num_replicas())}
-    , log{createLog()}
+    , log{Instance::create_log()}
     , init_mutex{}
     , nodes_init{}
     , nodes{}
     , all_nodes_init{}
+    , history_path{getenv("RUST_NR_HISTORY")}
+    , history{history_path ? &createHistory() : nullptr}
   {
     nodes.resize(num_replicas());
     assert(num_replicas() > 0);
@@ -158,14 +206,18 @@ class nr_rust_helper {
   }
 
   ~nr_rust_helper() {
-    // NYI
+    if (history) {
+      bool ok = dumpHistory(*history, rust::Str{history_path});
+      std::cerr << (ok ? "dumped history to " : "failed to dump history to ")
+                << history_path << std::endl;
+    }
   }
 
   static uint32_t get_node_id(uint32_t core_id) {
     return core_id % 2;
   }
 
-  ReplicaWrapper *get_node(uint32_t core_id)
+  typename Instance::replica_type *get_node(uint32_t core_id)
   {
     return nodes.at(get_node_id(core_id));
   }
@@ -178,7 +230,9 @@ class nr_rust_helper {
 
     if (core_id / num_replicas() == 0)
     {
-      auto replica = createReplica(log);
+      auto replica = Instance::create_replica(log);
+      if (history)
+        replica->RecordHistory(*history);
       std::cerr << "thread on core_id " << core_id
                 << " done initializing node_id " << node_id << std::endl;
       nodes[node_id] = replica;
@@ -0,0 +1,57 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Checks a history dumped by the benchmark (`RUST_NR_HISTORY`).
+//!
+//! usage: check_history [--spec vspace|counter|hashmap] <history-file>
+//!
+//! The spec has to match the data-structure the benchmark ran: `vspace`
+//! (the default) for `rust_nr`/`rust_nr_partitioned`, `counter` for a
+//! `USE_COUNTER` build.
+
+use std::process::exit;
+
+use vspace::history::{check, load, CounterSpec, Event, HashMapSpec, Spec, VSpaceSpec};
+
+const USAGE: &str = "usage: check_history [--spec vspace|counter|hashmap] <history-file>";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2);
+}
+
+fn run<S: Spec>(path: &str, events: &[Event]) {
+    match check::<S>(events) {
+        Ok(()) => println!("{}: {} ops, linearizable", path, events.len()),
+        Err(violation) => {
+            println!("{}: {}", path, violation);
+            exit(1);
+        }
+    }
+}
+
+fn main() {
+    let mut spec = String::from("vspace");
+    let mut path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--spec" => spec = args.next().unwrap_or_else(|| usage()),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage());
+
+    let events = load(&path).unwrap_or_else(|e| {
+        eprintln!("can't read {}: {}", path, e);
+        exit(2);
+    });
+
+    match spec.as_str() {
+        "vspace" => run::<VSpaceSpec>(&path, &events),
+        "counter" => run::<CounterSpec>(&path, &events),
+        "hashmap" => run::<HashMapSpec>(&path, &events),
+        _ => usage(),
+    }
+}
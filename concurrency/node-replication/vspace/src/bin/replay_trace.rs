@@ -0,0 +1,70 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Replays replica traces (`VSPACE_TRACE_DIR`) and compares the results.
+//!
+//! usage: replay_trace <trace-file>...
+//!
+//! Every trace is re-applied to a fresh VSpace, reporting operations whose
+//! response differs from the recorded one. The final page-tables of
+//! replicas of the same partition are then diffed against each other.
+
+use std::process::exit;
+
+use vspace::trace::Trace;
+use vspace::VSpace;
+
+/// Differences reported per pair of replicas.
+const MAX_DIFFS: usize = 16;
+
+fn main() {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("usage: replay_trace <trace-file>...");
+        exit(2);
+    }
+
+    let mut failed = false;
+    let mut replayed: Vec<(String, usize, usize, VSpace)> = Vec::new();
+    for path in paths {
+        let trace = Trace::load(&path).unwrap_or_else(|e| {
+            eprintln!("can't read {}: {}", path, e);
+            exit(2);
+        });
+        let (vspace, mismatches) = trace.replay();
+        println!(
+            "{}: partition {}/{}, {} ops, {} mismatches",
+            path,
+            trace.idx,
+            trace.nlogs,
+            trace.records.len(),
+            mismatches.len()
+        );
+        for (i, got) in mismatches.iter().take(MAX_DIFFS) {
+            println!("  #{} {:x?} -> got {:#x}", i, trace.records[*i], got);
+        }
+        failed |= !mismatches.is_empty();
+        replayed.push((path, trace.idx, trace.nlogs, vspace));
+    }
+
+    for (i, (path, idx, nlogs, vspace)) in replayed.iter().enumerate() {
+        // Compare against the first replica of the same partition
+        let first = replayed[..i]
+            .iter()
+            .find(|(_, other_idx, other_nlogs, _)| other_idx == idx && other_nlogs == nlogs);
+        if let Some((first_path, _, _, first)) = first {
+            let diffs = first.diff(vspace, MAX_DIFFS);
+            if !diffs.is_empty() {
+                failed = true;
+                println!("{} and {} differ:", first_path, path);
+                for d in diffs {
+                    println!("  {:#x}: {:x?} vs. {:x?}", d.vaddr, d.ours, d.theirs);
+                }
+            }
+        }
+    }
+
+    if failed {
+        exit(1);
+    }
+}
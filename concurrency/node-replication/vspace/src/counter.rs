@@ -0,0 +1,61 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A replicated counter, the Rust twin of `CounterIfc` in
+//! `ConcreteReplica.i.dfy` (used by `BundleCounter.i.dfy`).
+
+use node_replication::Dispatch;
+
+use crate::nr_wrapper::FfiOp;
+
+#[derive(Debug, Default)]
+pub struct Counter {
+    value: u64,
+}
+
+/// Read the current counter value.
+#[derive(Debug, PartialEq, Clone)]
+pub enum CounterRead {
+    Get,
+}
+
+/// Increment the counter (wrapping to 0 like the Dafny version).
+#[derive(Debug, PartialEq, Clone)]
+pub enum CounterWrite {
+    Increment,
+}
+
+impl FfiOp for CounterRead {
+    fn from_ffi(_a: u64, _b: u64) -> Self {
+        CounterRead::Get
+    }
+}
+
+impl FfiOp for CounterWrite {
+    fn from_ffi(_a: u64, _b: u64) -> Self {
+        CounterWrite::Increment
+    }
+}
+
+impl Dispatch for Counter {
+    type ReadOperation = CounterRead;
+    type WriteOperation = CounterWrite;
+    type Response = u64;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        match op {
+            CounterRead::Get => self.value,
+        }
+    }
+
+    /// Returns the value before the increment.
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        match op {
+            CounterWrite::Increment => {
+                let old = self.value;
+                self.value = if old == u64::MAX { 0 } else { old + 1 };
+                old
+            }
+        }
+    }
+}
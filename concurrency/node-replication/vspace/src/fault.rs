@@ -0,0 +1,175 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Demand paging: resolving translation misses through a fault handler.
+//!
+//! A miss is detected by a read-only `Access::TryResolve`; the fault is
+//! then handled by a `Modify::Fault` that goes through the log, so every
+//! replica installs the same mapping. The [`PageFaultHandler`] decides
+//! what (if anything) gets mapped at the faulting page.
+
+use std::sync::Arc;
+
+use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE};
+
+use crate::inject::AllocError;
+use crate::{MapAction, ResourceType, VSpace};
+
+/// Translation result of `Access::TryResolve` for an unmapped address.
+pub const NOT_PRESENT: u64 = u64::MAX;
+
+/// What to do about a fault.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum FaultResult {
+    /// Map the 4 KiB frame at `PAddr` at the faulting page.
+    Map(PAddr, MapAction),
+    /// The access is illegal (e.g., hit a guard page).
+    Reject,
+}
+
+/// Hands out frames to fault handlers.
+///
+/// Frames come from the page-table arena of the `VSpace`, so they are
+/// real (zeroed) memory that handlers can fill in.
+pub struct Frames<'a> {
+    vspace: &'a mut VSpace,
+    /// Frames handed out during this fault.
+    allocated: Vec<PAddr>,
+}
+
+impl<'a> Frames<'a> {
+    /// Allocates a zeroed 4 KiB frame.
+    pub fn alloc_zeroed(&mut self) -> Result<PAddr, AllocError> {
+        let frame = self.vspace.allocate_pages(1, ResourceType::Memory)?;
+        self.allocated.push(frame);
+        Ok(frame)
+    }
+
+    /// Gives access to the contents of a frame returned by `alloc_zeroed`.
+    ///
+    /// Panics for any other frame (it might hold the page-tables).
+    pub fn contents(&mut self, frame: PAddr) -> &mut [u8] {
+        assert!(self.allocated.contains(&frame), "{:#x} is not an allocated frame", frame);
+        let vaddr = self.vspace.paddr_to_kernel_vaddr(frame);
+        unsafe { std::slice::from_raw_parts_mut(vaddr.as_mut_ptr::<u8>(), BASE_PAGE_SIZE) }
+    }
+}
+
+/// Consulted on a translation miss at (page-aligned) `vaddr`.
+///
+/// Handlers must be deterministic: every replica calls them for the same
+/// faults in the same order and has to end up with the same mapping.
+pub trait PageFaultHandler: Send + Sync {
+    fn handle_fault(&self, vaddr: VAddr, frames: &mut Frames) -> FaultResult;
+}
+
+/// Maps a fresh zeroed frame on every fault (or rejects it if we're out
+/// of memory).
+pub struct ZeroFill {
+    pub rights: MapAction,
+}
+
+impl PageFaultHandler for ZeroFill {
+    fn handle_fault(&self, _vaddr: VAddr, frames: &mut Frames) -> FaultResult {
+        match frames.alloc_zeroed() {
+            Ok(frame) => FaultResult::Map(frame, self.rights),
+            Err(_) => FaultResult::Reject,
+        }
+    }
+}
+
+/// Backs `[base, base + data.len())` with a copy of `data`.
+///
+/// The last page is zero-filled past the end of `data`, faults outside the
+/// file are rejected.
+pub struct FileBacked {
+    pub base: VAddr,
+    pub data: Arc<[u8]>,
+    pub rights: MapAction,
+}
+
+impl PageFaultHandler for FileBacked {
+    fn handle_fault(&self, vaddr: VAddr, frames: &mut Frames) -> FaultResult {
+        if vaddr < self.base || vaddr >= self.base + self.data.len() {
+            return FaultResult::Reject;
+        }
+        let offset = (vaddr - self.base).as_usize();
+        let len = BASE_PAGE_SIZE.min(self.data.len() - offset);
+
+        let frame = match frames.alloc_zeroed() {
+            Ok(frame) => frame,
+            Err(_) => return FaultResult::Reject,
+        };
+        frames.contents(frame)[..len].copy_from_slice(&self.data[offset..offset + len]);
+        FaultResult::Map(frame, self.rights)
+    }
+}
+
+/// Rejects faults in `[base, base + len)`, defers to `inner` otherwise.
+pub struct GuardPages<H> {
+    pub base: VAddr,
+    pub len: usize,
+    pub inner: H,
+}
+
+impl<H: PageFaultHandler> PageFaultHandler for GuardPages<H> {
+    fn handle_fault(&self, vaddr: VAddr, frames: &mut Frames) -> FaultResult {
+        if vaddr >= self.base && vaddr < self.base + self.len {
+            FaultResult::Reject
+        } else {
+            self.inner.handle_fault(vaddr, frames)
+        }
+    }
+}
+
+/// Number of faults handled, for studying fault-heavy workloads.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FaultStats {
+    pub mapped: usize,
+    pub rejected: usize,
+}
+
+impl VSpace {
+    /// Installs the handler consulted by [`VSpace::handle_fault`].
+    pub fn set_fault_handler(&mut self, handler: Arc<dyn PageFaultHandler>) {
+        self.fault_handler = Some(handler);
+    }
+
+    pub fn fault_stats(&self) -> FaultStats {
+        self.fault_stats
+    }
+
+    /// Resolves a translation miss at `addr`.
+    ///
+    /// Returns the translation of `addr` afterwards, or `None` if there is
+    /// no handler, it rejected the fault or the mapping failed. A fault on an address that got
+    /// mapped in the meantime (e.g., by an earlier fault on the same page
+    /// further up the log) just returns the existing translation.
+    pub fn handle_fault(&mut self, addr: VAddr) -> Option<PAddr> {
+        if let Some(paddr) = self.resolve_addr(addr) {
+            return Some(paddr);
+        }
+        let handler = self.fault_handler.clone()?;
+
+        let page = addr.align_down_to_base_page();
+        // `map_generic` only gives back what it allocated itself, not the
+        // frame the handler got
+        let mem_counter = self.mem_counter;
+        let result = handler.handle_fault(page, &mut Frames {
+            vspace: self,
+            allocated: Vec::new(),
+        });
+        let mapped = match result {
+            FaultResult::Map(frame, rights) => self.map_generic(page, (frame, BASE_PAGE_SIZE), rights).is_ok(),
+            FaultResult::Reject => false,
+        };
+        if mapped {
+            self.fault_stats.mapped += 1;
+            self.resolve_addr(addr)
+        } else {
+            self.mem_counter = mem_counter;
+            self.fault_stats.rejected += 1;
+            None
+        }
+    }
+}
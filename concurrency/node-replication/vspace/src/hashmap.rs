@@ -0,0 +1,74 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A replicated hashmap backed by the `ResizingHashMap` from
+//! `examples/hashtable.rs`.
+
+use node_replication::Dispatch;
+
+use crate::hashtable::ResizingHashMap;
+use crate::nr_wrapper::{FfiOp, ABSENT};
+
+/// Initial number of slots of every replica's table.
+const INITIAL_SIZE: usize = 1024;
+
+pub struct NrHashMap {
+    map: ResizingHashMap<u64, u64>,
+}
+
+impl Default for NrHashMap {
+    fn default() -> NrHashMap {
+        NrHashMap {
+            map: ResizingHashMap::new(INITIAL_SIZE),
+        }
+    }
+}
+
+/// Look up a key.
+#[derive(Debug, PartialEq, Clone)]
+pub enum HashMapRead {
+    Get(u64),
+}
+
+/// Insert or remove a key.
+#[derive(Debug, PartialEq, Clone)]
+pub enum HashMapWrite {
+    Put(u64, u64),
+    Remove(u64),
+}
+
+impl FfiOp for HashMapRead {
+    fn from_ffi(key: u64, _b: u64) -> Self {
+        HashMapRead::Get(key)
+    }
+}
+
+/// C++ only issues puts; removals are for Rust-side users.
+///
+/// `ABSENT` is how a missing value comes back, so it can't be stored.
+impl FfiOp for HashMapWrite {
+    fn from_ffi(key: u64, value: u64) -> Self {
+        assert_ne!(value, ABSENT, "{:#x} can't be told apart from an absent value", value);
+        HashMapWrite::Put(key, value)
+    }
+}
+
+impl Dispatch for NrHashMap {
+    type ReadOperation = HashMapRead;
+    type WriteOperation = HashMapWrite;
+    type Response = Option<u64>;
+
+    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
+        match op {
+            HashMapRead::Get(key) => self.map.get(&key).copied(),
+        }
+    }
+
+    /// Returns the previous value for the key, if any.
+    fn dispatch_mut(&mut self, op: Self::WriteOperation) -> Self::Response {
+        match op {
+            HashMapWrite::Put(key, value) => self.map.insert_and_get_old(key, value),
+            HashMapWrite::Remove(key) => self.map.remove_and_get(&key),
+        }
+    }
+}
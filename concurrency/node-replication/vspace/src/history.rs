@@ -0,0 +1,377 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Recording operation histories and checking them for linearizability.
+//!
+//! `linearizability-argument.md` argues on paper why NR is linearizable;
+//! this makes it checkable on actual runs. A [`History`] can be attached
+//! to replicas (see `NrReplica::record_history`), it then logs every
+//! operation with the logical time it was invoked and returned at. The
+//! resulting history is checked against a sequential [`Spec`] with the
+//! Wing–Gong search, using the linked-list and memoization improvements
+//! from Lowe, "Testing for linearizability" (2017).
+//!
+//! Histories are P-compositional: a spec splits operations into
+//! independent partitions (e.g., one per page for the VSpace), which are
+//! checked separately. This is what keeps the search tractable on
+//! benchmark-sized histories.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::hash::Hash;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use x86::bits64::paging::BASE_PAGE_SIZE;
+
+use crate::nr_wrapper::ABSENT;
+use crate::VSPACE_RANGE;
+
+/// An operation as it crosses the FFI boundary (see `FfiOp`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Read(u64, u64),
+    Write(u64, u64),
+}
+
+/// A completed operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub op: Op,
+    pub ret: u64,
+    /// Logical time the operation was invoked at.
+    pub invoke: u64,
+    /// Logical time the operation returned at, always `> invoke`.
+    pub response: u64,
+}
+
+/// A shared recorder for the operations of (possibly) many replicas.
+///
+/// Timestamps come from a single atomic counter, so they order events
+/// in real time. Recording serializes on a lock and perturbs the
+/// benchmark; it is meant for checking, not for measuring.
+#[derive(Debug, Default)]
+pub struct History {
+    clock: AtomicU64,
+    events: Mutex<Vec<Event>>,
+}
+
+pub fn createHistory() -> &'static mut History {
+    Box::leak(Box::new(History::default()))
+}
+
+pub fn dumpHistory(history: &History, path: &str) -> bool {
+    history.dump(path).is_ok()
+}
+
+impl History {
+    /// Runs `f` (which executes `op`) and records it.
+    pub fn record<F: FnOnce() -> u64>(&self, op: Op, f: F) -> u64 {
+        let invoke = self.clock.fetch_add(1, Ordering::SeqCst);
+        let ret = f();
+        let response = self.clock.fetch_add(1, Ordering::SeqCst);
+        self.events.lock().unwrap().push(Event { op, ret, invoke, response });
+        ret
+    }
+
+    /// The operations recorded so far.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Writes the history as text, one event per line:
+    /// `<R|W> <a> <b> <ret> <invoke> <response>` (numbers in hex).
+    pub fn dump<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        for e in self.events.lock().unwrap().iter() {
+            let (kind, a, b) = match e.op {
+                Op::Read(a, b) => ('R', a, b),
+                Op::Write(a, b) => ('W', a, b),
+            };
+            writeln!(out, "{} {:x} {:x} {:x} {:x} {:x}", kind, a, b, e.ret, e.invoke, e.response)?;
+        }
+        out.flush()
+    }
+}
+
+/// Reads a history written by [`History::dump`].
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Vec<Event>> {
+    let invalid = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("bad event: {}", line));
+    let mut events = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 6 {
+            return Err(invalid(&line));
+        }
+        let mut nums = [0u64; 5];
+        for (n, f) in nums.iter_mut().zip(&fields[1..]) {
+            *n = u64::from_str_radix(f, 16).map_err(|_| invalid(&line))?;
+        }
+        let op = match fields[0] {
+            "R" => Op::Read(nums[0], nums[1]),
+            "W" => Op::Write(nums[0], nums[1]),
+            _ => return Err(invalid(&line)),
+        };
+        events.push(Event { op, ret: nums[2], invoke: nums[3], response: nums[4] });
+    }
+    Ok(events)
+}
+
+/// Sequential specification of a data-structure, for one partition.
+pub trait Spec: Clone + Eq + Hash {
+    /// Operations in different partitions commute and never influence
+    /// each other's results.
+    fn partition(op: &Op) -> u64;
+
+    /// State of `partition` before any operation.
+    fn initial(partition: u64) -> Self;
+
+    /// Applies `op`, returns what it should have returned.
+    fn step(&mut self, op: &Op) -> u64;
+}
+
+/// The VSpace as driven by `ReplicaResolve`/`ReplicaMap` (or the FFI
+/// `execute`/`execute_mut`), restricted to a single 4 KiB page.
+///
+/// Pages below `VSPACE_RANGE` start out identity mapped, everything
+/// else unmapped (resolving to 0).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VSpaceSpec {
+    frame: Option<u64>,
+}
+
+impl Spec for VSpaceSpec {
+    fn partition(op: &Op) -> u64 {
+        match op {
+            Op::Read(vaddr, _) | Op::Write(vaddr, _) => vaddr & !(BASE_PAGE_SIZE as u64 - 1),
+        }
+    }
+
+    fn initial(page: u64) -> VSpaceSpec {
+        VSpaceSpec {
+            frame: if page < VSPACE_RANGE { Some(page) } else { None },
+        }
+    }
+
+    fn step(&mut self, op: &Op) -> u64 {
+        match op {
+            Op::Read(vaddr, _) => self
+                .frame
+                .map(|f| f + (vaddr & (BASE_PAGE_SIZE as u64 - 1)))
+                .unwrap_or(0x0),
+            Op::Write(_vaddr, paddr) => {
+                self.frame = Some(*paddr);
+                1
+            }
+        }
+    }
+}
+
+/// The bridged `Counter` (see `counter.rs`), which is all one partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CounterSpec {
+    value: u64,
+}
+
+impl Spec for CounterSpec {
+    fn partition(_op: &Op) -> u64 {
+        0
+    }
+
+    fn initial(_partition: u64) -> CounterSpec {
+        CounterSpec { value: 0 }
+    }
+
+    fn step(&mut self, op: &Op) -> u64 {
+        match op {
+            Op::Read(_, _) => self.value,
+            Op::Write(_, _) => {
+                let old = self.value;
+                self.value = if old == u64::MAX { 0 } else { old + 1 };
+                old
+            }
+        }
+    }
+}
+
+/// The bridged `NrHashMap` (see `hashmap.rs`), restricted to a single key.
+/// Missing values read as `ABSENT`, like they are reported to C++.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HashMapSpec {
+    value: Option<u64>,
+}
+
+impl Spec for HashMapSpec {
+    fn partition(op: &Op) -> u64 {
+        match op {
+            Op::Read(key, _) | Op::Write(key, _) => *key,
+        }
+    }
+
+    fn initial(_key: u64) -> HashMapSpec {
+        HashMapSpec { value: None }
+    }
+
+    fn step(&mut self, op: &Op) -> u64 {
+        match op {
+            Op::Read(_, _) => self.value.unwrap_or(ABSENT),
+            Op::Write(_, value) => self.value.replace(*value).unwrap_or(ABSENT),
+        }
+    }
+}
+
+/// A partition of a history that has no linearization.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub partition: u64,
+    pub events: Vec<Event>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "history of partition {:#x} ({} ops) is not linearizable:",
+            self.partition,
+            self.events.len()
+        )?;
+        for e in self.events.iter().take(32) {
+            writeln!(f, "  [{}, {}] {:x?} -> {:#x}", e.invoke, e.response, e.op, e.ret)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `events` is linearizable with respect to `S`.
+pub fn check<S: Spec>(events: &[Event]) -> Result<(), Violation> {
+    let mut partitions: BTreeMap<u64, Vec<Event>> = BTreeMap::new();
+    for e in events {
+        debug_assert!(e.invoke < e.response);
+        partitions.entry(S::partition(&e.op)).or_insert_with(Vec::new).push(*e);
+    }
+
+    for (partition, events) in partitions {
+        if !check_partition(&events, S::initial(partition)) {
+            return Err(Violation { partition, events });
+        }
+    }
+    Ok(())
+}
+
+/// Call or return of the operation with the given index.
+#[derive(Clone, Copy)]
+enum Entry {
+    Call(usize),
+    Return(usize),
+}
+
+/// Node of the doubly linked list of pending calls/returns.
+#[derive(Clone, Copy)]
+struct Node {
+    entry: Entry,
+    prev: usize,
+    next: usize,
+}
+
+/// Per-operation random value for hashing sets of linearized operations
+/// (splitmix64).
+fn zobrist(op: usize) -> u64 {
+    let mut z = (op as u64).wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Node 0 is the list head, `NIL` terminates it.
+const HEAD: usize = 0;
+const NIL: usize = usize::MAX;
+
+/// Wing–Gong search over a single partition.
+fn check_partition<S: Spec>(events: &[Event], initial: S) -> bool {
+    let mut timeline: Vec<(u64, Entry)> = Vec::with_capacity(2 * events.len());
+    for (i, e) in events.iter().enumerate() {
+        timeline.push((e.invoke, Entry::Call(i)));
+        timeline.push((e.response, Entry::Return(i)));
+    }
+    timeline.sort_by_key(|(time, _)| *time);
+
+    let mut nodes = Vec::with_capacity(timeline.len() + 1);
+    nodes.push(Node { entry: Entry::Call(usize::MAX), prev: NIL, next: NIL });
+    let mut return_node = vec![0; events.len()];
+    for (time_idx, (_, entry)) in timeline.iter().enumerate() {
+        let idx = time_idx + 1;
+        let next = if idx < timeline.len() { idx + 1 } else { NIL };
+        nodes.push(Node { entry: *entry, prev: idx - 1, next });
+        if let Entry::Return(op) = entry {
+            return_node[*op] = idx;
+        }
+    }
+    nodes[HEAD].next = if timeline.is_empty() { NIL } else { 1 };
+
+    // Removes node `n` from the list, `relink` reverses it.
+    fn unlink(nodes: &mut [Node], n: usize) {
+        let Node { prev, next, .. } = nodes[n];
+        nodes[prev].next = next;
+        if next != NIL {
+            nodes[next].prev = prev;
+        }
+    }
+    fn relink(nodes: &mut [Node], n: usize) {
+        let Node { prev, next, .. } = nodes[n];
+        nodes[prev].next = n;
+        if next != NIL {
+            nodes[next].prev = n;
+        }
+    }
+
+    // The memoized configurations identify the set of linearized ops by
+    // a hash (and its size) instead of a copy of the set, which would make
+    // every step O(n). A collision could only hide a linearization.
+    let mut linearized = 0u64;
+    let mut seen: HashSet<(u64, usize, S)> = HashSet::new();
+    // Call nodes linearized so far, with the state before each of them
+    let mut stack: Vec<(usize, S)> = Vec::new();
+    let mut state = initial;
+    let mut cur = nodes[HEAD].next;
+
+    while nodes[HEAD].next != NIL {
+        match nodes[cur].entry {
+            Entry::Call(op) => {
+                let mut next_state = state.clone();
+                let ok = next_state.step(&events[op].op) == events[op].ret;
+                if ok && seen.insert((linearized ^ zobrist(op), stack.len() + 1, next_state.clone())) {
+                    linearized ^= zobrist(op);
+                    stack.push((cur, state));
+                    state = next_state;
+                    unlink(&mut nodes, cur);
+                    unlink(&mut nodes, return_node[op]);
+                    cur = nodes[HEAD].next;
+                } else {
+                    cur = nodes[cur].next;
+                }
+            }
+            Entry::Return(_) => {
+                // Some operation returned before any of the candidates could
+                // be linearized, backtrack.
+                let (call, prev_state) = match stack.pop() {
+                    Some(top) => top,
+                    None => return false,
+                };
+                let op = match nodes[call].entry {
+                    Entry::Call(op) => op,
+                    Entry::Return(_) => unreachable!(),
+                };
+                linearized ^= zobrist(op);
+                state = prev_state;
+                relink(&mut nodes, return_node[op]);
+                relink(&mut nodes, call);
+                cur = nodes[call].next;
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,152 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Fault injection for the allocations a [`VSpace`] makes.
+//!
+//! Page-table pages, frames handed to fault handlers, region backing and
+//! the page-table arena itself can be made to fail according to a
+//! [`FaultPolicy`], either passed to [`VSpace::with_alloc_faults`] or
+//! set through `VSPACE_ALLOC_FAULTS` (see [`FaultPolicy::from_str`]).
+//!
+//! Injection is deterministic: every replica applies the same operations
+//! and so makes the same allocations, which therefore fail the same way.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::VSpace;
+
+/// An allocation that failed, be it injected or for real.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError {
+    pub bytes: usize,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to allocate {:#x} bytes", self.bytes)
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/// When allocations should fail.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultPolicy {
+    Never,
+    /// Fail the n-th allocation (counting from 1), and only that one.
+    Nth(usize),
+    /// Fail every allocation with probability `p`, using a PRNG seeded
+    /// with `seed`.
+    Probability { p: f64, seed: u64 },
+    /// Fail all allocations once the given number of bytes got allocated.
+    AfterBytes(usize),
+}
+
+impl Default for FaultPolicy {
+    fn default() -> FaultPolicy {
+        FaultPolicy::Never
+    }
+}
+
+impl FaultPolicy {
+    /// Reads the policy from `VSPACE_ALLOC_FAULTS`, if set.
+    pub fn from_env() -> Option<FaultPolicy> {
+        let spec = std::env::var("VSPACE_ALLOC_FAULTS").ok()?;
+        match spec.parse() {
+            Ok(policy) => Some(policy),
+            Err(e) => panic!("bad VSPACE_ALLOC_FAULTS: {}", e),
+        }
+    }
+}
+
+/// Parses `never`, `nth:<n>`, `p:<p>[:<seed>]` or `bytes:<n>`.
+impl FromStr for FaultPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FaultPolicy, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let num = |s: &str| s.parse::<usize>().map_err(|e| format!("{}: {}", s, e));
+        match parts.as_slice() {
+            ["never"] => Ok(FaultPolicy::Never),
+            ["nth", n] => Ok(FaultPolicy::Nth(num(n)?)),
+            ["bytes", n] => Ok(FaultPolicy::AfterBytes(num(n)?)),
+            ["p", p] | ["p", p, _] => {
+                let p = p.parse::<f64>().map_err(|e| format!("{}: {}", p, e))?;
+                if !(0.0..=1.0).contains(&p) {
+                    return Err(format!("probability {} not in [0, 1]", p));
+                }
+                let seed = match parts.get(2) {
+                    Some(seed) => num(seed)? as u64,
+                    None => 0x5eed,
+                };
+                Ok(FaultPolicy::Probability { p, seed })
+            }
+            _ => Err(format!("unknown fault policy {:?}", s)),
+        }
+    }
+}
+
+/// Decides which allocations fail, according to a [`FaultPolicy`].
+#[derive(Debug, Clone, Default)]
+pub struct AllocFaults {
+    policy: FaultPolicy,
+    allocations: usize,
+    bytes: usize,
+    rng: u64,
+}
+
+impl AllocFaults {
+    pub fn new(policy: FaultPolicy) -> AllocFaults {
+        let rng = match policy {
+            // xorshift gets stuck at 0
+            FaultPolicy::Probability { seed, .. } => seed | 1,
+            _ => 1,
+        };
+        AllocFaults { policy, allocations: 0, bytes: 0, rng }
+    }
+
+    pub fn policy(&self) -> FaultPolicy {
+        self.policy
+    }
+
+    /// Accounts for an allocation of `bytes`, fails it if the policy says so.
+    pub fn allocate(&mut self, bytes: usize) -> Result<(), AllocError> {
+        self.allocations += 1;
+        let fail = match self.policy {
+            FaultPolicy::Never => false,
+            FaultPolicy::Nth(n) => self.allocations == n,
+            FaultPolicy::Probability { p, .. } => self.next_f64() < p,
+            FaultPolicy::AfterBytes(limit) => self.bytes + bytes > limit,
+        };
+        if fail {
+            Err(AllocError { bytes })
+        } else {
+            self.bytes += bytes;
+            Ok(())
+        }
+    }
+
+    /// Uniform in `[0, 1)`, from xorshift64*.
+    fn next_f64(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let x = self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl VSpace {
+    /// Makes allocations fail according to `policy` from now on.
+    pub fn set_alloc_faults(&mut self, policy: FaultPolicy) {
+        self.alloc_faults = AllocFaults::new(policy);
+    }
+
+    /// Applies `VSPACE_ALLOC_FAULTS`, if set.
+    pub(crate) fn alloc_faults_from_env(&mut self) {
+        if let Some(policy) = FaultPolicy::from_env() {
+            self.set_alloc_faults(policy);
+        }
+    }
+}
@@ -4,17 +4,49 @@
 //! Evaluates a virtual address space implementation using node-replication.
 //#![feature(test)]
 //#![feature(bench_black_box)]
+// `hash_one` in the example hashtable, still unstable on the pinned nightly
+#![feature(build_hasher_simple_hash_one)]
 #![crate_type = "staticlib"]
 extern crate alloc;
 
 use std::fmt;
 use std::mem::transmute;
 use std::pin::Pin;
+use std::sync::Arc;
 
 use log::{debug, trace};
 use x86::bits64::paging::*;
 
-use node_replication::{Log, Replica, Dispatch, ReplicaToken};
+use node_replication::{Dispatch, ReplicaToken};
+
+#[macro_use]
+pub mod nr_wrapper;
+pub mod counter;
+pub mod fault;
+pub mod hashmap;
+pub mod history;
+pub mod inject;
+pub mod locked;
+pub mod partitioned;
+pub mod regions;
+pub mod rmap;
+pub mod trace;
+#[path = "../../../../examples/hashtable.rs"]
+#[allow(dead_code)]
+pub mod hashtable;
+
+use counter::Counter;
+use fault::{FaultStats, PageFaultHandler, NOT_PRESENT};
+use hashmap::NrHashMap;
+use history::{createHistory, dumpHistory, History, Op};
+use inject::{AllocError, AllocFaults, FaultPolicy};
+use locked::{createRwLockedVSpace, RwLockedVSpace};
+use nr_wrapper::FfiOp;
+use regions::RegionManager;
+use rmap::ReverseMap;
+use trace::{Record, TraceWriter};
+use partitioned::{createPartitionedLog, createPartitionedReplica, PartitionedLogWrapper, PartitionedReplicaWrapper};
+
 const VSPACE_RANGE: u64 = 512*1024*1024*1024; 
 
 #[cxx::bridge]
@@ -43,22 +75,68 @@ mod ffi {
         type LogWrapper;
 
         pub fn RegisterWrapper(self: &mut ReplicaWrapper) -> usize;
+        pub fn RecordHistory(self: &mut ReplicaWrapper, history: &'static History);
         pub fn createLog() -> &'static mut LogWrapper;
         pub fn createReplica(log: &'static mut LogWrapper) -> *mut ReplicaWrapper;
 
         pub fn ReplicaResolve(self: &mut ReplicaWrapper, tkn: usize, key: u64) -> u64;
         pub fn ReplicaMap(self: &mut ReplicaWrapper, tkn: usize, key: u64, val: u64) -> u64;
-    }
-}
+        pub fn ReplicaResolveOrFault(self: &ReplicaWrapper, tkn: usize, key: u64) -> u64;
+        pub fn ReplicaMmap(self: &ReplicaWrapper, tkn: usize, len: usize) -> u64;
+        pub fn ReplicaMunmap(self: &ReplicaWrapper, tkn: usize, base: u64) -> bool;
+        pub fn execute(self: &ReplicaWrapper, tkn: usize, a: u64, b: u64) -> u64;
+        pub fn execute_mut(self: &ReplicaWrapper, tkn: usize, a: u64, b: u64) -> u64;
+
+        // VSpace with one NR log per address-range partition
+        type PartitionedLogWrapper;
+        type PartitionedReplicaWrapper;
+
+        pub fn createPartitionedLog(nlogs: usize) -> &'static mut PartitionedLogWrapper;
+        pub fn createPartitionedReplica(log: &'static PartitionedLogWrapper) -> *mut PartitionedReplicaWrapper;
+        pub fn RegisterWrapper(self: &mut PartitionedReplicaWrapper) -> usize;
+        pub fn RecordHistory(self: &mut PartitionedReplicaWrapper, history: &'static History);
+        pub fn ReplicaResolve(self: &PartitionedReplicaWrapper, tkn: usize, key: u64) -> u64;
+        pub fn ReplicaMap(self: &PartitionedReplicaWrapper, tkn: usize, key: u64, val: u64) -> u64;
+
+        // Generic NR wrappers (see `nr_wrapper.rs`), one block per instance
+        type CounterLogWrapper;
+        type CounterReplicaWrapper;
+
+        pub fn createCounterLog() -> &'static mut CounterLogWrapper;
+        pub fn createCounterReplica(log: &'static CounterLogWrapper) -> *mut CounterReplicaWrapper;
+        pub fn RegisterWrapper(self: &mut CounterReplicaWrapper) -> usize;
+        pub fn RecordHistory(self: &mut CounterReplicaWrapper, history: &'static History);
+        pub fn execute(self: &CounterReplicaWrapper, tkn: usize, a: u64, b: u64) -> u64;
+        pub fn execute_mut(self: &CounterReplicaWrapper, tkn: usize, a: u64, b: u64) -> u64;
+
+        type HashMapLogWrapper;
+        type HashMapReplicaWrapper;
+
+        pub fn createHashMapLog() -> &'static mut HashMapLogWrapper;
+        pub fn createHashMapReplica(log: &'static HashMapLogWrapper) -> *mut HashMapReplicaWrapper;
+        pub fn RegisterWrapper(self: &mut HashMapReplicaWrapper) -> usize;
+        pub fn RecordHistory(self: &mut HashMapReplicaWrapper, history: &'static History);
+        pub fn execute(self: &HashMapReplicaWrapper, tkn: usize, a: u64, b: u64) -> u64;
+        pub fn execute_mut(self: &HashMapReplicaWrapper, tkn: usize, a: u64, b: u64) -> u64;
+
+        // A VSpace behind a Rust rwlock (see `locked.rs`)
+        type RwLockedVSpace;
+
+        pub fn createRwLockedVSpace(numa: bool) -> *mut RwLockedVSpace;
+        pub fn resolveWrapped(self: &RwLockedVSpace, vbase: u64) -> u64;
+        pub fn mapGenericWrapped(
+            self: &RwLockedVSpace,
+            vbase: u64,
+            pregion: u64,
+            pregion_len: usize,
+        ) -> bool;
 
-fn kernel_vaddr_to_paddr(v: VAddr) -> PAddr {
-    let vaddr_val: usize = v.into();
-    PAddr::from(vaddr_val as u64 - 0x0)
-}
+        // Linearizability checking (see `history.rs`)
+        type History;
 
-fn paddr_to_kernel_vaddr(p: PAddr) -> VAddr {
-    let paddr_val: u64 = p.into();
-    VAddr::from((paddr_val + 0x0) as usize)
+        pub fn createHistory() -> &'static mut History;
+        pub fn dumpHistory(history: &History, path: &str) -> bool;
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
@@ -167,50 +245,196 @@ impl fmt::Display for MapAction {
         }
     }
 }
-use std::sync::Arc;
 
-pub struct ReplicaWrapper {
-    log: &'static LogWrapper,
-    inner: Arc<Replica<'static, VSpace>>,
+/// Memory type of a mapping.
+///
+/// Assumes the PAT MSR is programmed the way Linux does it:
+/// WB, WC, UC-, UC, WB, WP, UC-, WT for PAT entries 0 to 7.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum CacheType {
+    WriteBack,
+    WriteCombining,
+    Uncached,
+    WriteThrough,
 }
 
-impl ReplicaWrapper {
-    fn RegisterWrapper(&mut self) -> usize {
-        let tkn = self.inner.register().unwrap();
-        tkn.id()
+impl CacheType {
+    /// Index of the PAT entry selected by the PAT, PCD and PWT bits.
+    fn pat_index(&self) -> u64 {
+        match self {
+            CacheType::WriteBack => 0,
+            CacheType::WriteCombining => 1,
+            CacheType::Uncached => 3,
+            CacheType::WriteThrough => 7,
+        }
+    }
+}
+
+/// PWT and PCD are bits 3 and 4 at every level.
+const PWT_BIT: u64 = 1 << 3;
+const PCD_BIT: u64 = 1 << 4;
+/// The PAT bit is bit 7 in a PTE, but bit 7 is PS in a PDE/PDPTE, so
+/// large pages have it at bit 12 (the lowest address bit they don't use).
+const PT_PAT_BIT: u64 = 1 << 7;
+const LARGE_PAT_BIT: u64 = 1 << 12;
+/// Protection key, bits 59:62 of a leaf entry.
+const PKEY_SHIFT: u64 = 59;
+pub const MAX_PKEY: u8 = 15;
+
+/// Mapping rights extended with caching, global and protection-key
+/// attributes.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct MapRights {
+    pub action: MapAction,
+    pub cache: CacheType,
+    /// Survive CR3 switches in the TLB (needs CR4.PGE).
+    pub global: bool,
+    /// Protection key (PKU), 0 is the default key.
+    pub pkey: u8,
+}
+
+impl From<MapAction> for MapRights {
+    fn from(action: MapAction) -> MapRights {
+        MapRights {
+            action,
+            cache: CacheType::WriteBack,
+            global: false,
+            pkey: 0,
+        }
+    }
+}
+
+impl MapRights {
+    /// Bits shared by all levels except for where the PAT bit goes.
+    fn attribute_bits(&self, pat_bit: u64) -> u64 {
+        assert!(self.pkey <= MAX_PKEY, "pkey {} out of range", self.pkey);
+        let pat = self.cache.pat_index();
+        let mut bits = (self.pkey as u64) << PKEY_SHIFT;
+        if pat & 0b001 != 0 {
+            bits |= PWT_BIT;
+        }
+        if pat & 0b010 != 0 {
+            bits |= PCD_BIT;
+        }
+        if pat & 0b100 != 0 {
+            bits |= pat_bit;
+        }
+        if self.global {
+            bits |= PTFlags::G.bits();
+        }
+        bits
+    }
+
+    /// Transform MapRights into rights for 1 GiB page.
+    fn to_pdpt_rights(&self) -> PDPTFlags {
+        let extra = unsafe { PDPTFlags::from_bits_unchecked(self.attribute_bits(LARGE_PAT_BIT)) };
+        self.action.to_pdpt_rights() | extra
+    }
+
+    /// Transform MapRights into rights for 2 MiB page.
+    fn to_pd_rights(&self) -> PDFlags {
+        let extra = unsafe { PDFlags::from_bits_unchecked(self.attribute_bits(LARGE_PAT_BIT)) };
+        self.action.to_pd_rights() | extra
+    }
+
+    /// Transform MapRights into rights for 4KiB page.
+    fn to_pt_rights(&self) -> PTFlags {
+        let extra = unsafe { PTFlags::from_bits_unchecked(self.attribute_bits(PT_PAT_BIT)) };
+        self.action.to_pt_rights() | extra
     }
+}
+
+impl fmt::Display for MapRights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cache = match self.cache {
+            CacheType::WriteBack => "WB",
+            CacheType::WriteCombining => "WC",
+            CacheType::Uncached => "UC",
+            CacheType::WriteThrough => "WT",
+        };
+        write!(f, "{} {}", self.action, cache)?;
+        if self.global {
+            write!(f, " G")?;
+        }
+        if self.pkey != 0 {
+            write!(f, " pkey={}", self.pkey)?;
+        }
+        Ok(())
+    }
+}
+
+/// Address of a 1 GiB page, without the PAT bit sharing the address bits.
+fn huge_page_address(entry: PDPTEntry) -> PAddr {
+    PAddr::from(entry.address().as_u64() & !(HUGE_PAGE_SIZE as u64 - 1))
+}
+
+/// Address of a 2 MiB page, without the PAT bit sharing the address bits.
+fn large_page_address(entry: PDEntry) -> PAddr {
+    PAddr::from(entry.address().as_u64() & !(LARGE_PAGE_SIZE as u64 - 1))
+}
+nr_bridge_types!(VSpace, LogWrapper, ReplicaWrapper, createLog, createReplica);
+nr_bridge_types!(Counter, CounterLogWrapper, CounterReplicaWrapper, createCounterLog, createCounterReplica);
+nr_bridge_types!(NrHashMap, HashMapLogWrapper, HashMapReplicaWrapper, createHashMapLog, createHashMapReplica);
 
+impl ReplicaWrapper {
     fn ReplicaResolve(&self, tkn: usize, key: u64) -> u64 {
         let tkn = unsafe { ReplicaToken::new(tkn) };
-        self.inner.execute(Access::Resolve(key), tkn)
+        self.0.recorded(Op::Read(key, 0), || self.0.inner.execute(Access::Resolve(key), tkn))
     }
 
     fn ReplicaMap(&self, tkn: usize, key: u64, val: u64) -> u64 {
         let tkn = unsafe { ReplicaToken::new(tkn) };
-        self.inner.execute_mut(Modify::Map(key, val), tkn)
+        self.0.recorded(Op::Write(key, val), || self.0.inner.execute_mut(Modify::Map(key, val), tkn))
     }
-}
 
-pub fn createReplica(log: &'static LogWrapper) -> &'static mut ReplicaWrapper {
-    let inner = Replica::new(&log.0);
-    Box::leak(Box::new(ReplicaWrapper{ log, inner }))
-}
-
-pub struct LogWrapper(Arc<Log<'static, Modify>>);
+    /// Resolves `key`, taking a page fault through the log on a miss.
+    fn ReplicaResolveOrFault(&self, tkn: usize, key: u64) -> u64 {
+        let tkn = unsafe { ReplicaToken::new(tkn) };
+        match self.0.inner.execute(Access::TryResolve(key), tkn) {
+            NOT_PRESENT => self.0.inner.execute_mut(Modify::Fault(key), tkn),
+            paddr => paddr,
+        }
+    }
 
-pub fn createLog() -> &'static mut LogWrapper {
-    const TWO_MIB: usize = 2*1024*1024;
-    let log = Arc::new(Log::new(TWO_MIB));
+    /// Maps an anonymous read-write region of `len` bytes, returns its base.
+    fn ReplicaMmap(&self, tkn: usize, len: usize) -> u64 {
+        let tkn = unsafe { ReplicaToken::new(tkn) };
+        let op = Modify::Mmap(String::from("anon"), len, MapAction::ReadWriteUser);
+        self.0.inner.execute_mut(op, tkn)
+    }
 
-    Box::leak(Box::new(LogWrapper(log)))
+    fn ReplicaMunmap(&self, tkn: usize, base: u64) -> bool {
+        let tkn = unsafe { ReplicaToken::new(tkn) };
+        self.0.inner.execute_mut(Modify::Munmap(base), tkn) == 1
+    }
 }
 
-
 pub struct VSpace {
     pub pml4: Pin<Box<PML4>>,
     pub mem_counter: usize,
     mapping: mmap::MemoryMap,
     mem_ptr: *mut u8,
+    /// Physical address we pretend `mem_ptr` is at.
+    ///
+    /// Page-table entries store `phys_base` + offset into the backing
+    /// region, so the page-tables don't depend on where the region got
+    /// mapped in our process and can be exported as an image.
+    phys_base: u64,
+    /// Optional frame -> virtual pages index, see [`VSpace::enable_rmap`].
+    rmap: Option<ReverseMap>,
+    /// mmap-style regions, see `regions.rs`.
+    regions: RegionManager,
+    /// Consulted on translation misses, see `fault.rs`.
+    fault_handler: Option<Arc<dyn PageFaultHandler>>,
+    fault_stats: FaultStats,
+    /// Where applied operations get logged, see `trace.rs`.
+    trace: Option<TraceWriter>,
+    /// Decides which allocations fail, see `inject.rs`.
+    alloc_faults: AllocFaults,
+    /// What `map_generic` did so far (while `journaling`), to undo a
+    /// mapping that fails half-way.
+    journal: Vec<Undo>,
+    journaling: bool,
     //allocs: Vec<(*mut u8, usize)>,
 }
 
@@ -221,12 +445,37 @@ unsafe impl Send for VSpace {}
 #[derive(Debug, PartialEq, Clone)]
 pub enum Modify {
    Map(u64, u64),
+   /// Map a new region `(name, len, rights)`, returns its base.
+   Mmap(String, usize, MapAction),
+   /// Unmap the region at the given base.
+   Munmap(u64),
+   /// Resize the region at `base` to `len`, returns its (new) base.
+   Mremap(u64, usize),
+   /// Map device memory `(vbase, pbase, len)` uncached.
+   MapDevice(u64, u64, usize),
+   /// Resolve a translation miss at the address, returns the translation
+   /// (0 if the fault was rejected).
+   Fault(u64),
 }
 
 /// We support an immutable read operation to lookup a key from the hashmap.
 #[derive(Debug, PartialEq, Clone)]
 pub enum Access {
    Resolve(u64),
+   /// Like `Resolve` but returns `NOT_PRESENT` for unmapped addresses.
+   TryResolve(u64),
+}
+
+impl FfiOp for Modify {
+    fn from_ffi(key: u64, val: u64) -> Self {
+        Modify::Map(key, val)
+    }
+}
+
+impl FfiOp for Access {
+    fn from_ffi(key: u64, _b: u64) -> Self {
+        Access::Resolve(key)
+    }
 }
 
 /// The Dispatch traits executes `ReadOperation` (our Access enum)
@@ -239,9 +488,17 @@ impl Dispatch for VSpace {
 
    /// The `dispatch` function applies the immutable operations.
    fn dispatch(&self, op: Self::ReadOperation) -> Self::Response {
-       match op {
+       let ret = match op {
            Access::Resolve(key) => self.resolveWrapped(key),
+           Access::TryResolve(key) => self
+               .resolve_addr(VAddr::from(key))
+               .map(|pa| pa.as_u64())
+               .unwrap_or(NOT_PRESENT),
+       };
+       if let Some(trace) = &self.trace {
+           trace.record(&Record::Read(op, ret));
        }
+       ret
    }
 
    /// The `dispatch_mut` function applies the mutable operations.
@@ -249,9 +506,26 @@ impl Dispatch for VSpace {
        &mut self,
        op: Self::WriteOperation,
    ) -> Self::Response {
-       match op {
-           Modify::Map(key, value) => self.mapGenericWrapped(key, value, 0x1000) as u64,
+       let ret = match &op {
+           Modify::Map(key, value) => self.mapGenericWrapped(*key, *value, 0x1000) as u64,
+           Modify::Mmap(name, len, rights) => {
+               self.mmap(name, *len, *rights).map(|v| v.as_u64()).unwrap_or(0x0)
+           }
+           Modify::Munmap(base) => self.munmap(VAddr::from(*base)).is_ok() as u64,
+           Modify::Mremap(base, len) => {
+               self.mremap(VAddr::from(*base), *len).map(|v| v.as_u64()).unwrap_or(0x0)
+           }
+           Modify::MapDevice(vbase, pbase, len) => self
+               .map_device(VAddr::from(*vbase), (PAddr::from(*pbase), *len), CacheType::Uncached)
+               .is_ok() as u64,
+           Modify::Fault(addr) => {
+               self.handle_fault(VAddr::from(*addr)).map(|pa| pa.as_u64()).unwrap_or(0x0)
+           }
+       };
+       if let Some(trace) = &self.trace {
+           trace.record(&Record::Write(op, ret));
        }
+       ret
    }
 }
 
@@ -267,6 +541,48 @@ impl Dispatch for VSpace {
         pub fn resolveWrapped(self: &mut VSpace, vbase: u64) -> u64;
  */
 
+/// A change `map_generic` made, see [`VSpace::rollback`].
+enum Undo {
+    /// A page-table entry was overwritten, and had this value.
+    Entry(*mut u64, u64),
+    RmapInsert(VAddr, PAddr),
+    RmapRemove(VAddr, PAddr, usize),
+}
+
+/// The page-table entry that translates an address, see [`VSpace::leaf`].
+enum Leaf<'b> {
+    /// Nothing is mapped in the naturally aligned block of this size.
+    Unmapped(usize),
+    Huge(&'b mut PDPTEntry),
+    Large(&'b mut PDEntry),
+    Base(&'b mut PTEntry),
+}
+
+impl<'b> Leaf<'b> {
+    fn size(&self) -> usize {
+        match self {
+            Leaf::Unmapped(size) => *size,
+            Leaf::Huge(_) => HUGE_PAGE_SIZE,
+            Leaf::Large(_) => LARGE_PAGE_SIZE,
+            Leaf::Base(_) => BASE_PAGE_SIZE,
+        }
+    }
+
+    fn is_unmapped(&self) -> bool {
+        matches!(self, Leaf::Unmapped(_))
+    }
+
+    /// The raw page-table entry, if something is mapped.
+    fn entry(&self) -> Option<u64> {
+        match self {
+            Leaf::Unmapped(_) => None,
+            Leaf::Huge(e) => Some(e.0),
+            Leaf::Large(e) => Some(e.0),
+            Leaf::Base(e) => Some(e.0),
+        }
+    }
+}
+
 impl Drop for VSpace {
     fn drop(&mut self) {
         /*unsafe {
@@ -291,6 +607,10 @@ pub const ONE_GIB: usize = 1024 * 1024 * 1024;
 // sudo sh -c "echo 16 > /sys/devices/system/node/node3/hugepages/hugepages-1048576kB/nr_hugepages"
 
 pub fn alloc(size: usize, ps: usize) -> mmap::MemoryMap {
+    try_alloc(size, ps).expect("can't get memory, do we have reserved huge-pages?")
+}
+
+pub fn try_alloc(size: usize, ps: usize) -> Result<mmap::MemoryMap, AllocError> {
     use libc;
     use libc::{MAP_ANON, MAP_HUGETLB, MAP_POPULATE, MAP_SHARED};
     use mmap;
@@ -317,9 +637,9 @@ pub fn alloc(size: usize, ps: usize) -> mmap::MemoryMap {
         mmap::MapOption::MapReadable,
         mmap::MapOption::MapWritable,
     ];
-    let res = mmap::MemoryMap::new(size, &flags).expect("can't allocate?");
+    let res = mmap::MemoryMap::new(size, &flags).map_err(|_| AllocError { bytes: size })?;
     if res.data().is_null() {
-        panic!("can't get memory, do we have reserved huge-pages?");
+        return Err(AllocError { bytes: size });
     }
 
     // Make sure memory is not swapped:
@@ -329,27 +649,14 @@ pub fn alloc(size: usize, ps: usize) -> mmap::MemoryMap {
     //}
     //assert!(lock_ret == 0);
 
-    res
+    Ok(res)
 }
 
 // cpp glue fun
 pub fn createVSpace() -> &'static mut VSpace {
     //env_logger::try_init();
     //log::error!("createVSpace");
-    let mapping = alloc(3*ONE_GIB, ONE_GIB); 
-    let mem_ptr = mapping.data();
-
-    //unsafe { alloc::alloc::alloc(core::alloc::Layout::from_size_align_unchecked(1075851264, 4096)) };
-
-    let vs = Box::leak(Box::new(VSpace {
-        pml4: Box::pin(
-            [PML4Entry::new(PAddr::from(0x0u64), PML4Flags::empty()); PAGE_SIZE_ENTRIES],
-        ),
-        //allocs: Vec::with_capacity(1024),
-        mapping,
-        mem_counter: 4096,
-        mem_ptr
-    }));
+    let vs = Box::leak(Box::new(VSpace::new(3*ONE_GIB, 0x0)));
 
     for i in 0..VSPACE_RANGE / 4096 {
         assert!(vs.map_generic(
@@ -365,25 +672,11 @@ pub fn createVSpace() -> &'static mut VSpace {
 
 impl Default for VSpace {
     fn default() -> VSpace {
-        
-        let mapping = alloc(3*ONE_GIB, ONE_GIB); 
-        let mem_ptr = mapping.data();
-
         // make sure the memory for ptable is some contiguous block
         // this allows Linux / THP to kick in and increase tput by ~60Mops
         // make sure to do:
         // sudo sh -c "echo always > /sys/kernel/mm/transparent_hugepage/enabled"
-        //let mem_ptr = unsafe { alloc::alloc::alloc(core::alloc::Layout::from_size_align_unchecked(1075851264, 4096)) };
-
-        let mut vs = VSpace {
-            pml4: Box::pin(
-                [PML4Entry::new(PAddr::from(0x0u64), PML4Flags::empty()); PAGE_SIZE_ENTRIES],
-            ),
-            mapping,
-            mem_counter: 4096,
-            mem_ptr
-            //allocs: Vec::with_capacity(1024),
-        };
+        let mut vs = VSpace::new(3*ONE_GIB, 0x0);
         for i in 0..VSPACE_RANGE / 4096 {
             assert!(vs.map_generic(
                 VAddr::from(i * 4096),
@@ -394,11 +687,121 @@ impl Default for VSpace {
 
         log::error!("vs.mem_counter {}", vs.mem_counter);
 
+        // Same as `VSpace::partition(0, 1)` as far as replaying goes
+        vs.trace_from_env(0, 1);
+        vs.alloc_faults_from_env();
         vs
     }
 }
 
 impl VSpace {
+    /// Creates an empty address space.
+    ///
+    /// Page-tables are allocated from a fresh `arena_size` bytes region
+    /// which is assumed to be at physical address `phys_base`.
+    pub fn new(arena_size: usize, phys_base: u64) -> VSpace {
+        VSpace::with_alloc_faults(arena_size, phys_base, FaultPolicy::Never)
+            .expect("can't get memory, do we have reserved huge-pages?")
+    }
+
+    /// Like [`VSpace::new`], but allocations (starting with the arena)
+    /// fail according to `policy`.
+    pub fn with_alloc_faults(
+        arena_size: usize,
+        phys_base: u64,
+        policy: FaultPolicy,
+    ) -> Result<VSpace, AllocError> {
+        assert_eq!(phys_base % BASE_PAGE_SIZE as u64, 0);
+        let mut alloc_faults = AllocFaults::new(policy);
+        alloc_faults.allocate(arena_size)?;
+        let mapping = try_alloc(arena_size, ONE_GIB)?;
+        let mem_ptr = mapping.data();
+
+        Ok(VSpace {
+            pml4: Box::pin(
+                [PML4Entry::new(PAddr::from(0x0u64), PML4Flags::empty()); PAGE_SIZE_ENTRIES],
+            ),
+            //allocs: Vec::with_capacity(1024),
+            mapping,
+            // Keep the first page unused so no page-table ends up at `phys_base`
+            mem_counter: 4096,
+            mem_ptr,
+            phys_base,
+            rmap: None,
+            regions: RegionManager::default(),
+            fault_handler: None,
+            fault_stats: FaultStats::default(),
+            trace: None,
+            alloc_faults,
+            journal: Vec::new(),
+            journaling: false,
+        })
+    }
+
+    fn kernel_vaddr_to_paddr(&self, v: VAddr) -> PAddr {
+        let offset = v.as_u64() - self.mem_ptr as u64;
+        debug_assert!(offset < self.mapping.len() as u64, "{:#x} not in arena", v);
+        PAddr::from(self.phys_base + offset)
+    }
+
+    fn paddr_to_kernel_vaddr(&self, p: PAddr) -> VAddr {
+        let offset = p.as_u64() - self.phys_base;
+        debug_assert!(offset < self.mapping.len() as u64, "{:#x} not in arena", p);
+        VAddr::from(self.mem_ptr as u64 + offset)
+    }
+
+    /// Exports the page-tables as a position-independent image.
+    ///
+    /// The image is meant to be loaded at `phys_base`; it contains the
+    /// used part of the arena followed by a copy of the PML4 table, whose
+    /// physical address (i.e., the value for CR3) is returned as well.
+    pub fn export_image(&self) -> (Vec<u8>, PAddr) {
+        let used = unsafe { std::slice::from_raw_parts(self.mem_ptr, self.mem_counter) };
+        let mut image = used.to_vec();
+        let pml4_offset = image.len();
+        for entry in self.pml4.iter() {
+            image.extend_from_slice(&entry.0.to_le_bytes());
+        }
+        (image, PAddr::from(self.phys_base + pml4_offset as u64))
+    }
+
+    /// Creates partition `idx` of an address space split over `nlogs` logs.
+    ///
+    /// The partition only holds the identity mappings of the 1 GiB regions
+    /// that [`partitioned::log_index`] assigns to it, so its page-table
+    /// arena is sized accordingly.
+    pub fn partition(idx: usize, nlogs: usize) -> VSpace {
+        let mut vs = VSpace::partition_untraced(idx, nlogs);
+        vs.trace_from_env(idx, nlogs);
+        vs
+    }
+
+    /// Like `partition`, but never traced (`VSPACE_TRACE_DIR` is ignored).
+    pub fn partition_untraced(idx: usize, nlogs: usize) -> VSpace {
+        assert!(idx < nlogs);
+        // Page-tables for the whole range need a bit more than 1 GiB
+        let arena_size = ((3 * ONE_GIB / nlogs) + ONE_GIB - 1) / ONE_GIB * ONE_GIB;
+        let mut vs = VSpace::new(arena_size, 0x0);
+
+        for region in 0..VSPACE_RANGE / HUGE_PAGE_SIZE as u64 {
+            let region_base = region * HUGE_PAGE_SIZE as u64;
+            if partitioned::log_index(region_base, nlogs) != idx {
+                continue;
+            }
+            for i in 0..(HUGE_PAGE_SIZE / BASE_PAGE_SIZE) as u64 {
+                let addr = region_base + i * 4096;
+                assert!(vs.map_generic(
+                    VAddr::from(addr),
+                    (PAddr::from(addr), 4096),
+                    MapAction::ReadWriteExecuteUser,
+                ).is_ok());
+            }
+        }
+
+        vs.alloc_faults_from_env();
+        vs
+    }
+
     pub fn mapGenericWrapped(
         self: &mut VSpace,
         vbase: u64,
@@ -411,21 +814,47 @@ impl VSpace {
             (PAddr::from(pregion), pregion_len),
             rights,
         );
+        #[cfg(debug_assertions)]
+        self.check_rmap();
 
         r.is_ok()
     }
 
+    /// Maps `pregion` at `vbase`.
+    ///
+    /// Fails without modifying the page-tables if it runs out of memory
+    /// for page-tables, or runs into a large page, half-way.
     pub fn map_generic(
         &mut self,
         vbase: VAddr,
         pregion: (PAddr, usize),
-        rights: MapAction,
+        rights: impl Into<MapRights>,
+    ) -> Result<(), VSpaceError> {
+        let mem_counter = self.mem_counter;
+        self.journal.clear();
+        self.journaling = true;
+        let r = self.map_range(vbase, pregion, rights.into());
+        self.journaling = false;
+
+        if r.is_err() {
+            self.rollback();
+            // Page-tables allocated on the way are unreachable again
+            self.mem_counter = mem_counter;
+        }
+        r
+    }
+
+    fn map_range(
+        &mut self,
+        vbase: VAddr,
+        pregion: (PAddr, usize),
+        rights: MapRights,
     ) -> Result<(), VSpaceError> {
         let (pbase, psize) = pregion;
         assert_eq!(pbase % BASE_PAGE_SIZE, 0);
         assert_eq!(psize % BASE_PAGE_SIZE, 0);
         assert_eq!(vbase % BASE_PAGE_SIZE, 0);
-        assert_ne!(rights, MapAction::None);
+        assert_ne!(rights.action, MapAction::None);
 
         debug!(
             "map_generic {:#x} -- {:#x} -> {:#x} -- {:#x} {}",
@@ -439,7 +868,9 @@ impl VSpace {
         let pml4_idx = pml4_index(vbase);
         if !self.pml4[pml4_idx].is_present() {
             trace!("New PDPDT for {:?} @ PML4[{}]", vbase, pml4_idx);
-            self.pml4[pml4_idx] = self.new_pdpt();
+            let entry = self.new_pdpt().map_err(|_| VSpaceError { at: vbase.as_u64() })?;
+            let slot: *mut u64 = &mut self.pml4[pml4_idx].0;
+            self.set_entry(slot, entry.0);
         }
         assert!(
             self.pml4[pml4_idx].is_present(),
@@ -466,10 +897,12 @@ impl VSpace {
                 // and have 1 GiB chunks to map:
                 while mapped < psize && ((psize - mapped) >= HUGE_PAGE_SIZE) && pdpt_idx < 512 {
                     assert!(!pdpt[pdpt_idx].is_present());
-                    pdpt[pdpt_idx] = PDPTEntry::new(
+                    let entry = PDPTEntry::new(
                         pbase + mapped,
                         PDPTFlags::P | PDPTFlags::PS | rights.to_pdpt_rights(),
                     );
+                    self.set_entry(&mut pdpt[pdpt_idx].0, entry.0);
+                    self.rmap_insert(vbase + mapped, pbase + mapped, HUGE_PAGE_SIZE);
                     trace!(
                         "Mapped 1GiB range {:#x} -- {:#x} -> {:#x} -- {:#x}",
                         vbase + mapped,
@@ -490,7 +923,7 @@ impl VSpace {
                         (pbase + mapped),
                         pbase + (psize - mapped),
                     );
-                    return self.map_generic(
+                    return self.map_range(
                         vbase + mapped,
                         ((pbase + mapped), psize - mapped),
                         rights,
@@ -506,7 +939,8 @@ impl VSpace {
                     vbase,
                     vbase + psize
                 );
-                pdpt[pdpt_idx] = self.new_pd();
+                let entry = self.new_pd().map_err(|_| VSpaceError { at: vbase.as_u64() })?;
+                self.set_entry(&mut pdpt[pdpt_idx].0, entry.0);
             }
         }
         assert!(
@@ -539,10 +973,12 @@ impl VSpace {
                         return Err(VSpaceError { at: vbase.as_u64() });
                     }
 
-                    pd[pd_idx] = PDEntry::new(
+                    let entry = PDEntry::new(
                         pbase + mapped,
                         PDFlags::P | PDFlags::PS | rights.to_pd_rights(),
                     );
+                    self.set_entry(&mut pd[pd_idx].0, entry.0);
+                    self.rmap_insert(vbase + mapped, pbase + mapped, LARGE_PAGE_SIZE);
                     trace!(
                         "Mapped 2 MiB region {:#x} -- {:#x} -> {:#x} -- {:#x}",
                         vbase + mapped,
@@ -563,7 +999,7 @@ impl VSpace {
                         (pbase + mapped),
                         pbase + (psize - mapped),
                     );
-                    return self.map_generic(
+                    return self.map_range(
                         vbase + mapped,
                         ((pbase + mapped), psize - mapped),
                         rights,
@@ -579,7 +1015,8 @@ impl VSpace {
                     vbase,
                     vbase + psize
                 );
-                pd[pd_idx] = self.new_pt();
+                let entry = self.new_pt().map_err(|_| VSpaceError { at: vbase.as_u64() })?;
+                self.set_entry(&mut pd[pd_idx].0, entry.0);
             }
         }
         assert!(
@@ -597,7 +1034,12 @@ impl VSpace {
         while mapped < psize && pt_idx < 512 {
             // XXX: allow updates
             //if !pt[pt_idx].is_present() {
-                pt[pt_idx] = PTEntry::new(pbase + mapped, PTFlags::P | rights.to_pt_rights());
+                if pt[pt_idx].is_present() {
+                    self.rmap_remove(vbase + mapped, pt[pt_idx].address(), BASE_PAGE_SIZE);
+                }
+                let entry = PTEntry::new(pbase + mapped, PTFlags::P | rights.to_pt_rights());
+                self.set_entry(&mut pt[pt_idx].0, entry.0);
+                self.rmap_insert(vbase + mapped, pbase + mapped, BASE_PAGE_SIZE);
             //} else {
             //    return Err(VSpaceError { at: vbase.as_u64() });
             //}
@@ -615,29 +1057,152 @@ impl VSpace {
                 (pbase + mapped),
                 pbase + (psize - mapped),
             );
-            return self.map_generic(vbase + mapped, ((pbase + mapped), psize - mapped), rights);
+            return self.map_range(vbase + mapped, ((pbase + mapped), psize - mapped), rights);
         } else {
             // else we're done here, return
             Ok(())
         }
     }
 
+    /// Starts maintaining the reverse map, seeded from the current page-tables.
+    pub fn enable_rmap(&mut self) {
+        let mut rmap = ReverseMap::new();
+        for (vaddr, paddr, size) in self.mappings() {
+            rmap.insert(vaddr, paddr, size);
+        }
+        self.rmap = Some(rmap);
+    }
+
+    /// Returns `(vaddr, page size)` of every virtual page mapping `paddr`.
+    pub fn reverse_lookup(&self, paddr: PAddr) -> Vec<(VAddr, usize)> {
+        self.rmap
+            .as_ref()
+            .expect("reverse_lookup needs enable_rmap()")
+            .lookup(paddr)
+    }
+
+    fn rmap_insert(&mut self, vaddr: VAddr, paddr: PAddr, size: usize) {
+        if let Some(rmap) = self.rmap.as_mut() {
+            rmap.insert(vaddr, paddr, size);
+            if self.journaling {
+                self.journal.push(Undo::RmapInsert(vaddr, paddr));
+            }
+        }
+    }
+
+    fn rmap_remove(&mut self, vaddr: VAddr, paddr: PAddr, size: usize) {
+        if let Some(rmap) = self.rmap.as_mut() {
+            rmap.remove(vaddr, paddr);
+            if self.journaling {
+                self.journal.push(Undo::RmapRemove(vaddr, paddr, size));
+            }
+        }
+    }
+
+    /// Writes a page-table entry, journaling the old value.
+    fn set_entry(&mut self, slot: *mut u64, value: u64) {
+        unsafe {
+            if self.journaling {
+                self.journal.push(Undo::Entry(slot, *slot));
+            }
+            *slot = value;
+        }
+    }
+
+    /// Undoes everything in the journal, newest first.
+    fn rollback(&mut self) {
+        while let Some(undo) = self.journal.pop() {
+            match undo {
+                Undo::Entry(slot, old) => unsafe { *slot = old },
+                Undo::RmapInsert(vaddr, paddr) => self.rmap.as_mut().unwrap().remove(vaddr, paddr),
+                Undo::RmapRemove(vaddr, paddr, size) => {
+                    self.rmap.as_mut().unwrap().insert(vaddr, paddr, size)
+                }
+            }
+        }
+    }
+
+    /// Checks that the reverse map agrees with a full page-table walk.
+    #[cfg(debug_assertions)]
+    fn check_rmap(&self) {
+        if let Some(rmap) = self.rmap.as_ref() {
+            let mut walked: Vec<(u64, u64, usize)> = self
+                .mappings()
+                .into_iter()
+                .map(|(v, p, size)| (p.as_u64(), v.as_u64(), size))
+                .collect();
+            walked.sort_unstable();
+            assert_eq!(walked, rmap.records(), "rmap diverged from page-tables");
+        }
+    }
+
+    /// Returns `(vaddr, paddr, page size)` of every leaf entry in the page-tables.
+    pub fn mappings(&self) -> Vec<(VAddr, PAddr, usize)> {
+        let mut mappings = Vec::new();
+        self.for_each_leaf(|vaddr, paddr, size, _entry| mappings.push((vaddr, paddr, size)));
+        mappings
+    }
+
+    /// Calls `f(vaddr, paddr, page size, raw leaf entry)` for every mapped
+    /// page, in address order.
+    pub fn for_each_leaf<F: FnMut(VAddr, PAddr, usize, u64)>(&self, mut f: F) {
+        for pml4_idx in 0..PAGE_SIZE_ENTRIES {
+            if !self.pml4[pml4_idx].is_present() {
+                continue;
+            }
+            let pdpt = self.get_pdpt(self.pml4[pml4_idx]);
+            for pdpt_idx in 0..PAGE_SIZE_ENTRIES {
+                if !pdpt[pdpt_idx].is_present() {
+                    continue;
+                }
+                let pdpt_vaddr = PML4_SLOT_SIZE * pml4_idx + HUGE_PAGE_SIZE * pdpt_idx;
+                if pdpt[pdpt_idx].is_page() {
+                    f(VAddr::from(pdpt_vaddr), huge_page_address(pdpt[pdpt_idx]), HUGE_PAGE_SIZE, pdpt[pdpt_idx].0);
+                    continue;
+                }
+                let pd = self.get_pd(pdpt[pdpt_idx]);
+                for pd_idx in 0..PAGE_SIZE_ENTRIES {
+                    if !pd[pd_idx].is_present() {
+                        continue;
+                    }
+                    let pd_vaddr = pdpt_vaddr + LARGE_PAGE_SIZE * pd_idx;
+                    if pd[pd_idx].is_page() {
+                        f(VAddr::from(pd_vaddr), large_page_address(pd[pd_idx]), LARGE_PAGE_SIZE, pd[pd_idx].0);
+                        continue;
+                    }
+                    let pt = self.get_pt(pd[pd_idx]);
+                    for pt_idx in 0..PAGE_SIZE_ENTRIES {
+                        if pt[pt_idx].is_present() {
+                            let vaddr = pd_vaddr + BASE_PAGE_SIZE * pt_idx;
+                            f(VAddr::from(vaddr), pt[pt_idx].address(), BASE_PAGE_SIZE, pt[pt_idx].0);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     /// A simple wrapper function for allocating just one page.
-    fn allocate_one_page(&mut self) -> PAddr {
+    fn allocate_one_page(&mut self) -> Result<PAddr, AllocError> {
         log::info!("allocate a page...");
         self.mem_counter += 4096;
         self.allocate_pages(1, ResourceType::PageTable)
     }
 
-    fn allocate_pages(&mut self, how_many: usize, _typ: ResourceType) -> PAddr {
+    fn allocate_pages(&mut self, how_many: usize, _typ: ResourceType) -> Result<PAddr, AllocError> {
         log::info!("allocate_pages {}...", how_many);
+        let bytes = how_many * BASE_PAGE_SIZE;
+        self.alloc_faults.allocate(bytes)?;
+        if self.mem_counter + bytes > self.mapping.len() {
+            // Increase the arena size of the `VSpace` if this happens
+            return Err(AllocError { bytes });
+        }
 
         let new_region: *mut u8 = unsafe {
             /*alloc::alloc::alloc(core::alloc::Layout::from_size_align_unchecked(
                 how_many * BASE_PAGE_SIZE,
                 4096,
             ))*/
-            assert!(self.mem_counter < 3*ONE_GIB); // if this triggers you need to adjust the alloc size of `mem_ptr`
             self.mem_ptr.offset(self.mem_counter as isize)
         };
         self.mem_counter += how_many * 4096;
@@ -650,37 +1215,37 @@ impl VSpace {
         }
         //self.allocs.push((new_region, how_many * BASE_PAGE_SIZE));
 
-        kernel_vaddr_to_paddr(VAddr::from(new_region as usize))
+        Ok(self.kernel_vaddr_to_paddr(VAddr::from(new_region as usize)))
     }
 
-    fn new_pt(&mut self) -> PDEntry {
-        let paddr: PAddr = self.allocate_one_page();
-        return PDEntry::new(paddr, PDFlags::P | PDFlags::RW | PDFlags::US);
+    fn new_pt(&mut self) -> Result<PDEntry, AllocError> {
+        let paddr: PAddr = self.allocate_one_page()?;
+        return Ok(PDEntry::new(paddr, PDFlags::P | PDFlags::RW | PDFlags::US));
     }
 
-    fn new_pd(&mut self) -> PDPTEntry {
-        let paddr: PAddr = self.allocate_one_page();
-        return PDPTEntry::new(paddr, PDPTFlags::P | PDPTFlags::RW | PDPTFlags::US);
+    fn new_pd(&mut self) -> Result<PDPTEntry, AllocError> {
+        let paddr: PAddr = self.allocate_one_page()?;
+        return Ok(PDPTEntry::new(paddr, PDPTFlags::P | PDPTFlags::RW | PDPTFlags::US));
     }
 
-    fn new_pdpt(&mut self) -> PML4Entry {
-        let paddr: PAddr = self.allocate_one_page();
-        return PML4Entry::new(paddr, PML4Flags::P | PML4Flags::RW | PML4Flags::US);
+    fn new_pdpt(&mut self) -> Result<PML4Entry, AllocError> {
+        let paddr: PAddr = self.allocate_one_page()?;
+        return Ok(PML4Entry::new(paddr, PML4Flags::P | PML4Flags::RW | PML4Flags::US));
     }
 
     /// Resolve a PDEntry to a page table.
     fn get_pt<'b>(&self, entry: PDEntry) -> &'b mut PT {
-        unsafe { transmute::<VAddr, &mut PT>(paddr_to_kernel_vaddr(entry.address())) }
+        unsafe { transmute::<VAddr, &mut PT>(self.paddr_to_kernel_vaddr(entry.address())) }
     }
 
     /// Resolve a PDPTEntry to a page directory.
     fn get_pd<'b>(&self, entry: PDPTEntry) -> &'b mut PD {
-        unsafe { transmute::<VAddr, &mut PD>(paddr_to_kernel_vaddr(entry.address())) }
+        unsafe { transmute::<VAddr, &mut PD>(self.paddr_to_kernel_vaddr(entry.address())) }
     }
 
     /// Resolve a PML4Entry to a PDPT.
     fn get_pdpt<'b>(&self, entry: PML4Entry) -> &'b mut PDPT {
-        unsafe { transmute::<VAddr, &mut PDPT>(paddr_to_kernel_vaddr(entry.address())) }
+        unsafe { transmute::<VAddr, &mut PDPT>(self.paddr_to_kernel_vaddr(entry.address())) }
     }
 
     pub fn resolveWrapped(&self, addr: u64) -> u64 {
@@ -699,8 +1264,7 @@ impl VSpace {
                 if pdpt[pdpt_idx].is_page() {
                     // Page is a 1 GiB mapping, we have to return here
                     let page_offset = addr.huge_page_offset();
-                    unreachable!("dont go here");
-                    return Some(pdpt[pdpt_idx].address() + page_offset);
+                    return Some(huge_page_address(pdpt[pdpt_idx]) + page_offset);
                 } else {
                     let pd_idx = pd_index(addr);
                     let pd = self.get_pd(pdpt[pdpt_idx]);
@@ -708,8 +1272,7 @@ impl VSpace {
                         if pd[pd_idx].is_page() {
                             // Encountered a 2 MiB mapping, we have to return here
                             let page_offset = addr.large_page_offset();
-                            unreachable!("dont go here");
-                            return Some(pd[pd_idx].address() + page_offset);
+                            return Some(large_page_address(pd[pd_idx]) + page_offset);
                         } else {
                             let pt_idx = pt_index(addr);
                             let pt = self.get_pt(pd[pd_idx]);
@@ -723,12 +1286,8 @@ impl VSpace {
                     }
                 }
             }
-        }else {
-            // log::error!("pml4 not present {:#x}", addr);
-            unreachable!("dont go here");
         }
-        
-        unreachable!("dont go here");
+
         None
     }
 
@@ -741,9 +1300,105 @@ impl VSpace {
     ) -> Result<(PAddr, usize), VSpaceError> {
         assert_eq!(base % BASE_PAGE_SIZE, 0, "base is not page-aligned");
         assert_eq!(size % BASE_PAGE_SIZE, 0, "size is not page-aligned");
-        self.map_generic(base, (paddr, size), rights)?;
+        let r = self.map_generic(base, (paddr, size), rights);
+        #[cfg(debug_assertions)]
+        self.check_rmap();
+        r?;
         Ok((paddr, size))
     }
+
+    /// Maps device memory (e.g., MMIO registers) with the given cache type.
+    pub fn map_device(
+        &mut self,
+        vbase: VAddr,
+        pregion: (PAddr, usize),
+        cache: CacheType,
+    ) -> Result<(), VSpaceError> {
+        let rights = MapRights {
+            cache,
+            ..MapRights::from(MapAction::ReadWriteUser)
+        };
+        self.map_generic(vbase, pregion, rights)
+    }
+
+    /// Returns the leaf entry translating `addr`.
+    fn leaf<'b>(&self, addr: VAddr) -> Leaf<'b> {
+        let pml4_idx = pml4_index(addr);
+        if !self.pml4[pml4_idx].is_present() {
+            return Leaf::Unmapped(PML4_SLOT_SIZE);
+        }
+        let pdpt = self.get_pdpt(self.pml4[pml4_idx]);
+        let pdpt_idx = pdpt_index(addr);
+        if !pdpt[pdpt_idx].is_present() {
+            return Leaf::Unmapped(HUGE_PAGE_SIZE);
+        }
+        if pdpt[pdpt_idx].is_page() {
+            return Leaf::Huge(&mut pdpt[pdpt_idx]);
+        }
+        let pd = self.get_pd(pdpt[pdpt_idx]);
+        let pd_idx = pd_index(addr);
+        if !pd[pd_idx].is_present() {
+            return Leaf::Unmapped(LARGE_PAGE_SIZE);
+        }
+        if pd[pd_idx].is_page() {
+            return Leaf::Large(&mut pd[pd_idx]);
+        }
+        let pt = self.get_pt(pd[pd_idx]);
+        let pt_idx = pt_index(addr);
+        if !pt[pt_idx].is_present() {
+            return Leaf::Unmapped(BASE_PAGE_SIZE);
+        }
+        Leaf::Base(&mut pt[pt_idx])
+    }
+
+    /// Removes all mappings in `[vbase, vbase + size)`.
+    ///
+    /// Fails without modifying anything if a large page straddles the
+    /// boundary of the range. Page-table pages are not reclaimed.
+    pub fn unmap(&mut self, vbase: VAddr, size: usize) -> Result<(), VSpaceError> {
+        assert_eq!(vbase % BASE_PAGE_SIZE, 0, "base is not page-aligned");
+        assert_eq!(size % BASE_PAGE_SIZE, 0, "size is not page-aligned");
+        let end = vbase.as_usize() + size;
+
+        // Make sure we don't have to split any pages first
+        let mut cur = vbase.as_usize();
+        while cur < end {
+            let leaf = self.leaf(VAddr::from(cur));
+            let page_size = leaf.size();
+            let page_base = cur & !(page_size - 1);
+            if !leaf.is_unmapped() && (page_base != cur || cur + page_size > end) {
+                return Err(VSpaceError { at: cur as u64 });
+            }
+            cur = page_base + page_size;
+        }
+
+        let mut cur = vbase.as_usize();
+        while cur < end {
+            let vaddr = VAddr::from(cur);
+            let leaf = self.leaf(vaddr);
+            let page_size = leaf.size();
+            match leaf {
+                Leaf::Unmapped(_) => {}
+                Leaf::Huge(e) => {
+                    self.rmap_remove(vaddr, huge_page_address(*e), HUGE_PAGE_SIZE);
+                    *e = PDPTEntry::new(PAddr::from(0x0u64), PDPTFlags::empty());
+                }
+                Leaf::Large(e) => {
+                    self.rmap_remove(vaddr, large_page_address(*e), LARGE_PAGE_SIZE);
+                    *e = PDEntry::new(PAddr::from(0x0u64), PDFlags::empty());
+                }
+                Leaf::Base(e) => {
+                    self.rmap_remove(vaddr, e.address(), BASE_PAGE_SIZE);
+                    *e = PTEntry::new(PAddr::from(0x0u64), PTFlags::empty());
+                }
+            }
+            cur = (cur & !(page_size - 1)) + page_size;
+        }
+
+        #[cfg(debug_assertions)]
+        self.check_rmap();
+        Ok(())
+    }
 }
 /*
 mod mkbench;
@@ -869,6 +1524,392 @@ fn main() {
 }
 */
 
+#[test]
+fn counter_replica() {
+    let log = createCounterLog();
+    let replica = createCounterReplica(log);
+    let history = createHistory();
+    replica.RecordHistory(history);
+    let tkn = replica.RegisterWrapper();
+    assert_eq!(replica.execute_mut(tkn, 0, 0), 0);
+    assert_eq!(replica.execute_mut(tkn, 0, 0), 1);
+    assert_eq!(replica.execute(tkn, 0, 0), 2);
+    assert!(history::check::<history::CounterSpec>(&history.events()).is_ok());
+    assert!(history::check::<history::VSpaceSpec>(&history.events()).is_err());
+}
+
+#[test]
+fn hashmap_replica() {
+    let log = createHashMapLog();
+    let replica = createHashMapReplica(log);
+    let history = createHistory();
+    replica.RecordHistory(history);
+    let tkn = replica.RegisterWrapper();
+    assert_eq!(replica.execute(tkn, 0x10, 0), nr_wrapper::ABSENT);
+    assert_eq!(replica.execute_mut(tkn, 0x10, 0xaa), nr_wrapper::ABSENT);
+    assert_eq!(replica.execute_mut(tkn, 0x10, 0xbb), 0xaa);
+    assert_eq!(replica.execute(tkn, 0x10, 0), 0xbb);
+    // 0 is a value, not a miss
+    assert_eq!(replica.execute_mut(tkn, 0x20, 0), nr_wrapper::ABSENT);
+    assert_eq!(replica.execute(tkn, 0x20, 0), 0);
+    assert!(history::check::<history::HashMapSpec>(&history.events()).is_ok());
+}
+
+#[test]
+fn partitioned_replica() {
+    let log = createPartitionedLog(2);
+    let replica = createPartitionedReplica(log);
+    let history = createHistory();
+    replica.RecordHistory(history);
+    let tkn = replica.RegisterWrapper();
+    // 0x0 and ONE_GIB live in different partitions
+    assert_eq!(replica.ReplicaResolve(tkn, 0x1000), 0x1000);
+    assert_eq!(replica.ReplicaResolve(tkn, ONE_GIB as u64 + 0x1000), ONE_GIB as u64 + 0x1000);
+    assert_eq!(replica.ReplicaMap(tkn, 0x1000, 0xf000), 1);
+    assert_eq!(replica.ReplicaMap(tkn, ONE_GIB as u64 + 0x1000, 0xd000), 1);
+    assert_eq!(replica.ReplicaResolve(tkn, 0x1000), 0xf000);
+    assert_eq!(replica.ReplicaResolve(tkn, ONE_GIB as u64 + 0x1000), 0xd000);
+    assert_eq!(history.events().len(), 6);
+    assert!(history::check::<history::VSpaceSpec>(&history.events()).is_ok());
+
+    // Only a single log can pick free ranges
+    use partitioned::LogMapper;
+    let mmap = Modify::Mmap(String::from("anon"), 0x1000, MapAction::ReadWriteUser);
+    assert_eq!(mmap.log_index(1), Some(0));
+    assert_eq!(replica.execute_mut(tkn, mmap), None);
+    assert_eq!(replica.execute(tkn, Access::Resolve(0x1000)), Some(0xf000));
+}
+
+#[test]
+fn rwlocked_vspace() {
+    // Identity maps everything, so only one of them fits next to the
+    // other tests
+    let vs = createRwLockedVSpace(true);
+    assert_eq!(vs.resolveWrapped(0x1000), 0x1000);
+    assert!(vs.mapGenericWrapped(0x1000, 0xf000, 0x1000));
+    assert_eq!(vs.resolveWrapped(0x1000), 0xf000);
+}
+
+#[test]
+fn reverse_lookup() {
+    // Only identity maps the first GiB, keeps the rmap checks fast
+    let mut vs = VSpace::partition(0, 512);
+    vs.enable_rmap();
+    assert_eq!(vs.reverse_lookup(PAddr::from(0x1000u64)), vec![(VAddr::from(0x1000u64), BASE_PAGE_SIZE)]);
+
+    // Alias the frame at 0x1000 from a 4 KiB page, and map a 2 MiB page
+    assert!(vs.mapGenericWrapped(VSPACE_RANGE, 0x1000, 0x1000));
+    assert!(vs.mapGenericWrapped(VSPACE_RANGE + TWO_MIB as u64, 0x4000_0000, TWO_MIB));
+
+    let mut aliases = vs.reverse_lookup(PAddr::from(0x1800u64));
+    aliases.sort_by_key(|(v, _)| v.as_u64());
+    assert_eq!(aliases, vec![
+        (VAddr::from(0x1000u64), BASE_PAGE_SIZE),
+        (VAddr::from(VSPACE_RANGE), BASE_PAGE_SIZE),
+    ]);
+    assert_eq!(
+        vs.reverse_lookup(PAddr::from(0x4010_0000u64)),
+        vec![(VAddr::from(VSPACE_RANGE + TWO_MIB as u64), LARGE_PAGE_SIZE)]
+    );
+
+    // Remapping a 4 KiB page drops the old frame's reverse mapping
+    assert!(vs.mapGenericWrapped(VSPACE_RANGE, 0x2000, 0x1000));
+    assert_eq!(vs.reverse_lookup(PAddr::from(0x1000u64)), vec![(VAddr::from(0x1000u64), BASE_PAGE_SIZE)]);
+}
+
+#[test]
+fn mmap_regions() {
+    let mut vs = VSpace::partition(0, 512);
+
+    let small = vs.mmap("small", 0x3000, MapAction::ReadWriteUser).unwrap();
+    let large = vs.mmap("large", 2 * TWO_MIB, MapAction::ReadWriteUser).unwrap();
+    let huge = vs.mmap("huge", ONE_GIB, MapAction::ReadUser).unwrap();
+    assert_eq!(small.as_u64(), regions::MMAP_BASE);
+    assert_eq!(large % TWO_MIB, 0);
+    assert_eq!(huge % ONE_GIB, 0);
+    assert_eq!(vs.region(small + 0x2000usize).unwrap().name, "small");
+    assert!(vs.region(small + 0x3000usize).is_none());
+
+    let paddr = vs.resolve_addr(large + 0x1234usize).unwrap();
+    assert_eq!(paddr % TWO_MIB, 0x1234);
+    assert!(vs.mappings().contains(&(huge, vs.resolve_addr(huge).unwrap(), HUGE_PAGE_SIZE)));
+
+    // `small` can grow in place, `large` has to move past `huge`
+    assert_eq!(vs.mremap(small, 0x5000).unwrap(), small);
+    let moved = vs.mremap(large, ONE_GIB).unwrap();
+    assert!(moved > huge);
+    assert_eq!(vs.resolve_addr(moved + 0x1234usize), Some(paddr));
+    assert!(vs.region(large).is_none());
+
+    assert!(vs.munmap(huge).is_ok());
+    assert!(vs.region(huge).is_none());
+    assert_eq!(vs.resolve_addr(huge), None);
+    assert!(!vs.mappings().iter().any(|(v, _, _)| *v == huge));
+    assert!(vs.munmap(huge).is_err());
+
+    // The hole left by `huge` gets reused
+    assert_eq!(vs.mmap("again", ONE_GIB, MapAction::ReadUser).unwrap(), huge);
+}
+
+#[test]
+fn demand_paging() {
+    use fault::{FileBacked, GuardPages, ZeroFill};
+
+    let mut vs = VSpace::partition(0, 512);
+    let base = VAddr::from(VSPACE_RANGE);
+    assert_eq!(vs.resolve_addr(base), None);
+    assert_eq!(vs.handle_fault(base), None);
+
+    let file: Arc<[u8]> = (0..0x1800u32).map(|i| i as u8).collect::<Vec<u8>>().into();
+    vs.set_fault_handler(Arc::new(GuardPages {
+        base: base + 0x4000usize,
+        len: 0x1000,
+        inner: FileBacked { base, data: file.clone(), rights: MapAction::ReadUser },
+    }));
+
+    let paddr = vs.handle_fault(base + 0x1004usize).unwrap();
+    assert_eq!(vs.resolve_addr(base + 0x1004usize), Some(paddr));
+    let frame = unsafe {
+        std::slice::from_raw_parts(vs.paddr_to_kernel_vaddr(paddr - 0x4usize).as_ptr::<u8>(), BASE_PAGE_SIZE)
+    };
+    assert_eq!(&frame[..0x800], &file[0x1000..]);
+    assert!(frame[0x800..].iter().all(|b| *b == 0));
+
+    // A second fault on a mapped page is a no-op
+    assert_eq!(vs.handle_fault(base + 0x1000usize), Some(paddr - 0x4usize));
+    // Guard page and beyond the end of the file
+    assert_eq!(vs.handle_fault(base + 0x4000usize), None);
+    assert_eq!(vs.handle_fault(base + 0x2000usize), None);
+    assert_eq!(vs.fault_stats(), FaultStats { mapped: 1, rejected: 2 });
+
+    vs.set_fault_handler(Arc::new(ZeroFill { rights: MapAction::ReadWriteUser }));
+    assert!(vs.handle_fault(base + 0x2000usize).is_some());
+
+    // The frame is allocated, the page-tables to map it at an empty PML4
+    // slot aren't: nothing is left behind
+    let far = VAddr::from(0x4000_0000_0000u64);
+    let (before, mem_counter) = (vs.export_image(), vs.mem_counter);
+    vs.set_alloc_faults(FaultPolicy::Nth(2));
+    assert_eq!(vs.handle_fault(far), None);
+    assert_eq!((vs.export_image(), vs.mem_counter), (before, mem_counter));
+    assert_eq!(vs.fault_stats(), FaultStats { mapped: 2, rejected: 3 });
+    vs.set_alloc_faults(FaultPolicy::Never);
+    assert!(vs.handle_fault(far).is_some());
+}
+
+#[test]
+#[should_panic(expected = "is not an allocated frame")]
+fn demand_paging_foreign_frame() {
+    use fault::{FaultResult, Frames, PageFaultHandler};
+
+    // Writes through a frame it didn't get from `alloc_zeroed`
+    struct Scribble;
+    impl PageFaultHandler for Scribble {
+        fn handle_fault(&self, _vaddr: VAddr, frames: &mut Frames) -> FaultResult {
+            frames.contents(PAddr::from(0x0u64))[0] = 0xff;
+            FaultResult::Reject
+        }
+    }
+
+    let mut vs = VSpace::partition(0, 512);
+    vs.set_fault_handler(Arc::new(Scribble));
+    vs.handle_fault(VAddr::from(VSPACE_RANGE));
+}
+
+#[test]
+fn cache_attributes() {
+    let wt = MapRights {
+        action: MapAction::ReadWriteKernel,
+        cache: CacheType::WriteThrough,
+        global: true,
+        pkey: 5,
+    };
+    let pt = wt.to_pt_rights().bits();
+    let pd = wt.to_pd_rights().bits();
+    assert_eq!(pt & (PWT_BIT | PCD_BIT | PT_PAT_BIT), PWT_BIT | PCD_BIT | PT_PAT_BIT);
+    assert_eq!(pd & (PWT_BIT | PCD_BIT | LARGE_PAT_BIT), PWT_BIT | PCD_BIT | LARGE_PAT_BIT);
+    // Bit 7 is PS in a PDE, that one is up to map_generic
+    assert_eq!(pd & PT_PAT_BIT, 0);
+    assert_eq!(wt.to_pdpt_rights().bits(), pd);
+    assert_eq!((pt >> PKEY_SHIFT) & 0xf, 5);
+    assert!(wt.to_pt_rights().contains(PTFlags::G));
+
+    let wb = MapRights::from(MapAction::ReadUser);
+    assert_eq!(wb.to_pt_rights(), MapAction::ReadUser.to_pt_rights());
+
+    let mut vs = VSpace::partition(0, 512);
+    let dev = VAddr::from(VSPACE_RANGE);
+    assert!(vs.map_device(dev, (PAddr::from(0xfee0_0000u64), 0x1000), CacheType::Uncached).is_ok());
+    assert_eq!(vs.resolve_addr(dev + 0x20usize), Some(PAddr::from(0xfee0_0020u64)));
+
+    // A WT 2 MiB page has the PAT bit set in its address bits
+    let large = VAddr::from(VSPACE_RANGE + TWO_MIB as u64);
+    assert!(vs.map_generic(large, (PAddr::from(0x4000_0000u64), TWO_MIB), wt).is_ok());
+    assert_eq!(vs.resolve_addr(large + 0x1234usize), Some(PAddr::from(0x4000_1234u64)));
+}
+
+#[test]
+fn position_independent_image() {
+    const PHYS_BASE: u64 = 0x1_0000_0000;
+    let mut a = VSpace::new(ONE_GIB, 0x0);
+    let mut b = VSpace::new(ONE_GIB, 0x0);
+    let mut c = VSpace::new(ONE_GIB, PHYS_BASE);
+    for vs in [&mut a, &mut b, &mut c] {
+        assert!(vs.map_generic(VAddr::from(0x1000u64), (PAddr::from(0xd000u64), 0x1000), MapAction::ReadUser).is_ok());
+        assert!(vs.map_generic(VAddr::from(VSPACE_RANGE), (PAddr::from(0xf000u64), 0x1000), MapAction::ReadUser).is_ok());
+    }
+    // Same ops give the same image, no matter where the arenas got mapped
+    assert_eq!(a.export_image(), b.export_image());
+
+    // Walk the image by hand, like an emulator would
+    let (image, cr3) = c.export_image();
+    let walk = |vaddr: VAddr| {
+        const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+        let entry = |table: u64, idx: usize| {
+            let offset = (table - PHYS_BASE) as usize + idx * 8;
+            u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+        };
+        let pml4e = entry(cr3.as_u64(), pml4_index(vaddr));
+        let pdpte = entry(pml4e & ADDR_MASK, pdpt_index(vaddr));
+        let pde = entry(pdpte & ADDR_MASK, pd_index(vaddr));
+        let pte = entry(pde & ADDR_MASK, pt_index(vaddr));
+        (pte & ADDR_MASK) + vaddr.base_page_offset()
+    };
+    assert_eq!(walk(VAddr::from(0x1008u64)), 0xd008);
+    assert_eq!(walk(VAddr::from(VSPACE_RANGE + 0x10)), 0xf010);
+    assert_eq!(cr3, a.export_image().1 + PHYS_BASE);
+}
+
+#[test]
+fn linearizability_check() {
+    use history::{check, load, Event, VSpaceSpec};
+    let ev = |op, ret, invoke, response| Event { op, ret, invoke, response };
+
+    // The first read overlaps the map and may still see the old frame
+    let good = vec![
+        ev(Op::Write(0x1000, 0xf000), 1, 0, 5),
+        ev(Op::Read(0x1008, 0), 0x1008, 1, 2),
+        ev(Op::Read(0x1010, 0), 0xf010, 3, 6),
+        ev(Op::Read(VSPACE_RANGE, 0), 0x0, 4, 7),
+        ev(Op::Read(0x1010, 0), 0xf010, 8, 9),
+    ];
+    assert_eq!(check::<VSpaceSpec>(&good), Ok(()));
+
+    // Once a read saw the new frame, later reads can't see the old one
+    let mut stale = good.clone();
+    stale.push(ev(Op::Read(0x1010, 0), 0x1010, 10, 11));
+    let violation = check::<VSpaceSpec>(&stale).unwrap_err();
+    assert_eq!(violation.partition, 0x1000);
+    assert_eq!(violation.events.len(), 5);
+
+    // Dumps of recorded histories load back as they were
+    let history = History::default();
+    for e in good.iter() {
+        history.record(e.op, || e.ret);
+    }
+    let path = std::env::temp_dir().join(format!("vspace-history-{}", std::process::id()));
+    history.dump(&path).unwrap();
+    let loaded = load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, history.events());
+    assert!(loaded.iter().zip(good.iter()).all(|(l, e)| l.op == e.op && l.ret == e.ret));
+}
+
+#[test]
+fn trace_replay() {
+    use trace::Trace;
+
+    let path = std::env::temp_dir().join(format!("vspace-trace-{}", std::process::id()));
+    let mut vs = VSpace::partition(0, 512);
+    vs.trace_to(&path, 0, 512).unwrap();
+    let base = vs.dispatch_mut(Modify::Mmap(String::from("anon"), 3 * TWO_MIB, MapAction::ReadWriteUser));
+    assert_eq!(vs.dispatch_mut(Modify::Map(0x1000, 0xf000)), 1);
+    assert_eq!(vs.dispatch_mut(Modify::Mremap(base, TWO_MIB)), base);
+    assert_eq!(vs.dispatch(Access::Resolve(0x1008)), 0xf008);
+    assert_eq!(vs.dispatch(Access::TryResolve(base + 3 * TWO_MIB as u64)), NOT_PRESENT);
+    assert_eq!(vs.dispatch_mut(Modify::Munmap(0x1000)), 0);
+
+    let trace = Trace::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!((trace.idx, trace.nlogs, trace.records.len()), (0, 512, 6));
+    assert_eq!(trace.records[1], trace::Record::Write(Modify::Map(0x1000, 0xf000), 1));
+
+    let (replayed, mismatches) = trace.replay();
+    assert!(mismatches.is_empty());
+    assert!(replayed.diff(&vs, 16).is_empty());
+
+    // Replaying with tracing still enabled doesn't trace the replay
+    let dir = std::env::temp_dir().join(format!("vspace-traces-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::env::set_var("VSPACE_TRACE_DIR", &dir);
+    let (traced, _) = trace.replay();
+    std::env::remove_var("VSPACE_TRACE_DIR");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(traced.trace.is_none());
+
+    // A replica that applied one more op shows up in the diff
+    assert!(vs.mapGenericWrapped(0x3000, 0xd000, 0x1000));
+    let diffs = replayed.diff(&vs, 16);
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].vaddr, VAddr::from(0x3000u64));
+    assert_eq!(diffs[0].ours.map(|(size, entry)| (size, entry & !0xfff)), Some((BASE_PAGE_SIZE, 0x3000)));
+}
+
+#[test]
+fn alloc_fault_injection() {
+    use inject::FaultPolicy;
+
+    assert_eq!("nth:3".parse(), Ok(FaultPolicy::Nth(3)));
+    assert_eq!("bytes:8192".parse(), Ok(FaultPolicy::AfterBytes(8192)));
+    assert_eq!("p:0.5:7".parse(), Ok(FaultPolicy::Probability { p: 0.5, seed: 7 }));
+    assert!("p:2".parse::<FaultPolicy>().is_err());
+
+    // Failing the arena allocation fails the constructor
+    assert!(VSpace::with_alloc_faults(ONE_GIB, 0x0, FaultPolicy::Nth(1)).is_err());
+
+    let mut vs = VSpace::partition(0, 512);
+    vs.enable_rmap();
+    // Spans two page-tables in an empty PML4 slot: the second PT is allocated
+    // after the first half got mapped already
+    let vbase = VAddr::from(VSPACE_RANGE + (TWO_MIB - BASE_PAGE_SIZE) as u64);
+    let before = vs.export_image();
+    let mut failed = 0;
+    for n in 1.. {
+        vs.set_alloc_faults(FaultPolicy::Nth(n));
+        match vs.map_generic(vbase, (PAddr::from(0xd000u64), 2 * BASE_PAGE_SIZE), MapAction::ReadUser) {
+            Err(_) => {
+                assert_eq!(vs.export_image(), before);
+                #[cfg(debug_assertions)]
+                vs.check_rmap();
+                failed += 1;
+            }
+            Ok(()) => break,
+        }
+    }
+    // PDPT, PD and two PTs
+    assert_eq!(failed, 4);
+    assert_eq!(vs.resolve_addr(vbase + BASE_PAGE_SIZE), Some(PAddr::from(0xe000u64)));
+    // The frame is identity mapped as well
+    assert_eq!(
+        vs.reverse_lookup(PAddr::from(0xd000u64)),
+        vec![(VAddr::from(0xd000u64), BASE_PAGE_SIZE), (vbase, BASE_PAGE_SIZE)]
+    );
+
+    // Running out of arena is handled the same way
+    let mut tiny = VSpace::new(4 * BASE_PAGE_SIZE, 0x0);
+    let before = tiny.export_image();
+    assert!(tiny.map_generic(VAddr::from(0x1000u64), (PAddr::from(0x1000u64), 0x1000), MapAction::ReadUser).is_err());
+    assert_eq!(tiny.export_image(), before);
+
+    // Failed region allocations leave no region behind
+    vs.set_alloc_faults(FaultPolicy::AfterBytes(TWO_MIB));
+    assert!(vs.mmap("big", 2 * TWO_MIB, MapAction::ReadWriteUser).is_err());
+    assert!(vs.regions.iter().next().is_none());
+    let small = vs.mmap("small", TWO_MIB, MapAction::ReadWriteUser).unwrap();
+    assert!(vs.mremap(small, 2 * TWO_MIB).is_err());
+    assert_eq!(vs.region(small).unwrap().len, TWO_MIB);
+}
+
 #[test]
 fn silly2() {
     let _r = env_logger::try_init();
@@ -0,0 +1,50 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A single [`VSpace`] behind one of the Rust reader-writer locks, to
+//! compare them with NR and the other locks in the benchmark harness
+//! (`rust_rwlock` and `rust_numa_rwlock` in `main.cpp`).
+
+use rwlock::{NumaRWLock, RWLock};
+
+use crate::VSpace;
+
+enum Lock {
+    /// One reader count shared by all cores.
+    Plain(RWLock<VSpace>),
+    /// One reader count per NUMA node.
+    Numa(NumaRWLock<VSpace>),
+}
+
+pub struct RwLockedVSpace(Lock);
+
+/// Creates an identity-mapped `VSpace` (like [`VSpace::default`]) behind
+/// the per-node lock if `numa`, or the plain one.
+pub fn createRwLockedVSpace(numa: bool) -> &'static mut RwLockedVSpace {
+    let vs = VSpace::default();
+    let lock = if numa {
+        let lock = NumaRWLock::new(vs);
+        log::info!("rwlock over {} NUMA nodes", lock.topology().nodes());
+        Lock::Numa(lock)
+    } else {
+        Lock::Plain(RWLock::new(vs))
+    };
+    Box::leak(Box::new(RwLockedVSpace(lock)))
+}
+
+impl RwLockedVSpace {
+    pub fn resolveWrapped(&self, vbase: u64) -> u64 {
+        match &self.0 {
+            Lock::Plain(lock) => lock.read().resolveWrapped(vbase),
+            Lock::Numa(lock) => lock.read().resolveWrapped(vbase),
+        }
+    }
+
+    pub fn mapGenericWrapped(&self, vbase: u64, pregion: u64, pregion_len: usize) -> bool {
+        let map = |vs: &mut VSpace| vs.mapGenericWrapped(vbase, pregion, pregion_len);
+        match &self.0 {
+            Lock::Plain(lock) => lock.with_write(map),
+            Lock::Numa(lock) => lock.with_write(map),
+        }
+    }
+}
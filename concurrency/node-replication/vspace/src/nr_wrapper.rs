@@ -0,0 +1,173 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Generic glue between node-replication and the C++ benchmark.
+//!
+//! cxx can't export generic types, so every data-structure we want to
+//! drive from `main.cpp` needs its own concrete log and replica type.
+//! [`NrLog`] and [`NrReplica`] implement the logic once for any
+//! [`Dispatch`] type whose operations can be encoded as two `u64` words,
+//! and [`nr_bridge_types!`] stamps out the concrete wrappers and
+//! `create*Log`/`create*Replica` constructors that get listed in the
+//! `#[cxx::bridge]`.
+
+use std::sync::Arc;
+
+use node_replication::{Dispatch, Log, Replica, ReplicaToken};
+
+use crate::history::{History, Op};
+
+/// Size of the log we allocate for every bridged data-structure.
+pub const LOG_SIZE_BYTES: usize = 2 * 1024 * 1024;
+
+/// An operation that can be passed from C++ as two `u64` arguments.
+pub trait FfiOp: Sized {
+    fn from_ffi(a: u64, b: u64) -> Self;
+}
+
+/// A response that can be handed back to C++ as a `u64`.
+pub trait FfiResponse {
+    fn to_ffi(self) -> u64;
+}
+
+impl FfiResponse for u64 {
+    fn to_ffi(self) -> u64 {
+        self
+    }
+}
+
+impl FfiResponse for bool {
+    fn to_ffi(self) -> u64 {
+        self as u64
+    }
+}
+
+/// What an absent value is reported as to C++. 0 is a value like any
+/// other, so instances returning `Option<u64>` keep their values below
+/// this (like `Access::TryResolve` does with `NOT_PRESENT`).
+pub const ABSENT: u64 = u64::MAX;
+
+impl FfiResponse for Option<u64> {
+    fn to_ffi(self) -> u64 {
+        debug_assert_ne!(self, Some(ABSENT));
+        self.unwrap_or(ABSENT)
+    }
+}
+
+/// A shared NR log for data-structure `D`.
+pub struct NrLog<D: Dispatch>(pub Arc<Log<'static, D::WriteOperation>>)
+where
+    D::WriteOperation: 'static;
+
+impl<D: Dispatch> NrLog<D>
+where
+    D::WriteOperation: 'static,
+{
+    pub fn new() -> NrLog<D> {
+        NrLog(Arc::new(Log::new(LOG_SIZE_BYTES)))
+    }
+}
+
+/// A replica of `D` attached to a (leaked) [`NrLog`].
+pub struct NrReplica<D>
+where
+    D: Sized + Default + Dispatch + Sync + 'static,
+    D::WriteOperation: 'static,
+{
+    pub log: &'static NrLog<D>,
+    pub inner: Arc<Replica<'static, D>>,
+    /// Where operations get recorded, if anywhere.
+    pub history: Option<&'static History>,
+}
+
+impl<D> NrReplica<D>
+where
+    D: Sized + Default + Dispatch + Sync + 'static,
+    D::WriteOperation: 'static,
+    D::ReadOperation: FfiOp,
+    D::WriteOperation: FfiOp,
+    D::Response: FfiResponse,
+{
+    pub fn new(log: &'static NrLog<D>) -> NrReplica<D> {
+        let inner = Replica::new(&log.0);
+        NrReplica { log, inner, history: None }
+    }
+
+    /// Records all operations executed through this replica in `history`.
+    pub fn record_history(&mut self, history: &'static History) {
+        self.history = Some(history);
+    }
+
+    /// Runs `f`, recording it as `op` if we keep a history.
+    pub fn recorded<F: FnOnce() -> u64>(&self, op: Op, f: F) -> u64 {
+        match self.history {
+            Some(history) => history.record(op, f),
+            None => f(),
+        }
+    }
+
+    /// Registers the calling thread, returns the raw token id.
+    pub fn register(&self) -> usize {
+        let tkn = self.inner.register().unwrap();
+        tkn.id()
+    }
+
+    /// Executes a read-only operation built from `(a, b)`.
+    pub fn execute(&self, tkn: usize, a: u64, b: u64) -> u64 {
+        let tkn = unsafe { ReplicaToken::new(tkn) };
+        self.recorded(Op::Read(a, b), || {
+            self.inner.execute(D::ReadOperation::from_ffi(a, b), tkn).to_ffi()
+        })
+    }
+
+    /// Executes a mutating operation built from `(a, b)`.
+    pub fn execute_mut(&self, tkn: usize, a: u64, b: u64) -> u64 {
+        let tkn = unsafe { ReplicaToken::new(tkn) };
+        self.recorded(Op::Write(a, b), || {
+            self.inner.execute_mut(D::WriteOperation::from_ffi(a, b), tkn).to_ffi()
+        })
+    }
+}
+
+/// Generates the concrete log/replica wrappers for a bridged `Dispatch` type.
+///
+/// `nr_bridge_types!(D, LogTy, ReplicaTy, createLogFn, createReplicaFn)`
+/// defines `LogTy`, `ReplicaTy` (with `RegisterWrapper`, `RecordHistory`,
+/// `execute` and `execute_mut` methods) and the two constructor functions. The
+/// matching declarations still have to be added to the `#[cxx::bridge]`
+/// by hand, since cxx doesn't expand macros inside the bridge module.
+#[macro_export]
+macro_rules! nr_bridge_types {
+    ($dispatch:ty, $log:ident, $replica:ident, $create_log:ident, $create_replica:ident) => {
+        pub struct $log($crate::nr_wrapper::NrLog<$dispatch>);
+
+        pub struct $replica($crate::nr_wrapper::NrReplica<$dispatch>);
+
+        impl $replica {
+            fn RegisterWrapper(&mut self) -> usize {
+                self.0.register()
+            }
+
+            fn RecordHistory(&mut self, history: &'static $crate::history::History) {
+                self.0.record_history(history)
+            }
+
+            fn execute(&self, tkn: usize, a: u64, b: u64) -> u64 {
+                self.0.execute(tkn, a, b)
+            }
+
+            fn execute_mut(&self, tkn: usize, a: u64, b: u64) -> u64 {
+                self.0.execute_mut(tkn, a, b)
+            }
+        }
+
+        pub fn $create_log() -> &'static mut $log {
+            Box::leak(Box::new($log($crate::nr_wrapper::NrLog::new())))
+        }
+
+        pub fn $create_replica(log: &'static $log) -> &'static mut $replica {
+            let inner = $crate::nr_wrapper::NrReplica::new(&log.0);
+            Box::leak(Box::new($replica(inner)))
+        }
+    };
+}
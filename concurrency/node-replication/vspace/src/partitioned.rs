@@ -0,0 +1,140 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Address-range partitioned NR for the VSpace.
+//!
+//! With a single log every `Map` serializes with every other `Map`, even
+//! if they touch unrelated parts of the address space. Here the address
+//! space is split into 1 GiB regions (one PDPT entry each) which are
+//! distributed round-robin over `nlogs` logs. Every operation declares
+//! the log it belongs to through [`LogMapper`], and each log has its own
+//! replicated [`VSpace`] partition, so operations on different partitions
+//! proceed in parallel.
+
+use std::sync::Arc;
+
+use node_replication::{Log, Replica, ReplicaToken};
+use x86::bits64::paging::{pdpt_index, pml4_index, VAddr, PAGE_SIZE_ENTRIES};
+
+use crate::history::{History, Op};
+use crate::nr_wrapper::LOG_SIZE_BYTES;
+use crate::{Access, Modify, VSpace};
+
+/// Returns the log responsible for the 1 GiB region containing `vaddr`.
+pub fn log_index(vaddr: u64, nlogs: usize) -> usize {
+    let vaddr = VAddr::from(vaddr);
+    (pml4_index(vaddr) * PAGE_SIZE_ENTRIES + pdpt_index(vaddr)) % nlogs
+}
+
+/// Maps an operation to the log it has to go through, or `None` if no
+/// single log can take it.
+pub trait LogMapper {
+    fn log_index(&self, nlogs: usize) -> Option<usize>;
+}
+
+impl LogMapper for Access {
+    fn log_index(&self, nlogs: usize) -> Option<usize> {
+        match self {
+            Access::Resolve(vaddr) | Access::TryResolve(vaddr) => Some(log_index(*vaddr, nlogs)),
+        }
+    }
+}
+
+impl LogMapper for Modify {
+    fn log_index(&self, nlogs: usize) -> Option<usize> {
+        match self {
+            Modify::Map(vaddr, _paddr) => Some(log_index(*vaddr, nlogs)),
+            Modify::MapDevice(vaddr, _paddr, _len) => Some(log_index(*vaddr, nlogs)),
+            Modify::Munmap(base) | Modify::Mremap(base, _) => Some(log_index(*base, nlogs)),
+            Modify::Fault(vaddr) => Some(log_index(*vaddr, nlogs)),
+            // The region manager of a partition hands out ranges from the
+            // whole address space, most of which belong to other logs
+            Modify::Mmap(..) if nlogs == 1 => Some(0),
+            Modify::Mmap(..) => None,
+        }
+    }
+}
+
+pub struct PartitionedLogWrapper(Vec<Arc<Log<'static, Modify>>>);
+
+pub fn createPartitionedLog(nlogs: usize) -> &'static mut PartitionedLogWrapper {
+    assert!(nlogs > 0);
+    let logs = (0..nlogs)
+        .map(|_| Arc::new(Log::new(LOG_SIZE_BYTES)))
+        .collect();
+
+    Box::leak(Box::new(PartitionedLogWrapper(logs)))
+}
+
+/// One replica per log, the i-th one holding [`VSpace::partition`] `i`.
+pub struct PartitionedReplicaWrapper {
+    replicas: Vec<Arc<Replica<'static, VSpace>>>,
+    history: Option<&'static History>,
+}
+
+pub fn createPartitionedReplica(log: &'static PartitionedLogWrapper) -> &'static mut PartitionedReplicaWrapper {
+    let nlogs = log.0.len();
+    let replicas = log
+        .0
+        .iter()
+        .enumerate()
+        .map(|(idx, l)| Replica::with_data(l, VSpace::partition(idx, nlogs)))
+        .collect();
+
+    Box::leak(Box::new(PartitionedReplicaWrapper { replicas, history: None }))
+}
+
+impl PartitionedReplicaWrapper {
+    /// Registers with the replica of every log.
+    ///
+    /// Tokens are handed out in registration order, so a thread ends up
+    /// with the same id on all of them and we can return a single one.
+    pub fn RegisterWrapper(&mut self) -> usize {
+        let ids: Vec<usize> = self
+            .replicas
+            .iter()
+            .map(|r| r.register().unwrap().id())
+            .collect();
+        assert!(ids.iter().all(|id| *id == ids[0]), "registration raced?");
+        ids[0]
+    }
+
+    /// Records `ReplicaResolve`/`ReplicaMap` calls in `history`.
+    pub fn RecordHistory(&mut self, history: &'static History) {
+        self.history = Some(history);
+    }
+
+    fn recorded<F: FnOnce() -> u64>(&self, op: Op, f: F) -> u64 {
+        match self.history {
+            Some(history) => history.record(op, f),
+            None => f(),
+        }
+    }
+
+    pub fn ReplicaResolve(&self, tkn: usize, key: u64) -> u64 {
+        let tkn = unsafe { ReplicaToken::new(tkn) };
+        let idx = log_index(key, self.replicas.len());
+        self.recorded(Op::Read(key, 0), || self.replicas[idx].execute(Access::Resolve(key), tkn))
+    }
+
+    pub fn ReplicaMap(&self, tkn: usize, key: u64, val: u64) -> u64 {
+        let tkn = unsafe { ReplicaToken::new(tkn) };
+        let idx = log_index(key, self.replicas.len());
+        self.recorded(Op::Write(key, val), || self.replicas[idx].execute_mut(Modify::Map(key, val), tkn))
+    }
+
+    /// Executes `op` on the replica of its log, or returns `None` if it
+    /// has none (see [`LogMapper`]).
+    pub fn execute(&self, tkn: usize, op: Access) -> Option<u64> {
+        let idx = op.log_index(self.replicas.len())?;
+        let tkn = unsafe { ReplicaToken::new(tkn) };
+        Some(self.replicas[idx].execute(op, tkn))
+    }
+
+    /// Like `execute`, for mutating operations.
+    pub fn execute_mut(&self, tkn: usize, op: Modify) -> Option<u64> {
+        let idx = op.log_index(self.replicas.len())?;
+        let tkn = unsafe { ReplicaToken::new(tkn) };
+        Some(self.replicas[idx].execute_mut(op, tkn))
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! mmap-style virtual memory regions on top of the [`VSpace`].
+//!
+//! Callers of `map_new` have to pick virtual addresses themselves. The
+//! [`RegionManager`] instead hands out free ranges above the pre-mapped
+//! benchmark range, aligned to 1 GiB or 2 MiB whenever the length allows
+//! so `map_generic` can use large pages. Since it is part of the `VSpace`
+//! and driven through `Modify`, it is replicated like everything else.
+//!
+//! There is no real physical memory behind the page-tables, so frames come
+//! from a bump allocator and are never reused.
+
+use std::collections::BTreeMap;
+
+use x86::bits64::paging::{PAddr, VAddr, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE};
+
+use crate::inject::{AllocError, AllocFaults};
+use crate::{MapAction, VSpace, VSpaceError, VSPACE_RANGE};
+
+/// Lowest address handed out (everything below is pre-mapped).
+pub const MMAP_BASE: u64 = VSPACE_RANGE;
+/// End of the canonical lower half.
+pub const MMAP_END: u64 = 0x8000_0000_0000;
+/// Start of the (fake) physical frames backing regions.
+const FRAME_BASE: u64 = 1 << 40;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub base: u64,
+    pub len: usize,
+    pub rights: MapAction,
+    /// Physically contiguous chunks as `(offset in region, paddr, len)`.
+    pub backing: Vec<(usize, u64, usize)>,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.base + self.len as u64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RegionManager {
+    /// Regions indexed by their base address.
+    regions: BTreeMap<u64, Region>,
+    next_frame: u64,
+}
+
+impl Default for RegionManager {
+    fn default() -> RegionManager {
+        RegionManager {
+            regions: BTreeMap::new(),
+            next_frame: FRAME_BASE,
+        }
+    }
+}
+
+fn align_up(addr: u64, align: usize) -> u64 {
+    let align = align as u64;
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Largest page size we could map `len` bytes with.
+fn preferred_alignment(len: usize) -> usize {
+    if len >= HUGE_PAGE_SIZE {
+        HUGE_PAGE_SIZE
+    } else if len >= LARGE_PAGE_SIZE {
+        LARGE_PAGE_SIZE
+    } else {
+        BASE_PAGE_SIZE
+    }
+}
+
+impl RegionManager {
+    /// Returns the region containing `vaddr`.
+    pub fn get(&self, vaddr: VAddr) -> Option<&Region> {
+        self.regions
+            .range(..=vaddr.as_u64())
+            .next_back()
+            .map(|(_, r)| r)
+            .filter(|r| vaddr.as_u64() < r.end())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Region> {
+        self.regions.values()
+    }
+
+    /// Finds the lowest free `len` bytes range aligned to `align`.
+    fn find_free_aligned(&self, len: usize, align: usize) -> Option<u64> {
+        let mut cursor = align_up(MMAP_BASE, align);
+        for r in self.regions.values() {
+            if cursor + len as u64 <= r.base {
+                break;
+            }
+            cursor = align_up(cursor.max(r.end()), align);
+        }
+        if cursor + len as u64 <= MMAP_END {
+            Some(cursor)
+        } else {
+            None
+        }
+    }
+
+    /// Finds a free range, preferring large-page alignment.
+    pub fn find_free(&self, len: usize) -> Option<u64> {
+        let align = preferred_alignment(len);
+        self.find_free_aligned(len, align)
+            .or_else(|| self.find_free_aligned(len, BASE_PAGE_SIZE))
+    }
+
+    /// Is `[base, base + len)` free, ignoring the region at `except`?
+    fn is_free(&self, base: u64, len: usize, except: u64) -> bool {
+        let end = base + len as u64;
+        end <= MMAP_END
+            && base >= MMAP_BASE
+            && self
+                .regions
+                .values()
+                .all(|r| r.base == except || r.end() <= base || r.base >= end)
+    }
+
+    /// Allocates `len` bytes of physically contiguous frames.
+    fn alloc_frames(&mut self, len: usize, faults: &mut AllocFaults) -> Result<u64, AllocError> {
+        faults.allocate(len)?;
+        let paddr = align_up(self.next_frame, preferred_alignment(len));
+        self.next_frame = paddr + len as u64;
+        Ok(paddr)
+    }
+}
+
+impl VSpace {
+    /// Maps a fresh region of `len` bytes at an address of our choosing.
+    pub fn mmap(&mut self, name: &str, len: usize, rights: MapAction) -> Result<VAddr, VSpaceError> {
+        assert_eq!(len % BASE_PAGE_SIZE, 0, "len is not page-aligned");
+        assert!(len > 0);
+        let base = self.regions.find_free(len).ok_or(VSpaceError { at: 0x0 })?;
+        let paddr = self
+            .regions
+            .alloc_frames(len, &mut self.alloc_faults)
+            .map_err(|_| VSpaceError { at: base })?;
+        self.map_new(VAddr::from(base), len, rights, PAddr::from(paddr))?;
+
+        self.regions.regions.insert(
+            base,
+            Region {
+                name: String::from(name),
+                base,
+                len,
+                rights,
+                backing: vec![(0, paddr, len)],
+            },
+        );
+        Ok(VAddr::from(base))
+    }
+
+    /// Unmaps the region starting at `base`.
+    pub fn munmap(&mut self, base: VAddr) -> Result<(), VSpaceError> {
+        let len = match self.regions.regions.get(&base.as_u64()) {
+            Some(r) => r.len,
+            None => return Err(VSpaceError { at: base.as_u64() }),
+        };
+        self.unmap(base, len)?;
+        self.regions.regions.remove(&base.as_u64());
+        Ok(())
+    }
+
+    /// Resizes the region at `base`, moving it if it can't grow in place.
+    pub fn mremap(&mut self, base: VAddr, new_len: usize) -> Result<VAddr, VSpaceError> {
+        assert_eq!(new_len % BASE_PAGE_SIZE, 0, "len is not page-aligned");
+        assert!(new_len > 0);
+        let base = base.as_u64();
+        let region = match self.regions.regions.get(&base) {
+            Some(r) => r.clone(),
+            None => return Err(VSpaceError { at: base }),
+        };
+
+        if new_len <= region.len {
+            if new_len < region.len {
+                self.unmap(VAddr::from(base + new_len as u64), region.len - new_len)?;
+            }
+            let r = self.regions.regions.get_mut(&base).unwrap();
+            r.len = new_len;
+            r.backing = r
+                .backing
+                .iter()
+                .filter(|(off, _, _)| *off < new_len)
+                .map(|(off, paddr, len)| (*off, *paddr, (*len).min(new_len - *off)))
+                .collect();
+            return Ok(VAddr::from(base));
+        }
+
+        let extra = new_len - region.len;
+        let extra_paddr = self
+            .regions
+            .alloc_frames(extra, &mut self.alloc_faults)
+            .map_err(|_| VSpaceError { at: base })?;
+        if self.regions.is_free(region.end(), extra, base) {
+            self.map_new(VAddr::from(region.end()), extra, region.rights, PAddr::from(extra_paddr))?;
+            let r = self.regions.regions.get_mut(&base).unwrap();
+            r.backing.push((r.len, extra_paddr, extra));
+            r.len = new_len;
+            return Ok(VAddr::from(base));
+        }
+
+        // Move: map the old frames at the new place, then drop the old range
+        let new_base = self.regions.find_free(new_len).ok_or(VSpaceError { at: base })?;
+        let mut backing = region.backing.clone();
+        backing.push((region.len, extra_paddr, extra));
+        for (off, paddr, len) in backing.iter() {
+            let r = self.map_new(
+                VAddr::from(new_base + *off as u64),
+                *len,
+                region.rights,
+                PAddr::from(*paddr),
+            );
+            if let Err(e) = r {
+                // Don't leave the chunks we already moved behind
+                if *off > 0 {
+                    self.unmap(VAddr::from(new_base), *off)?;
+                }
+                return Err(e);
+            }
+        }
+        self.unmap(VAddr::from(base), region.len)?;
+
+        self.regions.regions.remove(&base);
+        self.regions.regions.insert(
+            new_base,
+            Region {
+                base: new_base,
+                len: new_len,
+                backing,
+                ..region
+            },
+        );
+        Ok(VAddr::from(new_base))
+    }
+
+    /// Returns the mmap'ed region containing `vaddr`, if any.
+    pub fn region(&self, vaddr: VAddr) -> Option<&Region> {
+        self.regions.get(vaddr)
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Reverse mappings from physical frames to the virtual pages mapping them.
+//!
+//! The page-tables only support forward walks, so unmap-by-frame, page
+//! migration or COW need a separate index. It is kept optional because
+//! maintaining it for the fully pre-mapped benchmark address space would
+//! cost a record per 4 KiB page.
+
+use std::collections::BTreeMap;
+
+use x86::bits64::paging::{PAddr, VAddr, HUGE_PAGE_SIZE};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReverseMap {
+    /// Page base address -> (vaddr, page size) of every mapping of that page.
+    frames: BTreeMap<u64, Vec<(u64, usize)>>,
+}
+
+impl ReverseMap {
+    pub fn new() -> ReverseMap {
+        ReverseMap { frames: BTreeMap::new() }
+    }
+
+    /// Records that `vaddr` maps the `size` bytes page starting at `paddr`.
+    pub fn insert(&mut self, vaddr: VAddr, paddr: PAddr, size: usize) {
+        let mappings = self.frames.entry(paddr.as_u64()).or_insert_with(Vec::new);
+        debug_assert!(
+            !mappings.iter().any(|(v, _)| *v == vaddr.as_u64()),
+            "{:#x} already in rmap for {:#x}",
+            vaddr,
+            paddr
+        );
+        mappings.push((vaddr.as_u64(), size));
+    }
+
+    /// Forgets the mapping of the page at `paddr` by `vaddr`.
+    pub fn remove(&mut self, vaddr: VAddr, paddr: PAddr) {
+        let pbase = paddr.as_u64();
+        let mut now_empty = false;
+        if let Some(mappings) = self.frames.get_mut(&pbase) {
+            let before = mappings.len();
+            mappings.retain(|(v, _)| *v != vaddr.as_u64());
+            debug_assert_eq!(before, mappings.len() + 1, "{:#x} not in rmap", vaddr);
+            now_empty = mappings.is_empty();
+        } else {
+            debug_assert!(false, "{:#x} has no rmap entries", paddr);
+        }
+        if now_empty {
+            self.frames.remove(&pbase);
+        }
+    }
+
+    /// Returns `(vaddr, size)` of every page mapping that covers `paddr`.
+    pub fn lookup(&self, paddr: PAddr) -> Vec<(VAddr, usize)> {
+        let paddr = paddr.as_u64();
+        // No page is larger than 1 GiB, so candidates start within that window
+        let lowest = paddr.saturating_sub(HUGE_PAGE_SIZE as u64 - 1);
+        self.frames
+            .range(lowest..=paddr)
+            .flat_map(|(pbase, mappings)| {
+                mappings
+                    .iter()
+                    .filter(move |(_, size)| paddr < pbase + *size as u64)
+                    .map(|(v, size)| (VAddr::from(*v), *size))
+            })
+            .collect()
+    }
+
+    /// Number of page mappings tracked.
+    pub fn len(&self) -> usize {
+        self.frames.values().map(|m| m.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// All `(paddr, vaddr, size)` records, sorted.
+    pub fn records(&self) -> Vec<(u64, u64, usize)> {
+        let mut records: Vec<(u64, u64, usize)> = self
+            .frames
+            .iter()
+            .flat_map(|(p, mappings)| mappings.iter().map(move |(v, size)| (*p, *v, *size)))
+            .collect();
+        records.sort_unstable();
+        records
+    }
+}
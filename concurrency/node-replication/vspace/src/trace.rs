@@ -0,0 +1,313 @@
+// Copyright © 2019-2021 VMware, Inc. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Capturing the operations a replica applies, and replaying them.
+//!
+//! When a benchmark run ends up with a wrong translation, the operation
+//! stream of each replica can be captured (set `VSPACE_TRACE_DIR`) and
+//! re-applied to a fresh [`VSpace`] with the `replay_trace` tool, which
+//! checks the responses and diffs the resulting page-tables of the
+//! replicas against each other.
+//!
+//! A trace starts with a header naming the partition the replica was
+//! created as (`VSpace::partition(idx, nlogs)`, the default `VSpace`
+//! being partition 0 of 1), followed by one record per operation: a tag
+//! byte, the operands and the response, all integers LEB128 encoded.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use node_replication::Dispatch;
+use x86::bits64::paging::VAddr;
+
+use crate::{Access, MapAction, Modify, VSpace};
+
+const MAGIC: &[u8; 8] = b"VSTRACE1";
+
+const TAG_RESOLVE: u8 = 0x00;
+const TAG_TRY_RESOLVE: u8 = 0x01;
+const TAG_MAP: u8 = 0x10;
+const TAG_MMAP: u8 = 0x11;
+const TAG_MUNMAP: u8 = 0x12;
+const TAG_MREMAP: u8 = 0x13;
+const TAG_MAP_DEVICE: u8 = 0x14;
+const TAG_FAULT: u8 = 0x15;
+
+/// Replicas get numbered in creation order to name their trace files.
+static NEXT_TRACE: AtomicUsize = AtomicUsize::new(0);
+
+const ACTIONS: [MapAction; 9] = [
+    MapAction::None,
+    MapAction::ReadUser,
+    MapAction::ReadKernel,
+    MapAction::ReadWriteUser,
+    MapAction::ReadWriteKernel,
+    MapAction::ReadExecuteUser,
+    MapAction::ReadExecuteKernel,
+    MapAction::ReadWriteExecuteUser,
+    MapAction::ReadWriteExecuteKernel,
+];
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+fn put_u64(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn get_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+        r.read_exact(&mut byte)?;
+        v |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(invalid("varint too long"))
+}
+
+/// One operation applied by a replica, with the response it got.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Read(Access, u64),
+    Write(Modify, u64),
+}
+
+impl Record {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let ret = match self {
+            Record::Read(op, ret) => {
+                match op {
+                    Access::Resolve(vaddr) => {
+                        buf.push(TAG_RESOLVE);
+                        put_u64(buf, *vaddr);
+                    }
+                    Access::TryResolve(vaddr) => {
+                        buf.push(TAG_TRY_RESOLVE);
+                        put_u64(buf, *vaddr);
+                    }
+                }
+                ret
+            }
+            Record::Write(op, ret) => {
+                match op {
+                    Modify::Map(vaddr, paddr) => {
+                        buf.push(TAG_MAP);
+                        put_u64(buf, *vaddr);
+                        put_u64(buf, *paddr);
+                    }
+                    Modify::Mmap(name, len, rights) => {
+                        buf.push(TAG_MMAP);
+                        put_u64(buf, name.len() as u64);
+                        buf.extend_from_slice(name.as_bytes());
+                        put_u64(buf, *len as u64);
+                        buf.push(*rights as u8);
+                    }
+                    Modify::Munmap(base) => {
+                        buf.push(TAG_MUNMAP);
+                        put_u64(buf, *base);
+                    }
+                    Modify::Mremap(base, len) => {
+                        buf.push(TAG_MREMAP);
+                        put_u64(buf, *base);
+                        put_u64(buf, *len as u64);
+                    }
+                    Modify::MapDevice(vbase, pbase, len) => {
+                        buf.push(TAG_MAP_DEVICE);
+                        put_u64(buf, *vbase);
+                        put_u64(buf, *pbase);
+                        put_u64(buf, *len as u64);
+                    }
+                    Modify::Fault(vaddr) => {
+                        buf.push(TAG_FAULT);
+                        put_u64(buf, *vaddr);
+                    }
+                }
+                ret
+            }
+        };
+        put_u64(buf, *ret);
+    }
+
+    /// Reads the next record, `None` at the end of the trace.
+    fn decode<R: Read>(r: &mut R) -> io::Result<Option<Record>> {
+        let mut tag = [0u8; 1];
+        if r.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let record = match tag[0] {
+            TAG_RESOLVE => Record::Read(Access::Resolve(get_u64(r)?), get_u64(r)?),
+            TAG_TRY_RESOLVE => Record::Read(Access::TryResolve(get_u64(r)?), get_u64(r)?),
+            TAG_MAP => Record::Write(Modify::Map(get_u64(r)?, get_u64(r)?), get_u64(r)?),
+            TAG_MMAP => {
+                let mut name = vec![0u8; get_u64(r)? as usize];
+                r.read_exact(&mut name)?;
+                let name = String::from_utf8(name).map_err(|_| invalid("bad region name"))?;
+                let len = get_u64(r)? as usize;
+                let mut rights = [0u8; 1];
+                r.read_exact(&mut rights)?;
+                let rights = *ACTIONS.get(rights[0] as usize).ok_or_else(|| invalid("bad rights"))?;
+                Record::Write(Modify::Mmap(name, len, rights), get_u64(r)?)
+            }
+            TAG_MUNMAP => Record::Write(Modify::Munmap(get_u64(r)?), get_u64(r)?),
+            TAG_MREMAP => Record::Write(Modify::Mremap(get_u64(r)?, get_u64(r)? as usize), get_u64(r)?),
+            TAG_MAP_DEVICE => Record::Write(
+                Modify::MapDevice(get_u64(r)?, get_u64(r)?, get_u64(r)? as usize),
+                get_u64(r)?,
+            ),
+            TAG_FAULT => Record::Write(Modify::Fault(get_u64(r)?), get_u64(r)?),
+            _ => return Err(invalid("bad record tag")),
+        };
+        Ok(Some(record))
+    }
+}
+
+/// Appends the operations of one replica to its trace file.
+///
+/// Every record is written with a single unbuffered `write`, so traces
+/// are complete even if the replica is never dropped (as in the C++
+/// benchmark), at the cost of a syscall per operation.
+#[derive(Debug)]
+pub struct TraceWriter {
+    file: Mutex<File>,
+}
+
+impl TraceWriter {
+    /// Starts a trace of a replica created as `VSpace::partition(idx, nlogs)`.
+    pub fn create<P: AsRef<Path>>(path: P, idx: usize, nlogs: usize) -> io::Result<TraceWriter> {
+        let mut file = File::create(path)?;
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&(idx as u32).to_le_bytes());
+        header.extend_from_slice(&(nlogs as u32).to_le_bytes());
+        file.write_all(&header)?;
+        Ok(TraceWriter { file: Mutex::new(file) })
+    }
+
+    pub fn record(&self, record: &Record) {
+        let mut buf = Vec::with_capacity(32);
+        record.encode(&mut buf);
+        // Losing the trace shouldn't take the benchmark down
+        if let Err(e) = self.file.lock().unwrap().write_all(&buf) {
+            log::error!("failed to write trace: {}", e);
+        }
+    }
+}
+
+/// Path of the trace file for the next replica, if tracing is enabled.
+pub fn next_trace_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("VSPACE_TRACE_DIR")?;
+    let n = NEXT_TRACE.fetch_add(1, Ordering::Relaxed);
+    Some(Path::new(&dir).join(format!("replica-{}.trace", n)))
+}
+
+/// A trace read back from disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub idx: usize,
+    pub nlogs: usize,
+    pub records: Vec<Record>,
+}
+
+impl Trace {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Trace> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 16];
+        r.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid("not a vspace trace"));
+        }
+        let idx = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+        let nlogs = u32::from_le_bytes([header[12], header[13], header[14], header[15]]) as usize;
+        if idx >= nlogs {
+            return Err(invalid("bad partition"));
+        }
+
+        let mut records = Vec::new();
+        while let Some(record) = Record::decode(&mut r)? {
+            records.push(record);
+        }
+        Ok(Trace { idx, nlogs, records })
+    }
+
+    /// Re-applies the trace to a fresh `VSpace`.
+    ///
+    /// Returns the resulting `VSpace` and `(record index, got)` for every
+    /// operation whose response differs from the recorded one. Faults are
+    /// replayed without a fault handler, so they only match if they were
+    /// rejected or raced with a mapping.
+    ///
+    /// The `VSpace` isn't traced itself, so replaying with
+    /// `VSPACE_TRACE_DIR` still set leaves the traces alone.
+    pub fn replay(&self) -> (VSpace, Vec<(usize, u64)>) {
+        let mut vspace = VSpace::partition_untraced(self.idx, self.nlogs);
+        let mut mismatches = Vec::new();
+        for (i, record) in self.records.iter().enumerate() {
+            let (got, expected) = match record {
+                Record::Read(op, ret) => (vspace.dispatch(op.clone()), *ret),
+                Record::Write(op, ret) => (vspace.dispatch_mut(op.clone()), *ret),
+            };
+            if got != expected {
+                mismatches.push((i, got));
+            }
+        }
+        (vspace, mismatches)
+    }
+}
+
+/// A page whose mapping differs between two `VSpace`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappingDiff {
+    pub vaddr: VAddr,
+    /// `(page size, raw leaf entry)` in either `VSpace`, if mapped.
+    pub ours: Option<(usize, u64)>,
+    pub theirs: Option<(usize, u64)>,
+}
+
+impl VSpace {
+    /// Starts tracing every operation applied to this `VSpace`, which was
+    /// created as `VSpace::partition(idx, nlogs)`.
+    pub fn trace_to<P: AsRef<Path>>(&mut self, path: P, idx: usize, nlogs: usize) -> io::Result<()> {
+        self.trace = Some(TraceWriter::create(path, idx, nlogs)?);
+        Ok(())
+    }
+
+    /// Enables tracing if `VSPACE_TRACE_DIR` is set.
+    pub(crate) fn trace_from_env(&mut self, idx: usize, nlogs: usize) {
+        if let Some(path) = next_trace_path() {
+            if let Err(e) = self.trace_to(&path, idx, nlogs) {
+                log::error!("can't trace to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Compares the leaf page-table entries with the ones of `other`.
+    ///
+    /// Returns at most `max` differences, in address order of the pages
+    /// mapped here (pages only mapped in `other` come last).
+    pub fn diff(&self, other: &VSpace, max: usize) -> Vec<MappingDiff> {
+        let mut diffs = Vec::new();
+        self.for_each_leaf(|vaddr, _paddr, size, entry| {
+            let theirs = other.leaf(vaddr);
+            let theirs = theirs.entry().map(|e| (theirs.size(), e));
+            if diffs.len() < max && theirs != Some((size, entry)) {
+                diffs.push(MappingDiff { vaddr, ours: Some((size, entry)), theirs });
+            }
+        });
+        other.for_each_leaf(|vaddr, _paddr, size, entry| {
+            if diffs.len() < max && self.leaf(vaddr).is_unmapped() {
+                diffs.push(MappingDiff { vaddr, ours: None, theirs: Some((size, entry)) });
+            }
+        });
+        diffs
+    }
+}
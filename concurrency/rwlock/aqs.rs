@@ -0,0 +1,195 @@
+// The AQS lock of node-replication/aqs.c (ShflLock, Kashyap et al.),
+// with a shared mode for readers.
+//
+// The lock is a word anyone can take with one CAS while nobody queues
+// ("stealing"), and an MCS queue behind it. The waiter at the head of the
+// queue turns stealing off and waits for the word to be free for it,
+// then passes the head on. Like the shared acquires of Java's
+// AbstractQueuedSynchronizer, readers count themselves in the word and a
+// reader at the head passes the head on as soon as it is in, so a run of
+// queued readers gets in together.
+//
+// aqs.c also reorders the queue so waiters on the same NUMA node go
+// one after the other (`shuffle_waiters`), this one keeps FIFO order.
+// Always spins.
+
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+use crate::padding::CachePadded;
+use crate::queue::{self, Node, Owned};
+use crate::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+use crate::sync::cell::{ConstPtr, MutPtr, UnsafeCell};
+use crate::sync::hint;
+
+// A writer is in
+const LOCKED: u32 = 0x1;
+// Someone queues, newcomers must too
+const NO_STEALING: u32 = 0x2;
+// The rest counts readers
+const READER: u32 = 0x4;
+
+pub struct AqsRWLock<T> {
+  cell: UnsafeCell<T>,
+  // One line for both, like aqs_mutex_t
+  head: CachePadded<Head>,
+}
+
+struct Head {
+  tail: AtomicPtr<Node>,
+  state: AtomicU32,
+}
+
+unsafe impl<T: Send> Send for AqsRWLock<T> {}
+unsafe impl<T: Send + Sync> Sync for AqsRWLock<T> {}
+
+// Held exclusive access, released on drop.
+pub struct WriteGuard<'a, T> {
+  lock: &'a AqsRWLock<T>,
+  // Dropped before releasing, loom tracks the access until then
+  ptr: ManuallyDrop<MutPtr<T>>,
+}
+
+// Held shared access, released on drop.
+pub struct ReadGuard<'a, T> {
+  lock: &'a AqsRWLock<T>,
+  ptr: ManuallyDrop<ConstPtr<T>>,
+}
+
+unsafe impl<'a, T: Sync> Sync for WriteGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for ReadGuard<'a, T> {}
+
+impl<T> AqsRWLock<T> {
+  pub fn new(t: T) -> AqsRWLock<T> {
+    AqsRWLock{
+      cell: UnsafeCell::new(t),
+      head: CachePadded(Head{ tail: AtomicPtr::new(ptr::null_mut()), state: AtomicU32::new(0) }),
+    }
+  }
+
+  pub fn write(&self) -> WriteGuard<'_, T> {
+    self.lock(false);
+    self.write_guard()
+  }
+
+  pub fn read(&self) -> ReadGuard<'_, T> {
+    self.lock(true);
+    self.read_guard()
+  }
+
+  // Try acquires ignore the queue, like aqs_mutex_trylock.
+
+  pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+    if self.try_take(false) {
+      Some(self.write_guard())
+    } else {
+      None
+    }
+  }
+
+  pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+    if self.try_take(true) {
+      Some(self.read_guard())
+    } else {
+      None
+    }
+  }
+
+  pub fn with_write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+    f(&mut self.write())
+  }
+
+  pub fn with_read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+    f(&self.read())
+  }
+
+  fn write_guard(&self) -> WriteGuard<'_, T> {
+    WriteGuard{ lock: self, ptr: ManuallyDrop::new(self.cell.get_mut()) }
+  }
+
+  fn read_guard(&self) -> ReadGuard<'_, T> {
+    ReadGuard{ lock: self, ptr: ManuallyDrop::new(self.cell.get()) }
+  }
+
+  fn lock(&self, shared: bool) {
+    if self.try_steal(shared) {
+      return;
+    }
+
+    let state = &self.head.0.state;
+    let me = Owned::new();
+    if !queue::enqueue(&self.head.0.tail, &me) {
+      // First in the queue, stealing stays off until it is empty again
+      state.fetch_or(NO_STEALING, Ordering::SeqCst);
+    }
+
+    // At the head, nobody else may take the word now
+    while !self.try_take(shared) {
+      hint::spin_loop();
+    }
+
+    if queue::pass_on(&self.head.0.tail, &me) {
+      state.fetch_and(!NO_STEALING, Ordering::SeqCst);
+    }
+  }
+
+  // Takes the word unless someone queues.
+  fn try_steal(&self, shared: bool) -> bool {
+    let state = &self.head.0.state;
+    if shared {
+      let s = state.load(Ordering::SeqCst);
+      s & (LOCKED | NO_STEALING) == 0
+        && state.compare_exchange(s, s + READER, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    } else {
+      state.compare_exchange(0, LOCKED, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+  }
+
+  // Takes the word if it is free for us, queue or not.
+  fn try_take(&self, shared: bool) -> bool {
+    let state = &self.head.0.state;
+    let s = state.load(Ordering::SeqCst);
+    let busy = if shared { LOCKED } else { !NO_STEALING };
+    let taken = if shared { s + READER } else { s | LOCKED };
+    s & busy == 0 && state.compare_exchange(s, taken, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+  }
+}
+
+impl<'a, T> Deref for WriteGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // The guard proves exclusive access
+    unsafe { MutPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> DerefMut for WriteGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { MutPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> Drop for WriteGuard<'a, T> {
+  fn drop(&mut self) {
+    unsafe { ManuallyDrop::drop(&mut self.ptr) };
+    self.lock.head.0.state.fetch_and(!LOCKED, Ordering::SeqCst);
+  }
+}
+
+impl<'a, T> Deref for ReadGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // The guard proves no writer is in
+    unsafe { ConstPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> Drop for ReadGuard<'a, T> {
+  fn drop(&mut self) {
+    unsafe { ManuallyDrop::drop(&mut self.ptr) };
+    self.lock.head.0.state.fetch_sub(READER, Ordering::SeqCst);
+  }
+}
@@ -0,0 +1,86 @@
+// Spinning vs blocking waits, with up to 4x as many threads as CPUs.
+//
+//   cargo bench --bench blocking [-- <seconds per run>]
+//
+// Reports throughput and the CPU time it cost: oversubscribed spinners
+// burn the time slices the lock holder needs to make progress.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rwlock::{RWLock, Wait};
+
+// Work done while holding the lock, and between acquisitions
+const CRITICAL: usize = 64;
+const OUTSIDE: usize = 256;
+
+fn cpu_time() -> Duration {
+  let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+  unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+  let tv = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+  tv(usage.ru_utime) + tv(usage.ru_stime)
+}
+
+fn work(n: usize, x: &mut [u64]) {
+  for i in 0..n {
+    x[i % x.len()] = x[i % x.len()].wrapping_mul(31).wrapping_add(i as u64);
+  }
+}
+
+// Returns (ops/s, CPU seconds per second of wall time).
+fn run(wait: Wait, threads: usize, write_pct: usize, duration: Duration) -> (f64, f64) {
+  let lock = Arc::new(RWLock::with_wait(vec![0u64; 8], wait));
+  let stop = Arc::new(AtomicBool::new(false));
+  let start = Arc::new(Barrier::new(threads + 1));
+
+  let workers: Vec<_> = (0..threads)
+    .map(|t| {
+      let (lock, stop, start) = (lock.clone(), stop.clone(), start.clone());
+      thread::spawn(move || {
+        let mut local = [t as u64; 8];
+        let mut ops = 0u64;
+        start.wait();
+        while !stop.load(Ordering::Relaxed) {
+          if (ops as usize * 7 + t) % 100 < write_pct {
+            work(CRITICAL, &mut lock.write());
+          } else {
+            let v = lock.read();
+            local[0] ^= v[(ops % 8) as usize];
+            work(CRITICAL, &mut local);
+          }
+          work(OUTSIDE, &mut local);
+          ops += 1;
+        }
+        ops
+      })
+    })
+    .collect();
+
+  start.wait();
+  let (t0, cpu0) = (Instant::now(), cpu_time());
+  thread::sleep(duration);
+  stop.store(true, Ordering::Relaxed);
+  let ops: u64 = workers.into_iter().map(|w| w.join().unwrap()).sum();
+  let (wall, cpu) = (t0.elapsed(), cpu_time() - cpu0);
+
+  (ops as f64 / wall.as_secs_f64(), cpu.as_secs_f64() / wall.as_secs_f64())
+}
+
+fn main() {
+  // `cargo bench` passes `--bench`
+  let secs = std::env::args().skip(1).find_map(|a| a.parse::<f64>().ok()).unwrap_or(1.0);
+  let duration = Duration::from_secs_f64(secs);
+  let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+
+  println!("{:>6} {:>8} {:>7} {:>14} {:>8}", "wait", "threads", "write%", "ops/s", "cpus");
+  for write_pct in [1, 10, 50] {
+    for threads in [cpus, 2 * cpus, 4 * cpus] {
+      for wait in [Wait::Spin, Wait::Block] {
+        let (ops, load) = run(wait, threads, write_pct, duration);
+        println!("{:>6} {:>8} {:>7} {:>14.0} {:>8.2}", format!("{:?}", wait), threads, write_pct, ops, load);
+      }
+    }
+  }
+}
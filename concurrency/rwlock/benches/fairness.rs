@@ -0,0 +1,94 @@
+// Worst-case waits under each policy, with one thread taking the lock
+// the other way than a stream of threads hammering it.
+//
+//   cargo bench --bench fairness [-- <seconds per run>]
+//
+// A victim that waited about as long as the run is starved: it only got
+// in once the hammers stopped.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rwlock::{Policy, RWLock, Wait};
+
+// How long the hammers hold the lock
+const HOLD: Duration = Duration::from_micros(200);
+const HAMMERS: usize = 4;
+
+// Returns (max, mean) wait of the victim, and how often it got in.
+fn run(policy: Policy, wait: Wait, victim_writes: bool, duration: Duration) -> (Duration, Duration, u32) {
+  let lock = Arc::new(RWLock::with_options(0u64, policy, wait));
+  let stop = Arc::new(AtomicBool::new(false));
+
+  let hammers: Vec<_> = (0..HAMMERS)
+    .map(|_| {
+      let (lock, stop) = (lock.clone(), stop.clone());
+      thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+          if victim_writes {
+            let _guard = lock.read();
+            thread::sleep(HOLD);
+          } else {
+            let mut guard = lock.write();
+            *guard += 1;
+            thread::sleep(HOLD);
+          }
+        }
+      })
+    })
+    .collect();
+
+  let victim = {
+    let (lock, stop) = (lock.clone(), stop.clone());
+    thread::spawn(move || {
+      let (mut max, mut total, mut n) = (Duration::ZERO, Duration::ZERO, 0);
+      while !stop.load(Ordering::Relaxed) {
+        let start = Instant::now();
+        if victim_writes {
+          *lock.write() += 1;
+        } else {
+          drop(lock.read());
+        }
+        let waited = start.elapsed();
+        max = max.max(waited);
+        total += waited;
+        n += 1;
+        thread::sleep(HOLD);
+      }
+      (max, total / n.max(1), n)
+    })
+  };
+
+  thread::sleep(duration);
+  stop.store(true, Ordering::Relaxed);
+  for h in hammers {
+    h.join().unwrap();
+  }
+  victim.join().unwrap()
+}
+
+fn main() {
+  // `cargo bench` passes `--bench`
+  let secs = std::env::args().skip(1).find_map(|a| a.parse::<f64>().ok()).unwrap_or(1.0);
+  let duration = Duration::from_secs_f64(secs);
+
+  println!("{:>18} {:>6} {:>7} {:>12} {:>12} {:>8}", "policy", "wait", "victim", "max", "mean", "entries");
+  for policy in [Policy::ReaderPreferring, Policy::WriterPreferring, Policy::PhaseFair] {
+    for wait in [Wait::Spin, Wait::Block] {
+      for victim_writes in [false, true] {
+        let (max, mean, n) = run(policy, wait, victim_writes, duration);
+        println!(
+          "{:>18} {:>6} {:>7} {:>12} {:>12} {:>8}",
+          format!("{:?}", policy),
+          format!("{:?}", wait),
+          if victim_writes { "writer" } else { "reader" },
+          format!("{:.3?}", max),
+          format!("{:.3?}", mean),
+          n,
+        );
+      }
+    }
+  }
+}
@@ -0,0 +1,116 @@
+// Throughput of every lock here, with the Rust ports of the MCS and AQS
+// locks main.cpp compares against, as threads are added.
+//
+//   cargo bench --bench locks [-- <seconds per run>]
+//
+// All the locks spin, up to as many threads as CPUs.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rwlock::{AqsRWLock, BigReaderRWLock, McsLock, NumaRWLock, RWLock};
+
+// Work done while holding the lock, and between acquisitions
+const CRITICAL: usize = 64;
+const OUTSIDE: usize = 256;
+
+trait Lock: Send + Sync + 'static {
+  fn new() -> Self;
+  fn read_op(&self) -> u64;
+  fn write_op(&self);
+}
+
+fn work(n: usize, x: &mut [u64]) {
+  for i in 0..n {
+    x[i % x.len()] = x[i % x.len()].wrapping_mul(31).wrapping_add(i as u64);
+  }
+}
+
+macro_rules! lock {
+  ($lock:ident) => {
+    impl Lock for $lock<[u64; 8]> {
+      fn new() -> Self {
+        $lock::new([0; 8])
+      }
+
+      fn read_op(&self) -> u64 {
+        let v = self.read();
+        let mut local = *v;
+        work(CRITICAL, &mut local);
+        local[0]
+      }
+
+      fn write_op(&self) {
+        work(CRITICAL, &mut *self.write());
+      }
+    }
+  };
+}
+
+lock!(RWLock);
+lock!(BigReaderRWLock);
+lock!(NumaRWLock);
+lock!(McsLock);
+lock!(AqsRWLock);
+
+// Returns acquisitions per second.
+fn run<L: Lock>(threads: usize, write_pct: usize, duration: Duration) -> f64 {
+  let lock = Arc::new(L::new());
+  let stop = Arc::new(AtomicBool::new(false));
+  let start = Arc::new(Barrier::new(threads + 1));
+
+  let workers: Vec<_> = (0..threads)
+    .map(|t| {
+      let (lock, stop, start) = (lock.clone(), stop.clone(), start.clone());
+      thread::spawn(move || {
+        let mut local = [t as u64; 8];
+        let mut ops = 0u64;
+        start.wait();
+        while !stop.load(Ordering::Relaxed) {
+          if (ops as usize * 7 + t) % 100 < write_pct {
+            lock.write_op();
+          } else {
+            local[0] ^= lock.read_op();
+          }
+          work(OUTSIDE, &mut local);
+          ops += 1;
+        }
+        std::hint::black_box(local);
+        ops
+      })
+    })
+    .collect();
+
+  start.wait();
+  let t0 = Instant::now();
+  thread::sleep(duration);
+  stop.store(true, Ordering::Relaxed);
+  let ops: u64 = workers.into_iter().map(|w| w.join().unwrap()).sum();
+  ops as f64 / t0.elapsed().as_secs_f64()
+}
+
+fn main() {
+  // `cargo bench` passes `--bench`
+  let secs = std::env::args().skip(1).find_map(|a| a.parse::<f64>().ok()).unwrap_or(1.0);
+  let duration = Duration::from_secs_f64(secs);
+  let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+
+  println!("{:>7} {:>8} {:>12} {:>12} {:>12} {:>12} {:>12}",
+    "write%", "threads", "RWLock", "BigReader", "Numa", "MCS", "AQS");
+  for write_pct in [0, 10, 50] {
+    let mut threads = 1;
+    while threads <= cpus {
+      println!("{:>7} {:>8} {:>12.0} {:>12.0} {:>12.0} {:>12.0} {:>12.0}",
+        write_pct,
+        threads,
+        run::<RWLock<_>>(threads, write_pct, duration),
+        run::<BigReaderRWLock<_>>(threads, write_pct, duration),
+        run::<NumaRWLock<_>>(threads, write_pct, duration),
+        run::<McsLock<_>>(threads, write_pct, duration),
+        run::<AqsRWLock<_>>(threads, write_pct, duration));
+      threads *= 2;
+    }
+  }
+}
@@ -0,0 +1,89 @@
+// Read throughput of `RWLock` and `BigReaderRWLock` as threads are added,
+// with an occasional writer.
+//
+//   cargo bench --bench readers [-- <seconds per run>]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rwlock::{BigReaderRWLock, RWLock};
+
+// One in WRITE_EVERY acquisitions of thread 0 writes
+const WRITE_EVERY: u64 = 1000;
+
+trait Lock: Send + Sync + 'static {
+  fn read_sum(&self) -> u64;
+  fn bump(&self);
+}
+
+impl Lock for RWLock<[u64; 4]> {
+  fn read_sum(&self) -> u64 {
+    self.read().iter().sum()
+  }
+
+  fn bump(&self) {
+    self.write()[0] += 1;
+  }
+}
+
+impl Lock for BigReaderRWLock<[u64; 4]> {
+  fn read_sum(&self) -> u64 {
+    self.read().iter().sum()
+  }
+
+  fn bump(&self) {
+    self.write()[0] += 1;
+  }
+}
+
+// Returns acquisitions per second.
+fn run<L: Lock>(lock: L, threads: usize, duration: Duration) -> f64 {
+  let lock = Arc::new(lock);
+  let stop = Arc::new(AtomicBool::new(false));
+  let start = Arc::new(Barrier::new(threads + 1));
+
+  let workers: Vec<_> = (0..threads)
+    .map(|t| {
+      let (lock, stop, start) = (lock.clone(), stop.clone(), start.clone());
+      thread::spawn(move || {
+        let (mut ops, mut sum) = (0u64, 0u64);
+        start.wait();
+        while !stop.load(Ordering::Relaxed) {
+          if t == 0 && ops % WRITE_EVERY == 0 {
+            lock.bump();
+          } else {
+            sum = sum.wrapping_add(lock.read_sum());
+          }
+          ops += 1;
+        }
+        std::hint::black_box(sum);
+        ops
+      })
+    })
+    .collect();
+
+  start.wait();
+  let t0 = Instant::now();
+  thread::sleep(duration);
+  stop.store(true, Ordering::Relaxed);
+  let ops: u64 = workers.into_iter().map(|w| w.join().unwrap()).sum();
+  ops as f64 / t0.elapsed().as_secs_f64()
+}
+
+fn main() {
+  // `cargo bench` passes `--bench`
+  let secs = std::env::args().skip(1).find_map(|a| a.parse::<f64>().ok()).unwrap_or(1.0);
+  let duration = Duration::from_secs_f64(secs);
+  let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+
+  println!("{:>8} {:>14} {:>14}", "threads", "RWLock", "BigReader");
+  let mut threads = 1;
+  while threads <= cpus {
+    let single = run(RWLock::new([0u64; 4]), threads, duration);
+    let big = run(BigReaderRWLock::new([0u64; 4]), threads, duration);
+    println!("{:>8} {:>14.0} {:>14.0}", threads, single, big);
+    threads *= 2;
+  }
+}
@@ -0,0 +1,191 @@
+// A big-reader variant of the RWLock: readers count themselves in one of
+// several cache-line-padded slots instead of all hitting `rc`, so they
+// don't bounce a cache line between cores. Writers pay for it by
+// draining every slot. This is how the NR rwlock's `refCounts` (one per
+// thread, RC_WIDTH of them) work.
+//
+// Same protocol as `RWLock` with `Policy::WriterPreferring`: a writer
+// takes `exc`, then waits for the readers to drain. Always spins.
+
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+
+use crate::sync::atomic::{fence, AtomicBool, AtomicU32, Ordering};
+use crate::sync::cell::{ConstPtr, MutPtr, UnsafeCell};
+use crate::numa;
+use crate::padding::CachePadded;
+use crate::sync::{hint, thread_index};
+
+pub struct BigReaderRWLock<T> {
+  cell: UnsafeCell<T>,
+  exc: CachePadded<AtomicBool>,
+  // Reader counts, threads use slot `thread_index() % slots.len()`
+  // unless told otherwise (see `NumaRWLock`)
+  slots: Box<[CachePadded<AtomicU32>]>,
+}
+
+unsafe impl<T: Send> Send for BigReaderRWLock<T> {}
+unsafe impl<T: Send + Sync> Sync for BigReaderRWLock<T> {}
+
+// Held exclusive access, released on drop.
+pub struct WriteGuard<'a, T> {
+  lock: &'a BigReaderRWLock<T>,
+  // Dropped before releasing, loom tracks the access until then
+  ptr: ManuallyDrop<MutPtr<T>>,
+}
+
+// Held shared access, released on drop.
+pub struct ReadGuard<'a, T> {
+  lock: &'a BigReaderRWLock<T>,
+  // A thread could move, so remember where we counted ourselves
+  slot: usize,
+  ptr: ManuallyDrop<ConstPtr<T>>,
+}
+
+unsafe impl<'a, T: Sync> Sync for WriteGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for ReadGuard<'a, T> {}
+
+impl<T> BigReaderRWLock<T> {
+  // One slot per CPU.
+  pub fn new(t: T) -> BigReaderRWLock<T> {
+    BigReaderRWLock::with_slots(t, numa::cpus())
+  }
+
+  pub fn with_slots(t: T, slots: usize) -> BigReaderRWLock<T> {
+    assert!(slots > 0);
+    BigReaderRWLock{
+      cell: UnsafeCell::new(t),
+      exc: CachePadded(AtomicBool::new(false)),
+      slots: (0..slots).map(|_| CachePadded(AtomicU32::new(0))).collect(),
+    }
+  }
+
+  pub fn slots(&self) -> usize {
+    self.slots.len()
+  }
+
+  pub fn write(&self) -> WriteGuard<'_, T> {
+    while !self.try_take_exc() {
+      hint::spin_loop();
+    }
+    for slot in self.slots.iter() {
+      while slot.0.load(Ordering::SeqCst) != 0 {
+        hint::spin_loop();
+      }
+    }
+    self.write_guard()
+  }
+
+  pub fn read(&self) -> ReadGuard<'_, T> {
+    self.read_in(thread_index())
+  }
+
+  pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+    if !self.try_take_exc() {
+      return None;
+    }
+    if self.slots.iter().any(|slot| slot.0.load(Ordering::SeqCst) != 0) {
+      self.exc.0.store(false, Ordering::SeqCst);
+      return None;
+    }
+    Some(self.write_guard())
+  }
+
+  pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+    self.try_read_in(thread_index())
+  }
+
+  pub fn with_write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+    f(&mut self.write())
+  }
+
+  pub fn with_read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+    f(&self.read())
+  }
+
+  // Reads, counted in slot `slot % slots()`.
+  pub(crate) fn read_in(&self, slot: usize) -> ReadGuard<'_, T> {
+    let slot = slot % self.slots.len();
+    loop {
+      while self.exc.0.load(Ordering::Relaxed) {
+        hint::spin_loop();
+      }
+      if self.try_enter(slot) {
+        return self.read_guard(slot);
+      }
+    }
+  }
+
+  pub(crate) fn try_read_in(&self, slot: usize) -> Option<ReadGuard<'_, T>> {
+    let slot = slot % self.slots.len();
+    if self.try_enter(slot) {
+      Some(self.read_guard(slot))
+    } else {
+      None
+    }
+  }
+
+  fn write_guard(&self) -> WriteGuard<'_, T> {
+    WriteGuard{ lock: self, ptr: ManuallyDrop::new(self.cell.get_mut()) }
+  }
+
+  fn read_guard(&self, slot: usize) -> ReadGuard<'_, T> {
+    ReadGuard{ lock: self, slot, ptr: ManuallyDrop::new(self.cell.get()) }
+  }
+
+  fn try_take_exc(&self) -> bool {
+    let res = self.exc.0.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst);
+    // Pairs with the fence in `try_enter`, see `RWLock::take`
+    fence(Ordering::SeqCst);
+    res.is_ok()
+  }
+
+  fn try_enter(&self, slot: usize) -> bool {
+    self.slots[slot].0.fetch_add(1, Ordering::SeqCst);
+    // See `try_take_exc`
+    fence(Ordering::SeqCst);
+    if self.exc.0.load(Ordering::SeqCst) {
+      self.slots[slot].0.fetch_sub(1, Ordering::SeqCst);
+      return false;
+    }
+    true
+  }
+}
+
+impl<'a, T> Deref for WriteGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // The guard proves exclusive access
+    unsafe { MutPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> DerefMut for WriteGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { MutPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> Drop for WriteGuard<'a, T> {
+  fn drop(&mut self) {
+    unsafe { ManuallyDrop::drop(&mut self.ptr) };
+    self.lock.exc.0.store(false, Ordering::SeqCst);
+  }
+}
+
+impl<'a, T> Deref for ReadGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // The guard proves no writer is in
+    unsafe { ConstPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> Drop for ReadGuard<'a, T> {
+  fn drop(&mut self) {
+    unsafe { ManuallyDrop::drop(&mut self.ptr) };
+    self.lock.slots[self.slot].0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
@@ -0,0 +1,6 @@
+// Declares the `loom` cfg, so newer compilers don't warn about it. The
+// single colon keeps older cargos (see vspace's rust-toolchain.toml) happy.
+
+fn main() {
+  println!("cargo:rustc-check-cfg=cfg(loom)");
+}
@@ -0,0 +1,135 @@
+// The MCS queue lock (Mellor-Crummey and Scott), a port of
+// node-replication/mcs.c. Waiters queue up in FIFO order and each spins
+// on its own node, so a release only touches the next waiter's line.
+//
+// It is a mutex: readers take it exclusively too, like the `mcs`
+// benchmark of main.cpp does. The guards are the same as `RWLock`'s so
+// it can stand in for it. Always spins.
+
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+use crate::padding::CachePadded;
+use crate::queue::{self, Node, Owned};
+use crate::sync::atomic::{AtomicPtr, Ordering};
+use crate::sync::cell::{ConstPtr, MutPtr, UnsafeCell};
+
+pub struct McsLock<T> {
+  cell: UnsafeCell<T>,
+  // The last waiter, or the holder if nobody waits
+  tail: CachePadded<AtomicPtr<Node>>,
+}
+
+unsafe impl<T: Send> Send for McsLock<T> {}
+unsafe impl<T: Send> Sync for McsLock<T> {}
+
+// Held exclusive access, released on drop.
+pub struct WriteGuard<'a, T> {
+  lock: &'a McsLock<T>,
+  node: Owned,
+  // Dropped before releasing, loom tracks the access until then
+  ptr: ManuallyDrop<MutPtr<T>>,
+}
+
+// Held exclusive access that only hands out `&T`, released on drop.
+pub struct ReadGuard<'a, T> {
+  lock: &'a McsLock<T>,
+  node: Owned,
+  ptr: ManuallyDrop<ConstPtr<T>>,
+}
+
+unsafe impl<'a, T: Sync> Sync for WriteGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for ReadGuard<'a, T> {}
+
+impl<T> McsLock<T> {
+  pub fn new(t: T) -> McsLock<T> {
+    McsLock{
+      cell: UnsafeCell::new(t),
+      tail: CachePadded(AtomicPtr::new(ptr::null_mut())),
+    }
+  }
+
+  pub fn write(&self) -> WriteGuard<'_, T> {
+    let node = self.lock();
+    WriteGuard{ lock: self, node, ptr: ManuallyDrop::new(self.cell.get_mut()) }
+  }
+
+  pub fn read(&self) -> ReadGuard<'_, T> {
+    let node = self.lock();
+    ReadGuard{ lock: self, node, ptr: ManuallyDrop::new(self.cell.get()) }
+  }
+
+  pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+    let node = self.try_lock()?;
+    Some(WriteGuard{ lock: self, node, ptr: ManuallyDrop::new(self.cell.get_mut()) })
+  }
+
+  pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+    let node = self.try_lock()?;
+    Some(ReadGuard{ lock: self, node, ptr: ManuallyDrop::new(self.cell.get()) })
+  }
+
+  pub fn with_write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+    f(&mut self.write())
+  }
+
+  pub fn with_read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+    f(&self.read())
+  }
+
+  // The holder is at the head of the queue.
+  fn lock(&self) -> Owned {
+    let me = Owned::new();
+    queue::enqueue(&self.tail.0, &me);
+    me
+  }
+
+  // Queues only if nobody is there, mcs_mutex_trylock.
+  fn try_lock(&self) -> Option<Owned> {
+    let me = Owned::new();
+    let res = self.tail.0.compare_exchange(ptr::null_mut(), me.as_ptr(), Ordering::SeqCst, Ordering::SeqCst);
+    res.ok().map(|_| me)
+  }
+
+  fn unlock(&self, me: &Owned) {
+    queue::pass_on(&self.tail.0, me);
+  }
+}
+
+impl<'a, T> Deref for WriteGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // The guard proves exclusive access
+    unsafe { MutPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> DerefMut for WriteGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { MutPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> Drop for WriteGuard<'a, T> {
+  fn drop(&mut self) {
+    unsafe { ManuallyDrop::drop(&mut self.ptr) };
+    self.lock.unlock(&self.node);
+  }
+}
+
+impl<'a, T> Deref for ReadGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    unsafe { ConstPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> Drop for ReadGuard<'a, T> {
+  fn drop(&mut self) {
+    unsafe { ManuallyDrop::drop(&mut self.ptr) };
+    self.lock.unlock(&self.node);
+  }
+}
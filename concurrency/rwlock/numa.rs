@@ -0,0 +1,163 @@
+// A NUMA-aware RWLock, the Rust side of the NR rwlock
+// (node-replication/rwlock/RwLock.i.dfy): readers count themselves on
+// their node, so only writers touch other nodes' cache lines.
+//
+// It is a `BigReaderRWLock` with one slot per node, where readers pick
+// the slot of the node they run on instead of going by thread.
+
+use std::fs;
+
+use crate::big_reader::{BigReaderRWLock, ReadGuard, WriteGuard};
+
+// Which CPU belongs to which NUMA node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topology {
+  // Node of every CPU, CPUs not listed are on node 0
+  cpu_node: Vec<usize>,
+  nodes: usize,
+}
+
+impl Topology {
+  // Everything on one node.
+  pub fn single() -> Topology {
+    Topology{ cpu_node: Vec::new(), nodes: 1 }
+  }
+
+  // Reads the topology from sysfs, one node if there is none.
+  pub fn detect() -> Topology {
+    let mut cpulists = Vec::new();
+    let dir = match fs::read_dir("/sys/devices/system/node") {
+      Ok(dir) => dir,
+      Err(_) => return Topology::single(),
+    };
+    for entry in dir.flatten() {
+      let name = entry.file_name();
+      let id = match name.to_str().and_then(|n| n.strip_prefix("node")).and_then(|n| n.parse::<usize>().ok()) {
+        Some(id) => id,
+        None => continue,
+      };
+      if let Ok(list) = fs::read_to_string(entry.path().join("cpulist")) {
+        cpulists.push((id, list));
+      }
+    }
+    // Node ids can have holes (offline or memory-only nodes)
+    cpulists.sort();
+    let lists: Vec<&str> = cpulists.iter().map(|(_, list)| list.as_str()).collect();
+    Topology::from_cpulists(&lists).unwrap_or_else(|_| Topology::single())
+  }
+
+  // Builds a topology from the `cpulist` of every node (like "0-3,8-11"),
+  // in node order.
+  pub fn from_cpulists(lists: &[&str]) -> Result<Topology, String> {
+    let mut cpu_node = Vec::new();
+    for (node, list) in lists.iter().enumerate() {
+      for cpu in parse_cpulist(list)? {
+        if cpu >= cpu_node.len() {
+          cpu_node.resize(cpu + 1, 0);
+        }
+        cpu_node[cpu] = node;
+      }
+    }
+    Ok(Topology{ cpu_node, nodes: lists.len().max(1) })
+  }
+
+  pub fn nodes(&self) -> usize {
+    self.nodes
+  }
+
+  pub fn node_of(&self, cpu: usize) -> usize {
+    self.cpu_node.get(cpu).copied().unwrap_or(0)
+  }
+
+  // The node the calling thread runs on right now, it may move.
+  pub fn current_node(&self) -> usize {
+    match current_cpu() {
+      Some(cpu) => self.node_of(cpu),
+      None => 0,
+    }
+  }
+}
+
+fn parse_cpulist(list: &str) -> Result<Vec<usize>, String> {
+  let num = |s: &str| s.trim().parse::<usize>().map_err(|e| format!("{:?}: {}", s, e));
+  let mut cpus = Vec::new();
+  for range in list.trim().split(',').filter(|r| !r.trim().is_empty()) {
+    match range.split_once('-') {
+      Some((first, last)) => cpus.extend(num(first)?..=num(last)?),
+      None => cpus.push(num(range)?),
+    }
+  }
+  Ok(cpus)
+}
+
+#[cfg(target_os = "linux")]
+fn current_cpu() -> Option<usize> {
+  let cpu = unsafe { libc::sched_getcpu() };
+  if cpu < 0 {
+    None
+  } else {
+    Some(cpu as usize)
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_cpu() -> Option<usize> {
+  None
+}
+
+// The number of online CPUs.
+pub fn cpus() -> usize {
+  #[cfg(target_os = "linux")]
+  {
+    let n = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if n > 0 {
+      return n as usize;
+    }
+  }
+  1
+}
+
+pub struct NumaRWLock<T> {
+  lock: BigReaderRWLock<T>,
+  topology: Topology,
+}
+
+impl<T> NumaRWLock<T> {
+  pub fn new(t: T) -> NumaRWLock<T> {
+    NumaRWLock::with_topology(t, Topology::detect())
+  }
+
+  pub fn with_topology(t: T, topology: Topology) -> NumaRWLock<T> {
+    NumaRWLock{ lock: BigReaderRWLock::with_slots(t, topology.nodes()), topology }
+  }
+
+  pub fn topology(&self) -> &Topology {
+    &self.topology
+  }
+
+  pub fn write(&self) -> WriteGuard<'_, T> {
+    self.lock.write()
+  }
+
+  // Counts itself on the node we run on when called, the guard
+  // remembers which in case we get moved.
+  pub fn read(&self) -> ReadGuard<'_, T> {
+    self.lock.read_in(self.topology.current_node())
+  }
+
+  pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+    self.lock.try_write()
+  }
+
+  pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+    self.lock.try_read_in(self.topology.current_node())
+  }
+
+  pub fn with_write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+    f(&mut self.write())
+  }
+
+  pub fn with_read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+    f(&self.read())
+  }
+}
@@ -0,0 +1,7 @@
+// Keeping hot words on cache lines of their own, like padding.h of the
+// C locks in node-replication/.
+
+// Two lines (L_CACHE_LINE_SIZE), adjacent-line prefetching pulls pairs
+// on x86
+#[repr(align(128))]
+pub(crate) struct CachePadded<T>(pub T);
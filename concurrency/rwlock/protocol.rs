@@ -0,0 +1,196 @@
+// The RWLock.dfy protocol as an executable state machine, to check the
+// lock against it at runtime.
+//
+// With the `protocol` feature, `RWLock` reports every atomic step it takes
+// on `exc` and `rc` to a `Monitor`, which checks the step is one of the
+// transitions of RWLock.dfy (and matches what the lock saw) and that the
+// invariant holds after it. `Policy::PhaseFair` works on other counters
+// and isn't checked.
+//
+// The monitor applies a step under a mutex together with the atomic
+// operation it stands for, so checked locks are slow and their memory
+// orderings are stronger than they look. The orderings are loom's job.
+
+use std::fmt;
+
+#[cfg(feature = "protocol")]
+use crate::sync::Mutex;
+
+// The whole state of the extension of RWLock.dfy. The lock protects one
+// value, so `held_value` is whether it is checked in, and the reader
+// handles (all for that value) are a count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct State {
+  pub phys_exc: bool,
+  pub phys_rc: u64,
+  pub logical_exc: bool,
+  pub logical_rc: u64,
+  pub held_value: bool,
+  pub exc_pending_handle: bool,
+  pub exc_taken_handle: bool,
+  pub shared_pending_handles: u64,
+  pub shared_taken_handles: u64,
+}
+
+// The transitions, named after their `_step` predicates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+  // exc: false -> true
+  AcquireExcPending,
+  // Saw `rc` at `readers`: 0, or 1 for an upgrading reader, whose handle
+  // then becomes a pending one it gives back with `AcquireSharedAbort`
+  AcquireExcFinish{ readers: u64 },
+  // exc: true -> false, giving up before finishing
+  AcquireExcAbort,
+  // rc += 1
+  AcquireSharedPending,
+  // Saw exc false
+  AcquireSharedFinish,
+  // rc -= 1, after seeing exc true or giving up
+  AcquireSharedAbort,
+  // exc: true -> false
+  ReleaseExc,
+  // rc -= 1
+  ReleaseShared,
+}
+
+// Why a step can't happen.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Illegal(pub &'static str);
+
+impl fmt::Display for Illegal {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.0)
+  }
+}
+
+fn require(cond: bool, why: &'static str) -> Result<(), Illegal> {
+  if cond {
+    Ok(())
+  } else {
+    Err(Illegal(why))
+  }
+}
+
+impl State {
+  // A free lock.
+  pub fn init() -> State {
+    State{
+      phys_exc: false,
+      phys_rc: 0,
+      logical_exc: false,
+      logical_rc: 0,
+      held_value: true,
+      exc_pending_handle: false,
+      exc_taken_handle: false,
+      shared_pending_handles: 0,
+      shared_taken_handles: 0,
+    }
+  }
+
+  // `Inv`.
+  pub fn invariant(&self) -> bool {
+       (!self.logical_exc || self.logical_rc == 0 && self.phys_exc)
+    && (self.logical_rc <= self.phys_rc)
+    && (!self.exc_pending_handle || self.phys_exc && !self.logical_exc)
+    && (!self.exc_taken_handle || self.logical_exc)
+    && (self.logical_exc != self.held_value)
+    && (self.held_value || self.shared_taken_handles == 0 && self.logical_rc == 0)
+    && (self.phys_rc == self.shared_pending_handles + self.shared_taken_handles)
+    && (self.logical_rc == self.shared_taken_handles)
+  }
+
+  // The state after `step`. Besides what RWLock.dfy asks of a step, what
+  // the lock saw to take it must match the physical values.
+  pub fn next(&self, step: Step) -> Result<State, Illegal> {
+    let mut s = *self;
+    match step {
+      Step::AcquireExcPending => {
+        require(!s.phys_exc, "exc is already set")?;
+        s.phys_exc = true;
+        s.exc_pending_handle = true;
+      }
+      Step::AcquireExcFinish{ readers } => {
+        require(s.exc_pending_handle, "no writer is pending")?;
+        require(s.phys_rc == readers, "rc isn't what the writer saw")?;
+        require(s.held_value && s.logical_rc == readers, "other readers are in")?;
+        s.logical_exc = true;
+        s.held_value = false;
+        s.exc_pending_handle = false;
+        s.exc_taken_handle = true;
+        s.logical_rc -= readers;
+        s.shared_taken_handles -= readers;
+        s.shared_pending_handles += readers;
+      }
+      Step::AcquireExcAbort => {
+        require(s.exc_pending_handle, "no writer is pending")?;
+        s.phys_exc = false;
+        s.exc_pending_handle = false;
+      }
+      Step::AcquireSharedPending => {
+        s.phys_rc += 1;
+        s.shared_pending_handles += 1;
+      }
+      Step::AcquireSharedFinish => {
+        require(s.shared_pending_handles > 0, "no reader is pending")?;
+        require(!s.phys_exc, "exc isn't what the reader saw")?;
+        require(s.held_value, "nothing is held")?;
+        s.logical_rc += 1;
+        s.shared_pending_handles -= 1;
+        s.shared_taken_handles += 1;
+      }
+      Step::AcquireSharedAbort => {
+        require(s.shared_pending_handles > 0, "no reader is pending")?;
+        s.phys_rc -= 1;
+        s.shared_pending_handles -= 1;
+      }
+      Step::ReleaseExc => {
+        require(s.exc_taken_handle, "no writer is in")?;
+        s.phys_exc = false;
+        s.logical_exc = false;
+        s.held_value = true;
+        s.exc_taken_handle = false;
+      }
+      Step::ReleaseShared => {
+        require(s.shared_taken_handles > 0, "no reader is in")?;
+        s.phys_rc -= 1;
+        s.logical_rc -= 1;
+        s.shared_taken_handles -= 1;
+      }
+    }
+    Ok(s)
+  }
+}
+
+// Follows the steps of one lock.
+#[cfg(feature = "protocol")]
+pub(crate) struct Monitor {
+  state: Mutex<State>,
+}
+
+#[cfg(feature = "protocol")]
+impl Monitor {
+  pub fn new() -> Monitor {
+    Monitor{ state: Mutex::new(State::init()) }
+  }
+
+  pub fn state(&self) -> State {
+    *self.state.lock().unwrap()
+  }
+
+  // Runs `op` and takes the step it made, if any, as one atomic step.
+  // Panics if the step is illegal or breaks the invariant.
+  pub fn step<R>(&self, op: impl FnOnce() -> R, step: impl FnOnce(&R) -> Option<Step>) -> R {
+    let mut state = self.state.lock().unwrap();
+    let r = op();
+    if let Some(step) = step(&r) {
+      let next = match state.next(step) {
+        Ok(next) => next,
+        Err(why) => panic!("{:?} in {:?}: {}", step, *state, why),
+      };
+      assert!(next.invariant(), "{:?} breaks the invariant: {:?} -> {:?}", step, *state, next);
+      *state = next;
+    }
+    r
+  }
+}
@@ -0,0 +1,140 @@
+// The waiter queue of the MCS and AQS locks: a waiter links a node in
+// behind the old tail and spins on a flag of its own, so waiters don't
+// fight over a cache line (mcs_node_t of node-replication/mcs.h).
+
+use std::ops::Deref;
+use std::ptr::{self, NonNull};
+
+use crate::padding::CachePadded;
+use crate::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+pub(crate) struct Node {
+  // The waiter queued behind this one, set by that waiter
+  pub next: CachePadded<AtomicPtr<Node>>,
+  // Cleared by the waiter ahead when it is our turn
+  pub wait: CachePadded<AtomicBool>,
+}
+
+impl Node {
+  fn new() -> Node {
+    Node{
+      next: CachePadded(AtomicPtr::new(ptr::null_mut())),
+      wait: CachePadded(AtomicBool::new(true)),
+    }
+  }
+}
+
+// A node of the calling thread, which stays put until dropped. A thread
+// needs one for every lock it holds or waits for.
+pub(crate) struct Owned(NonNull<Node>);
+
+impl Owned {
+  pub fn new() -> Owned {
+    let node = cache::take();
+    node.next.0.store(ptr::null_mut(), Ordering::Relaxed);
+    node.wait.0.store(true, Ordering::Relaxed);
+    Owned(unsafe { NonNull::new_unchecked(Box::into_raw(node)) })
+  }
+
+  pub fn as_ptr(&self) -> *mut Node {
+    self.0.as_ptr()
+  }
+}
+
+impl Deref for Owned {
+  type Target = Node;
+
+  fn deref(&self) -> &Node {
+    unsafe { self.0.as_ref() }
+  }
+}
+
+impl Drop for Owned {
+  fn drop(&mut self) {
+    // Nobody else points at it once its owner is through the queue
+    cache::put(unsafe { Box::from_raw(self.0.as_ptr()) });
+  }
+}
+
+// Hands the head of the queue on from `me` to whoever queued behind it,
+// or empties the queue if nobody did. Returns whether it emptied it.
+pub(crate) fn pass_on(tail: &AtomicPtr<Node>, me: &Owned) -> bool {
+  let mut next = me.next.0.load(Ordering::SeqCst);
+  if next.is_null() {
+    let res = tail.compare_exchange(me.as_ptr(), ptr::null_mut(), Ordering::SeqCst, Ordering::SeqCst);
+    if res.is_ok() {
+      return true;
+    }
+    // Someone swapped itself in as the tail but hasn't linked in yet
+    loop {
+      next = me.next.0.load(Ordering::SeqCst);
+      if !next.is_null() { break; }
+      crate::sync::hint::spin_loop();
+    }
+  }
+  // `next` waits for this store, so it is still there
+  unsafe { (*next).wait.0.store(false, Ordering::SeqCst) };
+  false
+}
+
+// Queues `me` behind the current tail and waits for the waiter ahead to
+// pass the head on. Returns whether there was anyone ahead.
+pub(crate) fn enqueue(tail: &AtomicPtr<Node>, me: &Owned) -> bool {
+  let prev = tail.swap(me.as_ptr(), Ordering::SeqCst);
+  if prev.is_null() {
+    return false;
+  }
+  // `prev` waits for us to link in before it lets go of its node
+  unsafe { (*prev).next.0.store(me.as_ptr(), Ordering::SeqCst) };
+  while me.wait.0.load(Ordering::SeqCst) {
+    crate::sync::hint::spin_loop();
+  }
+  true
+}
+
+// Nodes are reused by their thread, allocating one per acquire shows in
+// the benchmarks. Loom's atomics can't outlive an execution, so there
+// every node is fresh.
+//
+// The nodes are boxed as they are handed out, and `const` thread locals
+// are newer than the oldest rustc the lock builds with.
+#[cfg(not(loom))]
+#[allow(clippy::vec_box, clippy::missing_const_for_thread_local)]
+mod cache {
+  use std::cell::RefCell;
+
+  use super::Node;
+
+  // More than a thread holds at once in any sane program
+  const KEEP: usize = 8;
+
+  thread_local! {
+    static FREE: RefCell<Vec<Box<Node>>> = RefCell::new(Vec::new());
+  }
+
+  pub(crate) fn take() -> Box<Node> {
+    // The cache is gone while the thread exits
+    let node = FREE.try_with(|free| free.borrow_mut().pop()).ok().flatten();
+    node.unwrap_or_else(|| Box::new(Node::new()))
+  }
+
+  pub(crate) fn put(node: Box<Node>) {
+    let _ = FREE.try_with(move |free| {
+      let mut free = free.borrow_mut();
+      if free.len() < KEEP {
+        free.push(node);
+      }
+    });
+  }
+}
+
+#[cfg(loom)]
+mod cache {
+  use super::Node;
+
+  pub(crate) fn take() -> Box<Node> {
+    Box::new(Node::new())
+  }
+
+  pub(crate) fn put(_node: Box<Node>) {}
+}
@@ -1,76 +1,695 @@
 #![allow(dead_code)]
 
-use std::sync::atomic::{AtomicU32, AtomicBool, Ordering};
-use std::cell::UnsafeCell;
+// Executable twin of the RWLock.dfy protocol (see rwlock_verified.rs for
+// how the proof maps onto it).
+//
+// The tests in tests/loom.rs model-check it with loom:
+//
+//   RUSTFLAGS="--cfg loom" cargo test --release --test loom
+//
+// With `--features protocol` the lock checks its steps against the
+// protocol as it goes (see protocol.rs).
+//
+// `cargo bench --bench blocking` compares the ways of waiting (`Wait`),
+// `cargo bench --bench fairness` the worst-case waits of each `Policy`,
+// `cargo bench --bench readers` how readers scale with `BigReaderRWLock`,
+// `cargo bench --bench locks` all the locks, with the ports of the MCS and
+// AQS locks the C++ benchmarks use.
 
-struct RWLock<T> {
+pub mod aqs;
+pub mod big_reader;
+pub mod mcs;
+pub mod numa;
+mod padding;
+pub mod protocol;
+mod queue;
+mod sync;
+
+pub use aqs::AqsRWLock;
+pub use big_reader::BigReaderRWLock;
+pub use mcs::McsLock;
+pub use numa::NumaRWLock;
+
+use std::mem::{self, ManuallyDrop};
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+
+use protocol::Step;
+use sync::atomic::{fence, AtomicU32, Ordering};
+use sync::cell::{ConstPtr, MutPtr, UnsafeCell};
+use sync::futex::Parking;
+use sync::hint;
+
+// How a thread that can't get the lock waits for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wait {
+  // Busy-spin until the lock is free.
+  Spin,
+  // Spin with exponential backoff for a bit, then sleep on a futex.
+  // Costs a syscall on release whenever someone sleeps.
+  Block,
+}
+
+// Who gets the lock first when both readers and writers wait for it.
+//
+// Only untimed acquires get these guarantees: timed and try acquires
+// never queue, they take the lock only when it is free for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+  // Readers get in whenever no writer is in, a stream of overlapping
+  // readers starves writers.
+  ReaderPreferring,
+  // A writer that takes `exc` keeps new readers out until it is done,
+  // a stream of writers starves readers.
+  WriterPreferring,
+  // Read and write phases alternate: a reader waits for at most one
+  // writer, a writer for the readers in before it and the writers
+  // queued before it (Brandenburg and Anderson's PF-T lock).
+  PhaseFair,
+}
+
+// Backoff rounds before sleeping, round i spins 2^i times. Spinning just
+// multiplies the interleavings loom explores, so go to sleep right away.
+#[cfg(not(loom))]
+const SPIN_ROUNDS: u32 = 7;
+#[cfg(loom)]
+const SPIN_ROUNDS: u32 = 1;
+
+const FREE: u32 = 0;
+const TAKEN: u32 = 1;
+
+// The low byte of `rin` holds the writer bits, the rest counts readers
+const RINC: u32 = 0x100;
+const WBITS: u32 = 0x3;
+// A writer is present
+const PRES: u32 = 0x2;
+// Which phase it is, so readers notice back-to-back writers
+const PHID: u32 = 0x1;
+
+pub struct RWLock<T> {
   cell: UnsafeCell<T>,
-  exc: AtomicBool,
+  // FREE or TAKEN, a u32 to be a futex word
+  exc: AtomicU32,
   rc: AtomicU32,
+  // Held by writers and the upgradable reader, so the upgradable reader
+  // can take `exc` without racing a writer (FREE or TAKEN)
+  upg: AtomicU32,
+  // Only used by `Policy::PhaseFair`: readers in, readers out (both
+  // counting in RINC, `rin` with the writer bits), and writer tickets.
+  // The upgradable reader holds a writer ticket instead of `upg`.
+  rin: AtomicU32,
+  rout: AtomicU32,
+  win: AtomicU32,
+  wout: AtomicU32,
+  policy: Policy,
+  wait: Wait,
+  // Threads sleeping on any of the words above
+  sleepers: AtomicU32,
+  parking: Parking,
+  #[cfg(feature = "protocol")]
+  monitor: protocol::Monitor,
+}
+
+// Moving the lock moves the `T`. Sharing it lets writers on any thread
+// get `&mut T` and readers on several threads `&T` at once.
+unsafe impl<T: Send> Send for RWLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RWLock<T> {}
+
+// Held exclusive access, released on drop.
+pub struct WriteGuard<'a, T> {
+  lock: &'a RWLock<T>,
+  // Dropped before releasing, loom tracks the access until then
+  ptr: ManuallyDrop<MutPtr<T>>,
+}
+
+// Held shared access, released on drop.
+pub struct ReadGuard<'a, T> {
+  lock: &'a RWLock<T>,
+  ptr: ManuallyDrop<ConstPtr<T>>,
+}
+
+// Held shared access that can be turned into exclusive access without
+// letting go of the lock, released on drop. There is at most one at a
+// time, next to any number of plain readers.
+pub struct UpgradableReadGuard<'a, T> {
+  lock: &'a RWLock<T>,
+  ptr: ManuallyDrop<ConstPtr<T>>,
 }
 
+// Sharing a guard only hands out `&T`. No guard is `Send`, like the ones
+// of std.
+unsafe impl<'a, T: Sync> Sync for WriteGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for ReadGuard<'a, T> {}
+unsafe impl<'a, T: Sync> Sync for UpgradableReadGuard<'a, T> {}
+
 impl<T> RWLock<T> {
   pub fn new(t: T) -> RWLock<T> {
-    return RWLock{
+    RWLock::with_options(t, Policy::WriterPreferring, Wait::Spin)
+  }
+
+  pub fn with_wait(t: T, wait: Wait) -> RWLock<T> {
+    RWLock::with_options(t, Policy::WriterPreferring, wait)
+  }
+
+  pub fn with_policy(t: T, policy: Policy) -> RWLock<T> {
+    RWLock::with_options(t, policy, Wait::Spin)
+  }
+
+  pub fn with_options(t: T, policy: Policy, wait: Wait) -> RWLock<T> {
+    RWLock{
       cell: UnsafeCell::new(t),
-      exc: AtomicBool::new(false),
+      exc: AtomicU32::new(FREE),
       rc: AtomicU32::new(0),
-    };
+      upg: AtomicU32::new(FREE),
+      rin: AtomicU32::new(0),
+      rout: AtomicU32::new(0),
+      win: AtomicU32::new(0),
+      wout: AtomicU32::new(0),
+      policy,
+      wait,
+      sleepers: AtomicU32::new(0),
+      parking: Parking::new(),
+      #[cfg(feature = "protocol")]
+      monitor: protocol::Monitor::new(),
+    }
+  }
+
+  pub fn policy(&self) -> Policy {
+    self.policy
+  }
+
+  pub fn wait(&self) -> Wait {
+    self.wait
+  }
+
+  // Where the lock is in the RWLock.dfy protocol.
+  #[cfg(feature = "protocol")]
+  pub fn protocol(&self) -> protocol::State {
+    self.monitor.state()
+  }
+
+  pub fn write(&self) -> WriteGuard<'_, T> {
+    let ok = self.lock_exclusive(None);
+    debug_assert!(ok);
+    self.write_guard()
+  }
+
+  pub fn read(&self) -> ReadGuard<'_, T> {
+    let ok = self.lock_shared(None);
+    debug_assert!(ok);
+    self.read_guard()
+  }
+
+  pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+    self.write_until(Instant::now())
+  }
+
+  pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+    self.read_until(Instant::now())
+  }
+
+  pub fn write_timeout(&self, timeout: Duration) -> Option<WriteGuard<'_, T>> {
+    self.write_until(Instant::now() + timeout)
+  }
+
+  pub fn read_timeout(&self, timeout: Duration) -> Option<ReadGuard<'_, T>> {
+    self.read_until(Instant::now() + timeout)
+  }
+
+  pub fn write_until(&self, deadline: Instant) -> Option<WriteGuard<'_, T>> {
+    if self.lock_exclusive(Some(deadline)) {
+      Some(self.write_guard())
+    } else {
+      None
+    }
+  }
+
+  pub fn read_until(&self, deadline: Instant) -> Option<ReadGuard<'_, T>> {
+    if self.lock_shared(Some(deadline)) {
+      Some(self.read_guard())
+    } else {
+      None
+    }
+  }
+
+  pub fn upgradable_read(&self) -> UpgradableReadGuard<'_, T> {
+    if self.policy == Policy::PhaseFair {
+      // Our turn as a writer keeps other writers out until we're done
+      self.take_ticket();
+      self.rin.fetch_add(RINC, Ordering::SeqCst);
+    } else {
+      let ok = self.take(&self.upg, None, None) && self.lock_shared(None);
+      debug_assert!(ok);
+    }
+    UpgradableReadGuard{ lock: self, ptr: ManuallyDrop::new(self.cell.get()) }
+  }
+
+  pub fn with_write<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+    f(&mut self.write())
+  }
+
+  pub fn with_read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+    f(&self.read())
   }
 
   pub fn acquire_exclusive(&self, fun: fn(&mut T) -> ()) {
+    self.with_write(fun)
+  }
+
+  pub fn acquire_shared(&self, fun: fn(& T) -> ()) {
+    self.with_read(fun)
+  }
+
+  fn write_guard(&self) -> WriteGuard<'_, T> {
+    WriteGuard{ lock: self, ptr: ManuallyDrop::new(self.cell.get_mut()) }
+  }
+
+  fn read_guard(&self) -> ReadGuard<'_, T> {
+    ReadGuard{ lock: self, ptr: ManuallyDrop::new(self.cell.get()) }
+  }
+
+  // Acquiring gives up with `false` once `deadline` passed, without a
+  // deadline it always succeeds.
+
+  fn lock_exclusive(&self, deadline: Option<Instant>) -> bool {
+    if self.policy == Policy::PhaseFair {
+      let queued = match deadline {
+        None => {
+          self.take_ticket();
+          true
+        }
+        Some(_) => self.try_take_ticket(deadline),
+      };
+      return queued && self.exclude_readers_phase_fair(deadline);
+    }
+
+    if !self.take(&self.upg, None, deadline) {
+      return false;
+    }
+    if !self.exclude_readers(0, deadline) {
+      self.release(&self.upg, None);
+      return false;
+    }
+    true
+  }
+
+  fn lock_shared(&self, deadline: Option<Instant>) -> bool {
+    match self.policy {
+      Policy::ReaderPreferring => self.read_reader_preferring(deadline),
+      Policy::WriterPreferring => self.read_writer_preferring(deadline),
+      Policy::PhaseFair => match deadline {
+        None => {
+          self.read_phase_fair();
+          true
+        }
+        Some(_) => self.try_read_phase_fair(deadline),
+      },
+    }
+  }
+
+  // Takes `flag` (`exc` or `upg`), which is `step` of the protocol.
+  fn take(&self, flag: &AtomicU32, step: Option<Step>, deadline: Option<Instant>) -> bool {
+    let mut rounds = 0;
     loop {
-      let res = self.exc.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst);
+      let res = self.step(
+        || flag.compare_exchange(FREE, TAKEN, Ordering::SeqCst, Ordering::SeqCst),
+        |res| step.filter(|_| res.is_ok()));
       if res.is_ok() {
         break;
       }
+      if !self.wait_while(flag, TAKEN, &mut rounds, deadline) {
+        return false;
+      }
     }
 
+    // Pairs with the fence in the readers: either we see the reader's
+    // increment, or it sees our `exc`. The SeqCst accesses already
+    // guarantee that, but tools like loom only model fences.
+    fence(Ordering::SeqCst);
+    true
+  }
+
+  fn release(&self, flag: &AtomicU32, step: Option<Step>) {
+    self.step(|| flag.store(FREE, Ordering::SeqCst), |_| step);
+    self.wake(flag);
+  }
+
+  // Waits for `rc` to drop to `readers`, seeing it is `step`.
+  fn wait_for_readers(&self, readers: u32, step: Option<Step>, deadline: Option<Instant>) -> bool {
+    let mut rounds = 0;
     loop {
-      let r = self.rc.load(Ordering::SeqCst);
-      if r == 0 {
-        break;
+      let r = self.step(|| self.rc.load(Ordering::SeqCst), |r| step.filter(|_| *r == readers));
+      if r == readers {
+        return true;
+      }
+      if !self.wait_while(&self.rc, r, &mut rounds, deadline) {
+        return false;
       }
     }
+  }
 
-    let mut borrow;
-    unsafe {
-      borrow = &mut *self.cell.get();
+  // With `upg` held, takes `exc` and waits for the readers other than
+  // ourselves (`readers` is how many of `rc` are ours) to leave.
+  fn exclude_readers(&self, readers: u32, deadline: Option<Instant>) -> bool {
+    let finish = Step::AcquireExcFinish{ readers: readers as u64 };
+    loop {
+      if !self.take(&self.exc, Some(Step::AcquireExcPending), deadline) {
+        return false;
+      }
+      if self.policy == Policy::WriterPreferring {
+        if self.wait_for_readers(readers, Some(finish), deadline) {
+          return true;
+        }
+        self.release(&self.exc, Some(Step::AcquireExcAbort));
+        return false;
+      }
+      let r = self.step(|| self.rc.load(Ordering::SeqCst), |r| Some(finish).filter(|_| *r == readers));
+      if r == readers {
+        return true;
+      }
+      // Readers first, they stay counted in `rc` while they wait
+      self.release(&self.exc, Some(Step::AcquireExcAbort));
+      if !self.wait_for_readers(readers, None, deadline) {
+        return false;
+      }
     }
-
-    fun(&mut borrow);
-
-    self.exc.store(false, Ordering::SeqCst);
   }
 
-  pub fn acquire_shared(&self, fun: fn(& T) -> ()) {
+  fn read_writer_preferring(&self, deadline: Option<Instant>) -> bool {
+    let mut rounds = 0;
     loop {
       loop {
         let r = self.exc.load(Ordering::Relaxed);
-        if !r { break; }
+        if r == FREE { break; }
+        if !self.wait_while(&self.exc, TAKEN, &mut rounds, deadline) {
+          return false;
+        }
       }
 
-      self.rc.fetch_add(1, Ordering::SeqCst);
+      self.step(|| self.rc.fetch_add(1, Ordering::SeqCst), |_| Some(Step::AcquireSharedPending));
 
-      let already_taken = self.exc.load(Ordering::SeqCst);
+      // See `take`
+      fence(Ordering::SeqCst);
+
+      let already_taken = self.step(
+        || self.exc.load(Ordering::SeqCst) == TAKEN,
+        |taken| Some(Step::AcquireSharedFinish).filter(|_| !taken));
 
       if !already_taken {
-        break;
+        return true;
       } else {
-        self.rc.fetch_sub(1, Ordering::SeqCst);
+        // The writer may have gone to sleep on our increment
+        self.leave_rc(Step::AcquireSharedAbort);
       }
     }
+  }
+
+  fn read_reader_preferring(&self, deadline: Option<Instant>) -> bool {
+    self.step(|| self.rc.fetch_add(1, Ordering::SeqCst), |_| Some(Step::AcquireSharedPending));
+
+    // See `take`
+    fence(Ordering::SeqCst);
+
+    // A writer either got in before our increment or will back off
+    let mut rounds = 0;
+    loop {
+      let taken = self.step(
+        || self.exc.load(Ordering::SeqCst) == TAKEN,
+        |taken| Some(Step::AcquireSharedFinish).filter(|_| !taken));
+      if !taken {
+        return true;
+      }
+      if !self.wait_while(&self.exc, TAKEN, &mut rounds, deadline) {
+        self.leave_rc(Step::AcquireSharedAbort);
+        return false;
+      }
+    }
+  }
+
+  // Queues up behind the other writers.
+  fn take_ticket(&self) {
+    let ticket = self.win.fetch_add(1, Ordering::SeqCst);
+    let mut rounds = 0;
+    loop {
+      let w = self.wout.load(Ordering::SeqCst);
+      if w == ticket {
+        break;
+      }
+      self.wait_while(&self.wout, w, &mut rounds, None);
+    }
+  }
+
+  // Takes a ticket only once no writer is queued: a ticket can't be
+  // given back, so there is no giving up once we have one.
+  fn try_take_ticket(&self, deadline: Option<Instant>) -> bool {
+    let mut rounds = 0;
+    loop {
+      let w = self.wout.load(Ordering::SeqCst);
+      let res = self.win.compare_exchange(w, w.wrapping_add(1), Ordering::SeqCst, Ordering::SeqCst);
+      if res.is_ok() {
+        return true;
+      }
+      if !self.wait_while(&self.wout, w, &mut rounds, deadline) {
+        return false;
+      }
+    }
+  }
+
+  // With our turn as a writer, blocks new readers and waits for the ones
+  // that got in before.
+  fn exclude_readers_phase_fair(&self, deadline: Option<Instant>) -> bool {
+    let ticket = self.wout.load(Ordering::SeqCst);
+    let entered = self.rin.fetch_add(PRES | (ticket & PHID), Ordering::SeqCst);
+    let mut rounds = 0;
+    loop {
+      let r = self.rout.load(Ordering::SeqCst);
+      if r == entered {
+        return true;
+      }
+      if !self.wait_while(&self.rout, r, &mut rounds, deadline) {
+        self.release_exclusive();
+        return false;
+      }
+    }
+  }
+
+  fn read_phase_fair(&self) {
+    let w = self.rin.fetch_add(RINC, Ordering::SeqCst) & WBITS;
+    if w == 0 {
+      return;
+    }
+
+    // Wait for this writer to leave, which flips the bits even if the
+    // next writer is already waiting
+    let mut rounds = 0;
+    loop {
+      let r = self.rin.load(Ordering::SeqCst);
+      if r & WBITS != w {
+        break;
+      }
+      self.wait_while(&self.rin, r, &mut rounds, None);
+    }
+  }
+
+  // Only enters while no writer is present: once counted in `rin` a
+  // reader must go through, or the writer's count of readers is off.
+  fn try_read_phase_fair(&self, deadline: Option<Instant>) -> bool {
+    let mut rounds = 0;
+    loop {
+      let r = self.rin.load(Ordering::SeqCst);
+      if r & WBITS == 0 {
+        let res = self.rin.compare_exchange(r, r.wrapping_add(RINC), Ordering::SeqCst, Ordering::SeqCst);
+        if res.is_ok() {
+          return true;
+        }
+      } else if !self.wait_while(&self.rin, r, &mut rounds, deadline) {
+        return false;
+      }
+    }
+  }
+
+  fn upgrade(&self) {
+    if self.policy == Policy::PhaseFair {
+      // Leave as a reader, so we don't wait for ourselves
+      self.rout.fetch_add(RINC, Ordering::SeqCst);
+      let ok = self.exclude_readers_phase_fair(None);
+      debug_assert!(ok);
+    } else {
+      let ok = self.exclude_readers(1, None);
+      debug_assert!(ok);
+      // Nobody waits for `rc` while we hold `upg`. Our reader handle
+      // turned pending when we got in, this gives it back.
+      self.step(|| self.rc.fetch_sub(1, Ordering::SeqCst), |_| Some(Step::AcquireSharedAbort));
+    }
+  }
+
+  // Waits for a bit while `word` holds `value`, returns to let the caller
+  // re-check, or `false` if `deadline` passed. `rounds` counts the
+  // backoff rounds so far.
+  fn wait_while(&self, word: &AtomicU32, value: u32, rounds: &mut u32, deadline: Option<Instant>) -> bool {
+    let timeout = match deadline {
+      None => None,
+      Some(deadline) => {
+        let now = Instant::now();
+        if now >= deadline {
+          return false;
+        }
+        Some(deadline - now)
+      }
+    };
 
-    let borrow;
-    unsafe {
-      borrow = & *self.cell.get();
+    if self.wait == Wait::Spin {
+      hint::spin_loop();
+      return true;
     }
 
-    fun(borrow);
+    if *rounds < SPIN_ROUNDS {
+      for _ in 0..1u32 << *rounds {
+        hint::spin_loop();
+      }
+      *rounds += 1;
+      return true;
+    }
+
+    self.sleepers.fetch_add(1, Ordering::SeqCst);
+    // Pairs with the fence in `wake`: either the releaser sees us
+    // sleeping, or we see its change of `word` (which the futex checks
+    // before sleeping).
+    fence(Ordering::SeqCst);
+    self.parking.wait(word, value, timeout);
+    self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    true
+  }
+
+  // Wakes the threads waiting for `word` to change, after changing it.
+  fn wake(&self, word: &AtomicU32) {
+    if self.wait == Wait::Spin {
+      return;
+    }
+
+    // See `wait_while`
+    fence(Ordering::SeqCst);
+    if self.sleepers.load(Ordering::SeqCst) > 0 {
+      self.parking.wake_all(word);
+    }
+  }
+
+  fn release_exclusive(&self) {
+    if self.policy == Policy::PhaseFair {
+      self.rin.fetch_and(!WBITS, Ordering::SeqCst);
+      self.wake(&self.rin);
+      self.wout.fetch_add(1, Ordering::SeqCst);
+      self.wake(&self.wout);
+    } else {
+      self.release(&self.exc, Some(Step::ReleaseExc));
+      self.release(&self.upg, None);
+    }
+  }
 
-    self.rc.fetch_sub(1, Ordering::SeqCst);
+  fn release_shared(&self) {
+    if self.policy == Policy::PhaseFair {
+      // Whether a writer waits for exactly this count is its business
+      self.rout.fetch_add(RINC, Ordering::SeqCst);
+      self.wake(&self.rout);
+    } else {
+      self.leave_rc(Step::ReleaseShared);
+    }
+  }
+
+  fn release_upgradable(&self) {
+    self.release_shared();
+    if self.policy == Policy::PhaseFair {
+      self.wout.fetch_add(1, Ordering::SeqCst);
+      self.wake(&self.wout);
+    } else {
+      self.release(&self.upg, None);
+    }
+  }
+
+  // Decrements `rc`, which is `step` of the protocol.
+  fn leave_rc(&self, step: Step) {
+    // Writers wait for `rc` to hit 0, an upgrading reader for 1
+    if self.step(|| self.rc.fetch_sub(1, Ordering::SeqCst), |_| Some(step)) <= 2 {
+      self.wake(&self.rc);
+    }
+  }
+
+  // Runs `op`, an atomic operation, which is the protocol step `step`
+  // returns for its result (if any). With the `protocol` feature the
+  // monitor checks that step.
+  #[inline(always)]
+  fn step<R>(&self, op: impl FnOnce() -> R, step: impl FnOnce(&R) -> Option<Step>) -> R {
+    #[cfg(feature = "protocol")]
+    return self.monitor.step(op, step);
+    #[cfg(not(feature = "protocol"))]
+    {
+      let _ = step;
+      op()
+    }
   }
 }
 
-fn main() {
+impl<'a, T> Deref for WriteGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // The guard proves exclusive access
+    unsafe { MutPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> DerefMut for WriteGuard<'a, T> {
+  fn deref_mut(&mut self) -> &mut T {
+    unsafe { MutPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> Drop for WriteGuard<'a, T> {
+  fn drop(&mut self) {
+    unsafe { ManuallyDrop::drop(&mut self.ptr) };
+    self.lock.release_exclusive();
+  }
+}
+
+impl<'a, T> Deref for ReadGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // The guard proves no writer is in
+    unsafe { ConstPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> Drop for ReadGuard<'a, T> {
+  fn drop(&mut self) {
+    unsafe { ManuallyDrop::drop(&mut self.ptr) };
+    self.lock.release_shared();
+  }
+}
+
+impl<'a, T> UpgradableReadGuard<'a, T> {
+  // Waits for the other readers to leave, new ones are kept out.
+  pub fn upgrade(mut self) -> WriteGuard<'a, T> {
+    let lock = self.lock;
+    unsafe { ManuallyDrop::drop(&mut self.ptr) };
+    mem::forget(self);
+    lock.upgrade();
+    lock.write_guard()
+  }
+}
+
+impl<'a, T> Deref for UpgradableReadGuard<'a, T> {
+  type Target = T;
+
+  fn deref(&self) -> &T {
+    // The guard proves no writer is in
+    unsafe { ConstPtr::deref(&self.ptr) }
+  }
+}
+
+impl<'a, T> Drop for UpgradableReadGuard<'a, T> {
+  fn drop(&mut self) {
+    unsafe { ManuallyDrop::drop(&mut self.ptr) };
+    self.lock.release_upgradable();
+  }
 }
@@ -1,318 +1,466 @@
-#![allow(dead_code)]
-
 // Note: this exploration file won't make much sense unless
 // you understand the RWLock.dfy example first.
 // Also see rwlock_unverified.rs.
+//
+// It is written against galvanize (concurrency/galvanize), which gives us
+// the primitives for manipulating ghost state we'd like a verifier to
+// have. There is no verifier yet, so galvanize keeps the ghost state
+// around at runtime and checks each step as it happens instead of
+// proving it once. Run it with
+//
+//   cargo test --test verified
+
+#![cfg(not(loom))]
 
-// Let's assume we have some basic primitives for manipulating ghost state:
+use std::fmt::Debug;
 
-use galvanize::Invariant;
-use galvanize::PCM;
-use galvanize::PCMExtension;
+use galvanize::{Excl, Multiset, PCMExtension, Shared, Token, PCM};
 
 // A replacement for UnsafeCell (equivalent, except that it's not unsafe
 // because we use ghost state to verify its behavior)
 
-use galvanize::VerifiedCell;
+use galvanize::{VerifiedCell, VerifiedCellValue};
+
+// A store for ghost state any thread can open, behind an invariant
+
+use galvanize::GhostTokenStore;
 
 // A library for verified atomics using ghost state:
 
-use galvanize::atomic::{AtomicU32, AtomicBool, Ordering};
+use galvanize::atomic::{AtomicBool, AtomicU32, Ordering};
 
-// Define the extension PCM like in the RWLock.dfy file:
+// Define the extension PCM like in the RWLock.dfy file. Unlike there,
+// phys_exc and phys_rc aren't part of the central state: each lives with
+// its atomic.
 
+#[derive(Clone, PartialEq, Debug)]
 struct CentralState<Base> {
   logical_exc: bool,
-  logical_rc: nat,
+  logical_rc: u64,
   held_value: Option<Base>,
 }
 
 // Auto-derive the monoidal properties
-// (Option and nat are simple 'built in' monoids that allow auto-deriving)
-
-#[derive(PCM)]
-struct RWLockProtocol<Base> {
-  phys_exc: Option<bool>,
-  phys_rc: Option<nat>,
-  central: Option<CentralState<Base>>,
-  exc_pending_handle: Option<()>,
-  exc_taken_handle: Option<()>,
-  shared_pending_handles: nat,
-  shared_taken_handles: fn (Base) -> nat,
+// (Excl, u64 for nat and Multiset for `Base -> nat` are 'built in' monoids)
+
+#[derive(PCM, Clone, PartialEq, Debug)]
+struct RWLockProtocol<Base: Ord> {
+  phys_exc: Excl<bool>,
+  phys_rc: Excl<u64>,
+  central: Excl<CentralState<Base>>,
+  exc_pending_handle: Excl<()>,
+  exc_taken_handle: Excl<()>,
+  shared_pending_handles: u64,
+  shared_taken_handles: Multiset<Base>,
 }
 
-impl<Base> RWLockProtocol<Base> {
+// Struct update syntax fills in all the other fields with unit, which is
+// what the UnitalInit! macro we imagined was for.
+
+#[allow(non_snake_case)]
+impl<Base: Ord + Clone + Debug + Send + 'static> RWLockProtocol<Base> {
   fn PhysExcHandle(phys_exc: bool) -> RWLockProtocol<Base> {
-    // Easy to imagine some helper macros
-    // e.g., UnitalInit fills in the '...' by adding all the
-    // other fields with 'unit', set to unit
-    // so you don't have to write all the boilerplate like the equivalent
-    // commented block, below
-
-    UnitalInit!(RWLockProtocol{ phys_exc: Some(phys_exc), ... })
-
-    /*RWLockProtocol{
-      phys_exc: Some(phys_exc),
-      phys_rc: None,
-      central: None,
-      exc_pending_handle: None,
-      exc_taken_handle: None,
-      shared_pending_handles: 0,
-      shared_taken_handles: (|x| -> 0),
-    }*/
+    RWLockProtocol{ phys_exc: Excl::Own(phys_exc), ..PCM::unit() }
   }
 
-  fn PhysRcHandle(phys_rc: nat) -> RWLockProtocol<Base> {
-    UnitalInit!(RWLockProtocol{ phys_rc: Some(phys_rc), ... })
+  fn PhysRcHandle(phys_rc: u64) -> RWLockProtocol<Base> {
+    RWLockProtocol{ phys_rc: Excl::Own(phys_rc), ..PCM::unit() }
   }
 
-  fn CentralHandle(central: CentralState) -> RWLockProtocol<Base> {
-    UnitalInit!(RWLockProtocol{ central: Some(central), ... })
+  fn CentralHandle(central: CentralState<Base>) -> RWLockProtocol<Base> {
+    RWLockProtocol{ central: Excl::Own(central), ..PCM::unit() }
   }
 
   fn ExcPendingHandle() -> RWLockProtocol<Base> {
-    UnitalInit!(RWLockProtocol{ exc_pending_handle: Some(()), ... })
+    RWLockProtocol{ exc_pending_handle: Excl::Own(()), ..PCM::unit() }
   }
 
   fn ExcTakenHandle() -> RWLockProtocol<Base> {
-    UnitalInit!(RWLockProtocol{ exc_taken_handle: Some(()), ... })
+    RWLockProtocol{ exc_taken_handle: Excl::Own(()), ..PCM::unit() }
   }
 
   fn SharedPendingHandle() -> RWLockProtocol<Base> {
-    UnitalInit!(RWLockProtocol{ shared_pending_handles: 1, ... })
+    RWLockProtocol{ shared_pending_handles: 1, ..PCM::unit() }
   }
 
   fn SharedTakenHandle(b: Base) -> RWLockProtocol<Base> {
-    UnitalInit!(RWLockProtocol{ 
-      shared_taken_handles: (|x| -> (if x == b { 1 } else { 0 })),
-      ...
-    })
+    RWLockProtocol{ shared_taken_handles: Multiset::singleton(b), ..PCM::unit() }
   }
 }
 
-// I want to somehow create a trait that ties Base and RWLockProtocol<Base>
-// together. Not sure if this is possible in Rust ???
-impl PCMExtension for (Base, RWLockProtocol<Base>) {
-  // Proof code for the invariants, transitions from the RWLock.dfy example
-  // all go here.
-  fn invariant(m: RWLockProtocol<Base>) -> bool { ... }
-  fn interp(m: RWLockProtocol<Base>) -> Base { ... }
-
-  #[internal_transition]
-  fn acquire_exc_pending_step(
-    m: RWLockProtocol<Base>,
-    m': RWLockProtocol<Base>)
-  {
-       m == PhysExc(false)
-    && m' == PhysExc(true) · ExcPendingHandle()
+// The PCMExtension trait ties RWLockProtocol<Base> to the PCM it manages,
+// here the permission of a VerifiedCell (Base is its value).
+impl<Base: Ord + Clone + Debug + Send + 'static> PCMExtension for RWLockProtocol<Base> {
+  type Base = Excl<Base>;
+
+  fn invariant(&self) -> bool {
+    let (phys_exc, phys_rc, central) = match (&self.phys_exc, &self.phys_rc, &self.central) {
+      (Excl::Own(e), Excl::Own(rc), Excl::Own(c)) => (*e, *rc, c),
+      _ => return false,
+    };
+    let taken = |b: &Base| self.shared_taken_handles.count(b);
+    let held_taken = central.held_value.as_ref().map_or(0, taken);
+
+       (!central.logical_exc || central.logical_rc == 0 && phys_exc)
+    && (central.logical_rc <= phys_rc)
+    && (self.exc_pending_handle == Excl::Unit || phys_exc && !central.logical_exc)
+    && (self.exc_taken_handle == Excl::Unit || central.logical_exc)
+    && (central.logical_exc != central.held_value.is_some())
+    // Nobody holds a handle to anything but the held value (or anything
+    // at all while it's checked out)
+    && (self.shared_taken_handles.len() == held_taken)
+    && (phys_rc == self.shared_pending_handles + held_taken)
+    && (central.logical_rc == held_taken)
+  }
+
+  fn interp(&self) -> Excl<Base> {
+    // Some thread has the exclusive lock when nothing is held, and the
+    // lock has the unit.
+    match self.central.own().and_then(|c| c.held_value.clone()) {
+      Some(b) => Excl::Own(b),
+      None => Excl::Unit,
+    }
   }
 
-  // ... all the other transitions from RWLock.dfy go here
-  // as well as proofs about invariant-preservation and so on
+  // The borrow_shared_handle lemma: a reader handle lends its value.
+  fn lends(&self) -> Option<Excl<Base>> {
+    self.shared_taken_handles.iter().next().map(|(b, _)| Excl::Own(b.clone()))
+  }
 }
 
-struct RWLock<T> {
-  cell: VerifiedCell<T>,
+// The transitions of RWLock.dfy, on tokens. Each joins the tokens it is
+// given into the state `m` of the step, moves to `m'` and splits that
+// back up. Galvanize checks `m -> m'` preserves the invariant for the
+// rest of the state, which is what the `_preserves` lemmas prove.
 
-  // VerifiedCellValue<T> is the type of the ghost-state that gives you
-  // permission to access VerifiedCell<T>.
-  // RWLockProtocol<VerifiedCellValue<T>>, then, is a ghost state for
-  // the RWLockProtocol that manages access to VerifiedCell<T>.
-  // Finally, GhostTokenStore<...> allows atomic access to a piece of said ghost state
-  ghost central_token: GhostTokenStore<RWLockProtocol<VerifiedCellValue<T>>>
+type Protocol = RWLockProtocol<VerifiedCellValue>;
+type ProtocolToken = Token<Protocol>;
+type CellToken = Token<Excl<VerifiedCellValue>>;
 
-  // Atomics store ghost state as well
-  exc: AtomicBool<RWLockProtocol<VerifiedCellValue<T>>>,
-  rc: AtomicU32<RWLockProtocol<VerifiedCellValue<T>>>,
+fn central(t: &ProtocolToken) -> CentralState<VerifiedCellValue> {
+  t.value().central.own().expect("not the central token").clone()
 }
 
+fn phys_rc(t: &ProtocolToken) -> u64 {
+  *t.value().phys_rc.own().expect("not the rc token")
+}
 
-impl<T> RWLock<T> {
-  ghost fn central_inv(t: &RWLockProtocol) {
-    t.central.is_some()
+impl Protocol {
+  // exc: false -> true
+  fn acquire_exc_pending(exc: ProtocolToken) -> (ProtocolToken, ProtocolToken) {
+    let (e, p) = (Protocol::PhysExcHandle(true), Protocol::ExcPendingHandle());
+    exc.update(e.op(&p)).split(e, p)
   }
 
-  // Invariants that tie the ghost state to the actual values stored in the Atomics
+  // Observed rc == 0, takes out the cell permission
+  fn acquire_exc_finish(rc: ProtocolToken, central_token: ProtocolToken, pending: ProtocolToken)
+    -> (ProtocolToken, ProtocolToken, ProtocolToken, CellToken)
+  {
+    let mut c = central(&central_token);
+    let b = c.held_value.take().expect("nothing held");
+    c.logical_exc = true;
+
+    let (r, c, t) = (Protocol::PhysRcHandle(phys_rc(&rc)), Protocol::CentralHandle(c), Protocol::ExcTakenHandle());
+    let (m, cell_token) = rc.join(central_token).join(pending).withdraw(r.op(&c).op(&t), Excl::Own(b));
+    let (rc, m) = m.split(r, c.op(&t));
+    let (central_token, taken) = m.split(c, t);
+    (rc, central_token, taken, cell_token)
+  }
 
-  ghost fn exc_inv(b: bool, t: &RWLockProtocol) {
-    t == PhysExcHandle(b)
+  // rc += 1
+  fn acquire_shared_pending(rc: ProtocolToken) -> (ProtocolToken, ProtocolToken) {
+    let (r, p) = (Protocol::PhysRcHandle(phys_rc(&rc) + 1), Protocol::SharedPendingHandle());
+    rc.update(r.op(&p)).split(r, p)
   }
 
-  ghost fn shared_inv(n: nat, t: &RWLockProtocol) {
-    t == PhysSharedHandle(n)
+  // Observed exc == false
+  fn acquire_shared_finish(exc: ProtocolToken, central_token: ProtocolToken, pending: ProtocolToken)
+    -> (ProtocolToken, ProtocolToken, ProtocolToken)
+  {
+    let mut c = central(&central_token);
+    let b = c.held_value.expect("nothing held");
+    c.logical_rc += 1;
+
+    let (e, c, t) = (exc.value().clone(), Protocol::CentralHandle(c), Protocol::SharedTakenHandle(b));
+    let m = exc.join(central_token).join(pending).update(e.op(&c).op(&t));
+    let (exc, m) = m.split(e, c.op(&t));
+    let (central_token, taken) = m.split(c, t);
+    (exc, central_token, taken)
   }
 
-  ghost fn invariant(&self) {
-    // We have several tokens floating around - make sure they all refer
-    // to the same instance of the protocol. Also make sure they correspond
-    // to the VerifiedCell instance they are supposed to be managing
-       self.central_token.loc.base == self.cell.loc
-    && self.exc.loc == self.central_token.loc
-    && self.rc.loc == self.central_token.loc
-
-    // Tie the Atomics and the Invariant to the invariants defined above
-    && self.central_token.inv == central_inv
-    && self.exc.inv == exc_inv
-    && self.rc.inv == rc_inv
+  // Observed exc == true, rc -= 1 to let the writer in. RWLock.dfy
+  // doesn't have this step yet.
+  fn acquire_shared_abort(rc: ProtocolToken, pending: ProtocolToken) -> ProtocolToken {
+    let r = Protocol::PhysRcHandle(phys_rc(&rc) - 1);
+    rc.join(pending).update(r)
   }
 
+  // exc: true -> false, puts the cell permission back
+  fn release_exc(exc: ProtocolToken, central_token: ProtocolToken, taken: ProtocolToken, cell_token: CellToken)
+    -> (ProtocolToken, ProtocolToken)
+  {
+    let mut c = central(&central_token);
+    c.logical_exc = false;
+    c.held_value = cell_token.value().own().cloned();
+
+    let (e, c) = (Protocol::PhysExcHandle(false), Protocol::CentralHandle(c));
+    exc.join(central_token).join(taken).deposit(cell_token, e.op(&c)).split(e, c)
+  }
+
+  // rc -= 1
+  fn release_shared(rc: ProtocolToken, central_token: ProtocolToken, taken: ProtocolToken)
+    -> (ProtocolToken, ProtocolToken)
+  {
+    let mut c = central(&central_token);
+    c.logical_rc -= 1;
+
+    let (r, c) = (Protocol::PhysRcHandle(phys_rc(&rc) - 1), Protocol::CentralHandle(c));
+    rc.join(central_token).join(taken).update(r.op(&c)).split(r, c)
+  }
+
+  // Shared access to the cell, for as long as we hold the handle
+  fn borrow(taken: &ProtocolToken) -> Shared<'_, Excl<VerifiedCellValue>> {
+    taken.borrow()
+  }
+}
+
+pub struct RWLock<T> {
+  cell: VerifiedCell<T>,
+
+  // VerifiedCellValue is the value of the ghost-state that gives you
+  // permission to access VerifiedCell<T>.
+  // RWLockProtocol<VerifiedCellValue>, then, is a ghost state for
+  // the RWLockProtocol that manages access to VerifiedCell<T>.
+  // Finally, GhostTokenStore<...> allows atomic access to a piece of said ghost state
+  central_token: GhostTokenStore<Protocol>,
+
+  // Atomics store ghost state as well
+  exc: AtomicBool<Protocol>,
+  rc: AtomicU32<Protocol>,
+}
+
+// Invariants that tie the ghost state to the actual values stored in the
+// Atomics. The tokens all refer to the same instance of the protocol
+// because transitions only join tokens of one instance.
+
+fn central_inv(t: &ProtocolToken) -> bool {
+  t.value().central.own().is_some()
+}
+
+fn exc_inv(b: bool, t: &ProtocolToken) -> bool {
+  *t.value() == Protocol::PhysExcHandle(b)
+}
+
+fn rc_inv(n: u32, t: &ProtocolToken) -> bool {
+  *t.value() == Protocol::PhysRcHandle(n as u64)
+}
+
+impl<T> RWLock<T> {
   pub fn new(t: T) -> RWLock<T> {
-    // The 'VerifiedCell' library would let us create a 'cell token' which represents
+    // The VerifiedCell library creates a 'cell token' which represents
     // permission to access the contents of the VerifiedCell. So for example,
     // if we have exclusive access to the cell token, we can get a &mut borrow from
     // the VerifiedCell. If we have shared access to the cell token, we can get a
     // shared &borrow from the VerifiedCell.
 
-    // Keep in mind the token is "ghost".
-
-    // cell: VerifiedCell<T>
-    // token: Token<VerifiedCellValue<T>>
-
     let (cell, token) = VerifiedCell::new(t);
 
-    // Instantiate an RWLockProtocol to manage access to the 'cell token'.
-
-    // Again, I'm imagining some cool syntax that makes this less cumbersome.
-    // This 'let token ... from ...' syntax would let you both
-    //  (i) create some RWLockProtocol-state from the CellToken-state and
-    //  (ii) split it into three parts for us
-    // with a lot of cumbersome repeated boilerplate
-
-    GhostManipulate!(
-      let token phys_exc_token = PhysExcHandle(false);
-      let token phys_rc_handle = PhysRcHandle(0);
-      let token central_token = CentralHandle(CentralState{
-            logical_exc: false,
-            phys_rc: 0,
-            held_value: Some(token.value()),
-          });
-      from PCMExtensionCreate(token)
-    );
+    // Instantiate an RWLockProtocol to manage access to the 'cell token',
+    // and split its state in three.
+
+    let (e, r) = (Protocol::PhysExcHandle(false), Protocol::PhysRcHandle(0));
+    let c = Protocol::CentralHandle(CentralState{
+      logical_exc: false,
+      logical_rc: 0,
+      held_value: Some(cell.id()),
+    });
+    let m = Token::extend(token, e.op(&r).op(&c));
+    let (phys_exc_token, m) = m.split(e, r.op(&c));
+    let (phys_rc_token, central_token) = m.split(r, c);
 
     // Build the object.
 
-    return RWLock{
-      cell: VerifiedCell::new(t),
+    RWLock{
+      cell,
       central_token: GhostTokenStore::new(central_token, central_inv),
       exc: AtomicBool::new(false, phys_exc_token, exc_inv),
       rc: AtomicU32::new(0, phys_rc_token, rc_inv),
-    };
+    }
   }
 
-  pub fn acquire_exclusive(&self, fun: fn(&mut T) -> ()) {
-    ghost let pending_handle;
-
-    loop {
-      // Here, I'm imagining some special syntax for convenient access to the ghost
-      // state of the atomic. The idea is this:
-      // When you perform the compare_exchange, we are doing some transition on the
-      // physical staet `old_b -> new_b` (e.g., on success, we end up doing a transition
-      // false -> true). Inside the 'atomic' block we can execute ghost code that manipulates
-      // the ghost state (and returns it at the end). We have to make sure that the invariants
-      // are maintained.
-
-      let res = atomic!(self.exc.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst) acquire (exc_token, old_b, new_b) {
-        if res.is_okay() {
-          exc_token, pending_handle := RWLockProtocol::acquire_exc_pending(exc_token);
-        } else {
-          // do nothing
-        }
-        release exc_token;
-      });
-      
-      if res.is_okay() {
-        break;
+  pub fn acquire_exclusive(&self, fun: fn(&mut T)) {
+    // When you perform the compare_exchange, we are doing some transition on the
+    // physical state `old_b -> new_b` (e.g., on success, we end up doing a transition
+    // false -> true). The closure is ghost code that runs in the same atomic step,
+    // it gets the atomic's token and has to give one back that still satisfies
+    // the invariants.
+
+    let mut pending_handle = loop {
+      let (_, pending) = self.exc.compare_exchange_with(false, true, Ordering::SeqCst, Ordering::SeqCst,
+        |res, exc_token| {
+          if res.is_ok() {
+            let (exc_token, pending_handle) = Protocol::acquire_exc_pending(exc_token);
+            (exc_token, Some(pending_handle))
+          } else {
+            // do nothing
+            (exc_token, None)
+          }
+        });
+
+      if let Some(pending) = pending {
+        break Some(pending);
       }
-    }
+    };
 
     // At this point we've obtained an ExcPendingHandle token.
     // Now for the second phase of the 'acquire' step.
     // (acquire_exc_finish_step)
 
-    ghost let cell_token;
-    ghost let taken_handle;
-
-    loop {
-      let r = atomic!(self.rc.load(Ordering::SeqCst) acquire (rc_token) {
+    let (taken_handle, mut cell_token) = loop {
+      let (_, taken) = self.rc.load_with(Ordering::SeqCst, |rc, rc_token| {
         if rc == 0 {
-          atomic!(self.central_token.open() acquire (central_token) {
-            rc_token, central_token, cell_token, taken_handle = RWLockProtocol::transition_exc_finish(rc_token, central_token, pending_handle);
-            release central_token;
-          });
+          self.central_token.open(|central_token| {
+            let (rc_token, central_token, taken_handle, cell_token) =
+              Protocol::acquire_exc_finish(rc_token, central_token, pending_handle.take().unwrap());
+            (central_token, (rc_token, Some((taken_handle, cell_token))))
+          })
+        } else {
+          (rc_token, None)
         }
-        release rc_token;
       });
 
-      if r == 0 {
-        break;
+      if let Some(taken) = taken {
+        break taken;
       }
-    }
+    };
 
     // This step lets us obtain the CellToken! Remember, we originally put the
     // Cell token "into the RWLockProtocol world". Here, we've now "extracted"
     // it from that world and we can manipulate it normally. Since we own the CellToken,
     // we can get &mut access to the contents of the VerifiedCell.
 
-    let borrow = &mut *self.t.get_mut(cell_token);
-    fun(&mut borrow);
-
-    // borrow expires, we get cell_token back?
-    // this part is a little awkward
+    fun(self.cell.borrow_mut(&mut cell_token));
 
     // Do the release step (i.e., put the CellToken back into the RWLockProtocol world)
-    let res = atomic!(self.exc.store(false, Ordering::SeqCst) acquire(exc_token) {
-      atomic self.central_token.open() acquire (central_token) {
-        exc_token, central_token = RWLockProtocol::release_exc(exc_token, central_token, cell_token, taken_handle);
-        release central_token;
-      }
-      release exc_token;
+    self.exc.store_with(false, Ordering::SeqCst, |_, _, exc_token| {
+      self.central_token.open(|central_token| {
+        let (exc_token, central_token) = Protocol::release_exc(exc_token, central_token, taken_handle, cell_token);
+        (central_token, (exc_token, ()))
+      })
     });
   }
 
-  pub fn acquire_shared(&self, fun: fn(& T) -> ()) {
+  pub fn acquire_shared(&self, fun: fn(&T)) {
     // Process for acquiring shared access is pretty similar at first.
 
-    let shared_handle;
-
-    loop {
+    let shared_handle = loop {
       loop {
         let r = self.exc.load(Ordering::Relaxed);
         if !r { break; }
       }
 
-      atomic!(self.rc.fetch_add(1, Ordering::SeqCst) {
-        // ... (acquire_shared_pending_step)
+      // (acquire_shared_pending_step)
+      let (_, pending_handle) = self.rc.fetch_add_with(1, Ordering::SeqCst, |_, _, rc_token| {
+        Protocol::acquire_shared_pending(rc_token)
       });
 
-      let already_taken = atomic!(self.exc.load(Ordering::SeqCst) {
+      let (_, taken) = self.exc.load_with(Ordering::SeqCst, |already_taken, exc_token| {
         if !already_taken {
-          // ... (acquire_shared_finish)
+          // (acquire_shared_finish_step)
+          self.central_token.open(|central_token| {
+            let (exc_token, central_token, shared_handle) =
+              Protocol::acquire_shared_finish(exc_token, central_token, pending_handle);
+            (central_token, (exc_token, Ok(shared_handle)))
+          })
         } else {
+          (exc_token, Err(pending_handle))
         }
       });
 
-      if !already_taken {
-        break;
-      } else {
-        // abort and try again
-        atomic!(self.rc.fetch_sub(1, Ordering::SeqCst) {
-          // ...
-        });
+      match taken {
+        Ok(shared_handle) => break shared_handle,
+        Err(pending_handle) => {
+          // abort and try again
+          self.rc.fetch_sub_with(1, Ordering::SeqCst, |_, _, rc_token| {
+            (Protocol::acquire_shared_abort(rc_token, pending_handle), ())
+          });
+        }
       }
-    }
+    };
 
     // The main difference here is that we get a 'shared borrow' of the cell_token
     // rather than a 'mut borrow'.
-    let cell_token = & RWLockProtocol::borrow(shared_handle);
+    let cell_token = Protocol::borrow(&shared_handle);
 
     // With that 'shared borrow' we can get a 'shared borrow' of the object in the
     // VerifiedCell.
-    let borrow = self.cell.get_borrow(cell_token);
+    let borrow = self.cell.borrow(cell_token);
 
     fun(borrow);
 
-    atomic!(self.rc.fetch_sub(1, Ordering::SeqCst) {
-      // ...
+    self.rc.fetch_sub_with(1, Ordering::SeqCst, |_, _, rc_token| {
+      self.central_token.open(|central_token| {
+        let (rc_token, central_token) = Protocol::release_shared(rc_token, central_token, shared_handle);
+        (central_token, (rc_token, ()))
+      })
     });
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::thread;
+
+  use super::*;
+
+  #[test]
+  fn readers_and_writers() {
+    fn write(x: &mut (u64, u64)) {
+      x.0 += 1;
+      x.1 += 1;
+    }
+    fn read(x: &(u64, u64)) {
+      // Stay a while, for writers to run into
+      thread::yield_now();
+      assert_eq!(x.0, x.1, "torn read");
+    }
+
+    let lock = Arc::new(RWLock::new((0, 0)));
+    let threads: Vec<_> = (0..4).map(|_| {
+      let lock = lock.clone();
+      thread::spawn(move || {
+        for _ in 0..200 {
+          lock.acquire_exclusive(write);
+          lock.acquire_shared(read);
+        }
+      })
+    }).collect();
+    for t in threads {
+      t.join().unwrap();
+    }
+    lock.acquire_shared(|x| assert_eq!(*x, (800, 800)));
+  }
+
+  // Skipping the wait for readers (taking `rc` as 0 whatever it is) must
+  // not get past the checks.
+  #[test]
+  #[should_panic(expected = "breaks the invariant")]
+  fn writer_ignoring_readers() {
+    let (cell, token) = VerifiedCell::new(0);
+    let (e, r) = (Protocol::PhysExcHandle(false), Protocol::PhysRcHandle(0));
+    let c = Protocol::CentralHandle(CentralState{ logical_exc: false, logical_rc: 0, held_value: Some(cell.id()) });
+    let m = Token::extend(token, e.op(&r).op(&c));
+    let (exc, m) = m.split(e, r.op(&c));
+    let (rc, central_token) = m.split(r, c);
+
+    // A reader gets in
+    let (rc, pending) = Protocol::acquire_shared_pending(rc);
+    let (exc, central_token, shared_handle) = Protocol::acquire_shared_finish(exc, central_token, pending);
+    assert_eq!(*cell.borrow(Protocol::borrow(&shared_handle)), 0);
+
+    // And a writer doesn't wait for it
+    let (_exc, pending) = Protocol::acquire_exc_pending(exc);
+    Protocol::acquire_exc_finish(rc, central_token, pending);
+  }
+}
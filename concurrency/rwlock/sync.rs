@@ -0,0 +1,193 @@
+// The synchronization primitives the locks are built from: loom's
+// model-checked versions when built with `--cfg loom`, std otherwise.
+
+#[cfg(loom)]
+pub use loom::sync::atomic;
+
+#[cfg(not(loom))]
+pub use std::sync::atomic;
+
+#[cfg(loom)]
+pub use loom::hint;
+
+#[cfg(not(loom))]
+pub use std::hint;
+
+#[cfg(all(loom, feature = "protocol"))]
+pub use loom::sync::Mutex;
+
+#[cfg(all(not(loom), feature = "protocol"))]
+pub use std::sync::Mutex;
+
+#[cfg(loom)]
+pub use loom::cell;
+
+#[cfg(not(loom))]
+pub mod cell {
+  // std's UnsafeCell with the closure-based API of loom's, so accesses
+  // can be tracked when model-checking.
+  pub struct UnsafeCell<T>(std::cell::UnsafeCell<T>);
+
+  impl<T> UnsafeCell<T> {
+    pub fn new(t: T) -> UnsafeCell<T> {
+      UnsafeCell(std::cell::UnsafeCell::new(t))
+    }
+
+    pub fn with<R>(&self, f: impl FnOnce(*const T) -> R) -> R {
+      f(self.0.get())
+    }
+
+    pub fn with_mut<R>(&self, f: impl FnOnce(*mut T) -> R) -> R {
+      f(self.0.get())
+    }
+
+    pub fn get(&self) -> ConstPtr<T> {
+      ConstPtr(self.0.get())
+    }
+
+    pub fn get_mut(&self) -> MutPtr<T> {
+      MutPtr(self.0.get())
+    }
+  }
+
+  // Pointers into the cell that can outlive a closure, for lock guards.
+  pub struct ConstPtr<T>(*const T);
+
+  impl<T> ConstPtr<T> {
+    pub unsafe fn deref(&self) -> &T {
+      &*self.0
+    }
+  }
+
+  pub struct MutPtr<T>(*mut T);
+
+  impl<T> MutPtr<T> {
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn deref(&self) -> &mut T {
+      &mut *self.0
+    }
+  }
+}
+
+// Parking a thread until a word changes. Lost wake-ups show up as
+// deadlocks under loom, which doesn't know about futexes, so there the
+// futex is modelled with a condvar.
+#[cfg(loom)]
+pub mod futex {
+  use std::time::Duration;
+
+  use loom::sync::atomic::{AtomicU32, Ordering};
+  use loom::sync::{Condvar, Mutex};
+
+  pub struct Parking {
+    mutex: Mutex<()>,
+    cond: Condvar,
+  }
+
+  impl Parking {
+    pub fn new() -> Parking {
+      Parking{ mutex: Mutex::new(()), cond: Condvar::new() }
+    }
+
+    // Sleeps unless `word` no longer holds `expected`, may return
+    // spuriously. Loom has no notion of time, so waits with a timeout
+    // return right away.
+    pub fn wait(&self, word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+      if timeout.is_some() {
+        return;
+      }
+      let guard = self.mutex.lock().unwrap();
+      if word.load(Ordering::SeqCst) == expected {
+        drop(self.cond.wait(guard).unwrap());
+      }
+    }
+
+    // Wakes everyone sleeping on `word`, call after changing it.
+    pub fn wake_all(&self, _word: &AtomicU32) {
+      drop(self.mutex.lock().unwrap());
+      self.cond.notify_all();
+    }
+  }
+}
+
+#[cfg(not(loom))]
+pub mod futex {
+  use std::sync::atomic::AtomicU32;
+  use std::time::Duration;
+
+  pub struct Parking;
+
+  impl Parking {
+    pub fn new() -> Parking {
+      Parking
+    }
+
+    // Sleeps unless `word` no longer holds `expected`, for at most
+    // `timeout`. May return spuriously.
+    #[cfg(target_os = "linux")]
+    pub fn wait(&self, word: &AtomicU32, expected: u32, timeout: Option<Duration>) {
+      let timeout = timeout.map(|t| libc::timespec{
+        tv_sec: t.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+        tv_nsec: t.subsec_nanos() as _,
+      });
+      let timeout = match &timeout {
+        Some(t) => t as *const libc::timespec,
+        None => std::ptr::null(),
+      };
+      // EAGAIN, EINTR and ETIMEDOUT are as good as a wake-up, callers
+      // re-check
+      unsafe {
+        libc::syscall(
+          libc::SYS_futex,
+          word as *const AtomicU32 as *const u32,
+          libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+          expected,
+          timeout,
+        );
+      }
+    }
+
+    // Wakes everyone sleeping on `word`, call after changing it.
+    #[cfg(target_os = "linux")]
+    pub fn wake_all(&self, word: &AtomicU32) {
+      unsafe {
+        libc::syscall(
+          libc::SYS_futex,
+          word as *const AtomicU32 as *const u32,
+          libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+          i32::MAX,
+        );
+      }
+    }
+
+    // No futexes, fall back to yielding.
+    #[cfg(not(target_os = "linux"))]
+    pub fn wait(&self, _word: &AtomicU32, _expected: u32, _timeout: Option<Duration>) {
+      std::thread::yield_now();
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn wake_all(&self, _word: &AtomicU32) {}
+  }
+}
+
+// A small number identifying the calling thread, for spreading threads
+// over per-thread slots.
+#[cfg(loom)]
+pub fn thread_index() -> usize {
+  // Loom needs the same index in every execution, which the ids only
+  // print as (their hash changes): ThreadId(n), numbered from 0
+  let id = format!("{:?}", loom::thread::current().id());
+  id.trim_start_matches("ThreadId(").trim_end_matches(')').parse().unwrap()
+}
+
+#[cfg(not(loom))]
+pub fn thread_index() -> usize {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  static NEXT: AtomicUsize = AtomicUsize::new(0);
+  thread_local! {
+    static INDEX: usize = NEXT.fetch_add(1, Ordering::Relaxed);
+  }
+  INDEX.with(|i| *i)
+}
@@ -0,0 +1,125 @@
+// Try, timed and upgradable acquires, with every policy.
+
+#![cfg(not(loom))]
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rwlock::{Policy, RWLock, Wait};
+
+const POLICIES: [Policy; 3] = [Policy::ReaderPreferring, Policy::WriterPreferring, Policy::PhaseFair];
+
+fn locks() -> impl Iterator<Item = RWLock<usize>> {
+  POLICIES
+    .iter()
+    .flat_map(|&p| [Wait::Spin, Wait::Block].map(move |w| RWLock::with_options(0, p, w)))
+}
+
+#[test]
+fn try_locks() {
+  for lock in locks() {
+    {
+      let _w = lock.try_write().unwrap();
+      assert!(lock.try_write().is_none());
+      assert!(lock.try_read().is_none());
+    }
+    {
+      let _r = lock.try_read().unwrap();
+      assert!(lock.try_read().is_some());
+      assert!(lock.try_write().is_none());
+    }
+    // Failed attempts leave nothing behind
+    *lock.write() += 1;
+    assert_eq!(*lock.read(), 1, "{:?}", lock.policy());
+  }
+}
+
+#[test]
+fn timeouts() {
+  const TIMEOUT: Duration = Duration::from_millis(20);
+
+  for lock in locks() {
+    let lock = Arc::new(lock);
+    let held = lock.write();
+
+    let start = Instant::now();
+    assert!(lock.read_timeout(TIMEOUT).is_none());
+    assert!(lock.write_timeout(TIMEOUT).is_none());
+    assert!(start.elapsed() >= 2 * TIMEOUT);
+
+    // Released in time
+    let other = lock.clone();
+    let t = thread::spawn(move || *other.write_timeout(Duration::from_secs(10)).unwrap() += 1);
+    thread::sleep(TIMEOUT);
+    drop(held);
+    t.join().unwrap();
+    assert_eq!(*lock.read_timeout(TIMEOUT).unwrap(), 1);
+  }
+}
+
+#[test]
+fn upgradable() {
+  for lock in locks() {
+    let lock = Arc::new(lock);
+    let upgradable = lock.upgradable_read();
+    // Plain readers get in next to it, writers don't
+    assert!(lock.try_read().is_some());
+    assert!(lock.try_write().is_none());
+
+    // The upgrade waits for the other readers
+    let reader = lock.read();
+    let other = lock.clone();
+    let t = thread::spawn(move || {
+      let mut w = upgradable_upgrade(&other);
+      *w += 1;
+    });
+    drop(upgradable);
+    thread::sleep(Duration::from_millis(5));
+    assert_eq!(*reader, 0);
+    drop(reader);
+    t.join().unwrap();
+    assert_eq!(*lock.read(), 1, "{:?}", lock.policy());
+
+    // Dropping it without upgrading lets writers in again
+    drop(lock.upgradable_read());
+    *lock.try_write().unwrap() += 1;
+  }
+}
+
+fn upgradable_upgrade(lock: &RWLock<usize>) -> rwlock::WriteGuard<'_, usize> {
+  let upgradable = lock.upgradable_read();
+  let before = *upgradable;
+  let w = upgradable.upgrade();
+  assert_eq!(*w, before, "a writer got in during the upgrade");
+  w
+}
+
+#[test]
+fn upgrades_race_writers() {
+  const THREADS: usize = 4;
+  const ITERS: usize = 1000;
+
+  for policy in POLICIES {
+    let lock = Arc::new(RWLock::with_options(0usize, policy, Wait::Block));
+    let workers: Vec<_> = (0..THREADS)
+      .map(|i| {
+        let lock = lock.clone();
+        thread::spawn(move || {
+          for _ in 0..ITERS {
+            match i % 3 {
+              0 => *upgradable_upgrade(&lock) += 1,
+              1 => *lock.write() += 1,
+              _ => assert!(*lock.read() <= THREADS * ITERS),
+            }
+          }
+        })
+      })
+      .collect();
+    for w in workers {
+      w.join().unwrap();
+    }
+    let writers = (0..THREADS).filter(|i| i % 3 != 2).count();
+    assert_eq!(*lock.read(), writers * ITERS, "{:?}", policy);
+  }
+}
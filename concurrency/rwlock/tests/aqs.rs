@@ -0,0 +1,63 @@
+// The AQS lock on real threads, tests/loom.rs covers the interleavings.
+
+#![cfg(not(loom))]
+
+#[macro_use]
+mod common;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use rwlock::AqsRWLock;
+
+#[test]
+fn counters() {
+  counters!(AqsRWLock::new([0usize; 2]), 4, 2_000, 4);
+}
+
+#[test]
+fn try_locks() {
+  let lock = AqsRWLock::new(0);
+  {
+    let _r = lock.read();
+    assert!(lock.try_read().is_some());
+    assert!(lock.try_write().is_none());
+  }
+  {
+    let _w = lock.try_write().unwrap();
+    assert!(lock.try_read().is_none());
+    assert!(lock.try_write().is_none());
+  }
+  *lock.write() += 1;
+  assert_eq!(lock.with_read(|x| *x), 1);
+}
+
+#[test]
+fn queued_readers_share() {
+  const READERS: usize = 3;
+
+  // The readers queue behind the writer and get in together after it
+  let lock = Arc::new(AqsRWLock::new(()));
+  let inside = Arc::new(AtomicUsize::new(0));
+  let queued = Arc::new(Barrier::new(READERS + 1));
+  let writer = lock.write();
+  let readers: Vec<_> = (0..READERS)
+    .map(|_| {
+      let (lock, inside, queued) = (lock.clone(), inside.clone(), queued.clone());
+      thread::spawn(move || {
+        queued.wait();
+        let _r = lock.read();
+        inside.fetch_add(1, Ordering::SeqCst);
+        while inside.load(Ordering::SeqCst) < READERS {
+          thread::yield_now();
+        }
+      })
+    })
+    .collect();
+  queued.wait();
+  drop(writer);
+  for r in readers {
+    r.join().unwrap();
+  }
+}
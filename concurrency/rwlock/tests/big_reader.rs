@@ -0,0 +1,32 @@
+// The big-reader lock on real threads, tests/loom.rs covers the
+// interleavings.
+
+#![cfg(not(loom))]
+
+#[macro_use]
+mod common;
+
+use rwlock::BigReaderRWLock;
+
+#[test]
+fn counters() {
+  // Fewer slots than threads, so some share one
+  counters!(BigReaderRWLock::with_slots([0usize; 2], 4), 6, 10_000, 8);
+}
+
+#[test]
+fn try_locks() {
+  let lock = BigReaderRWLock::new(0);
+  {
+    let _r = lock.read();
+    assert!(lock.try_read().is_some());
+    assert!(lock.try_write().is_none());
+  }
+  {
+    let _w = lock.try_write().unwrap();
+    assert!(lock.try_read().is_none());
+    assert!(lock.try_write().is_none());
+  }
+  *lock.write() += 1;
+  assert_eq!(lock.with_read(|x| *x), 1);
+}
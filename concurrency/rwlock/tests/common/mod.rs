@@ -0,0 +1,33 @@
+// Shared by the tests of the different locks. They have the same guard
+// interface but no common trait, hence a macro.
+
+/// Stress test: `threads` threads each run `iters` iterations over the
+/// `[usize; 2]` behind `lock`, bumping both counters on every `every`-th one
+/// and checking on the others that readers never see them differ.
+macro_rules! counters {
+  ($lock:expr, $threads:expr, $iters:expr, $every:expr) => {{
+    let lock = std::sync::Arc::new($lock);
+    let workers: Vec<_> = (0..$threads)
+      .map(|i| {
+        let lock = lock.clone();
+        std::thread::spawn(move || {
+          let mut writes = 0;
+          for j in 0..$iters {
+            if (i + j) % $every == 0 {
+              let mut v = lock.write();
+              v[0] += 1;
+              v[1] += 1;
+              writes += 1;
+            } else {
+              let v = lock.read();
+              assert_eq!(v[0], v[1], "torn read");
+            }
+          }
+          writes
+        })
+      })
+      .collect();
+    let writes: usize = workers.into_iter().map(|t| t.join().unwrap()).sum();
+    assert_eq!(lock.with_read(|v| *v), [writes; 2]);
+  }};
+}
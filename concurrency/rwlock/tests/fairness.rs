@@ -0,0 +1,92 @@
+// Starvation tests: one thread takes the lock the other way than a
+// stream of threads hammering it, and must get in within a bound, for
+// the policies that promise so. `cargo bench --bench fairness` shows
+// the policies that don't.
+
+#![cfg(not(loom))]
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rwlock::{Policy, RWLock, Wait};
+
+// How long the hammering threads hold the lock
+const HOLD: Duration = Duration::from_millis(1);
+const HAMMERS: usize = 4;
+const ATTEMPTS: usize = 20;
+// Way above a few HOLDs, but tight enough to catch starvation
+const BOUND: Duration = Duration::from_millis(500);
+
+// The longest the victim waited for the lock, taking it to write if
+// `victim_writes`, while the hammers take it the other way.
+fn max_wait(policy: Policy, victim_writes: bool) -> Duration {
+  let lock = Arc::new(RWLock::with_options(0usize, policy, Wait::Block));
+  let stop = Arc::new(AtomicBool::new(false));
+
+  let hammers: Vec<_> = (0..HAMMERS)
+    .map(|_| {
+      let (lock, stop) = (lock.clone(), stop.clone());
+      thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+          if victim_writes {
+            let _guard = lock.read();
+            thread::sleep(HOLD);
+          } else {
+            let mut guard = lock.write();
+            *guard += 1;
+            thread::sleep(HOLD);
+          }
+        }
+      })
+    })
+    .collect();
+
+  // Let the hammering start
+  thread::sleep(10 * HOLD);
+  let mut max = Duration::ZERO;
+  for _ in 0..ATTEMPTS {
+    let start = Instant::now();
+    if victim_writes {
+      let mut guard = lock.write();
+      max = max.max(start.elapsed());
+      *guard += 1;
+    } else {
+      let guard = lock.read();
+      max = max.max(start.elapsed());
+      assert!(*guard < usize::MAX);
+    }
+    thread::sleep(HOLD);
+  }
+
+  stop.store(true, Ordering::Relaxed);
+  for h in hammers {
+    h.join().unwrap();
+  }
+  max
+}
+
+#[test]
+fn reader_preferring_reader_not_starved() {
+  let wait = max_wait(Policy::ReaderPreferring, false);
+  assert!(wait < BOUND, "reader waited {:?}", wait);
+}
+
+#[test]
+fn writer_preferring_writer_not_starved() {
+  let wait = max_wait(Policy::WriterPreferring, true);
+  assert!(wait < BOUND, "writer waited {:?}", wait);
+}
+
+#[test]
+fn phase_fair_reader_not_starved() {
+  let wait = max_wait(Policy::PhaseFair, false);
+  assert!(wait < BOUND, "reader waited {:?}", wait);
+}
+
+#[test]
+fn phase_fair_writer_not_starved() {
+  let wait = max_wait(Policy::PhaseFair, true);
+  assert!(wait < BOUND, "writer waited {:?}", wait);
+}
@@ -0,0 +1,35 @@
+// Exercises the guards on real threads, tests/loom.rs covers the
+// interleavings.
+
+#![cfg(not(loom))]
+
+#[macro_use]
+mod common;
+
+use std::thread;
+
+use rwlock::{RWLock, Wait};
+
+#[test]
+fn spinning() {
+  counters!(RWLock::with_wait([0usize; 2], Wait::Spin), 4, 10_000, 2);
+}
+
+#[test]
+fn blocking() {
+  // Enough threads that some of them end up asleep
+  let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+  counters!(RWLock::with_wait([0usize; 2], Wait::Block), 4 * cpus, 10_000, 2);
+}
+
+#[test]
+fn readers_overlap() {
+  let lock = RWLock::new(1);
+  let a = lock.read();
+  let b = lock.read();
+  assert_eq!(*a + *b, 2);
+  drop(a);
+  drop(b);
+  *lock.write() += 1;
+  assert_eq!(lock.with_read(|x| *x), 2);
+}
@@ -0,0 +1,431 @@
+// Model-checks RWLock with loom, run with
+//
+//   RUSTFLAGS="--cfg loom" cargo test --release --test loom
+//
+// Data races on the protected value are caught by loom's UnsafeCell, so
+// weakening any of the orderings the lock relies on makes these fail.
+
+#![cfg(loom)]
+
+use loom::sync::atomic::{AtomicUsize, Ordering};
+use loom::sync::Arc;
+use loom::thread;
+
+use rwlock::{Policy, RWLock, Wait};
+
+type Options = (Policy, Wait);
+
+fn lock<T>(t: T, (policy, wait): Options) -> Arc<RWLock<T>> {
+  Arc::new(RWLock::with_options(t, policy, wait))
+}
+
+fn builder() -> loom::model::Builder {
+  let mut builder = loom::model::Builder::new();
+  // Spinning makes the full state space explode, 2 preemptions are
+  // enough to find all the bugs we know of.
+  if builder.preemption_bound.is_none() {
+    builder.preemption_bound = Some(2);
+  }
+  builder.max_branches = 10_000;
+  builder
+}
+
+// Checks `f` with every policy, with locks that spin and that sleep.
+fn model<F: Fn(Options) + Sync + Send + Copy + 'static>(f: F) {
+  let builder = builder();
+  for policy in [Policy::ReaderPreferring, Policy::WriterPreferring, Policy::PhaseFair] {
+    for wait in [Wait::Spin, Wait::Block] {
+      builder.check(move || f((policy, wait)));
+    }
+  }
+}
+
+fn increment(x: &mut usize) {
+  *x += 1;
+}
+
+#[test]
+fn writers_exclude_writers() {
+  fn check(x: &usize) {
+    assert_eq!(*x, 2);
+  }
+
+  model(|opts| {
+    let lock = lock(0usize, opts);
+    let other = lock.clone();
+    let t = thread::spawn(move || other.acquire_exclusive(increment));
+    lock.acquire_exclusive(increment);
+    t.join().unwrap();
+    lock.acquire_shared(check);
+  });
+}
+
+#[test]
+fn writers_exclude_readers() {
+  fn write(x: &mut (usize, usize)) {
+    x.0 += 1;
+    x.1 += 1;
+  }
+  fn read(x: &(usize, usize)) {
+    assert_eq!(x.0, x.1, "torn read");
+  }
+
+  model(|opts| {
+    let lock = lock((0usize, 0usize), opts);
+    let other = lock.clone();
+    let t = thread::spawn(move || other.acquire_exclusive(write));
+    lock.acquire_shared(read);
+    t.join().unwrap();
+  });
+}
+
+#[test]
+fn readers_share() {
+  // Every reader waits inside the lock until both got in, which can only
+  // terminate if readers don't exclude each other.
+  fn read(inside: &AtomicUsize) {
+    inside.fetch_add(1, Ordering::SeqCst);
+    while inside.load(Ordering::SeqCst) < 2 {
+      thread::yield_now();
+    }
+  }
+
+  model(|opts| {
+    let lock = lock(AtomicUsize::new(0), opts);
+    let other = lock.clone();
+    let t = thread::spawn(move || other.acquire_shared(read));
+    lock.acquire_shared(read);
+    t.join().unwrap();
+  });
+}
+
+#[test]
+fn readers_and_writers() {
+  fn write(x: &mut (usize, usize)) {
+    x.0 += 1;
+    x.1 += 1;
+  }
+  fn read(x: &(usize, usize)) {
+    assert_eq!(x.0, x.1, "torn read");
+  }
+  fn check(x: &(usize, usize)) {
+    assert_eq!(*x, (2, 2));
+  }
+
+  // Two threads taking the lock both ways, more threads are out of
+  // loom's reach since readers retry while a writer holds `exc`.
+  model(|opts| {
+    let lock = lock((0usize, 0usize), opts);
+    let other = lock.clone();
+    let t = thread::spawn(move || {
+      other.acquire_shared(read);
+      other.acquire_exclusive(write);
+    });
+    lock.acquire_exclusive(write);
+    lock.acquire_shared(read);
+    t.join().unwrap();
+    lock.acquire_shared(check);
+  });
+}
+
+#[test]
+fn guards() {
+  // Holds the lock across statements and captures state, which the
+  // fn-pointer API couldn't.
+  model(|opts| {
+    let lock = lock((0usize, 0usize), opts);
+    let other = lock.clone();
+    let t = thread::spawn(move || {
+      let step = 2;
+      let mut guard = other.write();
+      guard.0 += step;
+      let first = guard.0;
+      guard.1 = first;
+    });
+    let (a, b) = lock.with_read(|x| *x);
+    assert_eq!(a, b, "torn read");
+    {
+      let guard = lock.read();
+      assert_eq!(guard.0, guard.1, "torn read");
+    }
+    t.join().unwrap();
+    assert_eq!(lock.with_write(|x| { x.0 += 1; x.0 }), 3);
+  });
+}
+
+#[test]
+fn try_locks() {
+  // Failed attempts must leave the lock as they found it, or the final
+  // `write` deadlocks.
+  model(|opts| {
+    let lock = lock((0usize, 0usize), opts);
+    let other = lock.clone();
+    let t = thread::spawn(move || {
+      if let Some(mut guard) = other.try_write() {
+        guard.0 += 1;
+        guard.1 += 1;
+      }
+    });
+    if let Some(guard) = lock.try_read() {
+      assert_eq!(guard.0, guard.1, "torn read");
+    }
+    t.join().unwrap();
+    let mut guard = lock.write();
+    guard.0 += 1;
+    guard.1 += 1;
+  });
+}
+
+#[test]
+fn upgrade() {
+  model(|opts| {
+    let lock = lock(0usize, opts);
+    let other = lock.clone();
+    let t = thread::spawn(move || *other.write() += 1);
+    let upgradable = lock.upgradable_read();
+    let before = *upgradable;
+    let mut guard = upgradable.upgrade();
+    assert_eq!(*guard, before, "a writer got in during the upgrade");
+    *guard += 1;
+    drop(guard);
+    t.join().unwrap();
+    assert_eq!(*lock.read(), 2);
+  });
+}
+
+#[test]
+fn upgrade_next_to_reader() {
+  model(|opts| {
+    let lock = lock((0usize, 0usize), opts);
+    let other = lock.clone();
+    let t = thread::spawn(move || {
+      let guard = other.read();
+      assert_eq!(guard.0, guard.1, "torn read");
+    });
+    let mut guard = lock.upgradable_read().upgrade();
+    guard.0 += 1;
+    guard.1 += 1;
+    drop(guard);
+    t.join().unwrap();
+  });
+}
+
+mod big_reader {
+  use super::*;
+
+  use rwlock::BigReaderRWLock;
+
+  fn model<F: Fn(usize) + Sync + Send + Copy + 'static>(f: F) {
+    // With one slot everybody shares it, with two each thread has its own
+    let builder = super::builder();
+    for slots in [1, 2] {
+      builder.check(move || f(slots));
+    }
+  }
+
+  fn write(x: &mut (usize, usize)) {
+    x.0 += 1;
+    x.1 += 1;
+  }
+
+  fn read(x: &(usize, usize)) {
+    assert_eq!(x.0, x.1, "torn read");
+  }
+
+  #[test]
+  fn writers_exclude_readers() {
+    model(|slots| {
+      let lock = Arc::new(BigReaderRWLock::with_slots((0usize, 0usize), slots));
+      let other = lock.clone();
+      let t = thread::spawn(move || other.with_write(write));
+      lock.with_read(read);
+      t.join().unwrap();
+    });
+  }
+
+  #[test]
+  fn writers_exclude_writers() {
+    model(|slots| {
+      let lock = Arc::new(BigReaderRWLock::with_slots((0usize, 0usize), slots));
+      let other = lock.clone();
+      let t = thread::spawn(move || {
+        other.with_read(read);
+        other.with_write(write);
+      });
+      lock.with_write(write);
+      t.join().unwrap();
+      assert_eq!(*lock.read(), (2, 2));
+    });
+  }
+
+  #[test]
+  fn readers_share() {
+    model(|slots| {
+      let lock = Arc::new(BigReaderRWLock::with_slots(AtomicUsize::new(0), slots));
+      let other = lock.clone();
+      let enter = |inside: &AtomicUsize| {
+        inside.fetch_add(1, Ordering::SeqCst);
+        while inside.load(Ordering::SeqCst) < 2 {
+          thread::yield_now();
+        }
+      };
+      let t = thread::spawn(move || other.with_read(enter));
+      lock.with_read(enter);
+      t.join().unwrap();
+    });
+  }
+
+  #[test]
+  fn try_locks() {
+    model(|slots| {
+      let lock = Arc::new(BigReaderRWLock::with_slots((0usize, 0usize), slots));
+      let other = lock.clone();
+      let t = thread::spawn(move || {
+        if let Some(mut guard) = other.try_write() {
+          write(&mut guard);
+        }
+      });
+      if let Some(guard) = lock.try_read() {
+        read(&guard);
+      }
+      t.join().unwrap();
+      lock.with_write(write);
+    });
+  }
+}
+
+// The queue locks, MCS is a mutex so it has no `readers_share`.
+mod queue_locks {
+  use super::*;
+
+  use rwlock::{AqsRWLock, McsLock};
+
+  fn write(x: &mut (usize, usize)) {
+    x.0 += 1;
+    x.1 += 1;
+  }
+
+  fn read(x: &(usize, usize)) {
+    assert_eq!(x.0, x.1, "torn read");
+  }
+
+  macro_rules! exclusion {
+    ($lock:ident) => {
+      #[test]
+      fn writers_exclude_readers() {
+        builder().check(|| {
+          let lock = Arc::new($lock::new((0usize, 0usize)));
+          let other = lock.clone();
+          let t = thread::spawn(move || other.with_write(write));
+          lock.with_read(read);
+          t.join().unwrap();
+        });
+      }
+
+      #[test]
+      fn writers_exclude_writers() {
+        builder().check(|| {
+          let lock = Arc::new($lock::new((0usize, 0usize)));
+          let other = lock.clone();
+          let t = thread::spawn(move || {
+            other.with_read(read);
+            other.with_write(write);
+          });
+          lock.with_write(write);
+          t.join().unwrap();
+          assert_eq!(*lock.read(), (2, 2));
+        });
+      }
+
+      #[test]
+      fn try_locks() {
+        builder().check(|| {
+          let lock = Arc::new($lock::new((0usize, 0usize)));
+          let other = lock.clone();
+          let t = thread::spawn(move || {
+            if let Some(mut guard) = other.try_write() {
+              write(&mut guard);
+            }
+          });
+          if let Some(guard) = lock.try_read() {
+            read(&guard);
+          }
+          t.join().unwrap();
+          lock.with_write(write);
+        });
+      }
+    };
+  }
+
+  mod mcs {
+    use super::*;
+
+    exclusion!(McsLock);
+
+    #[test]
+    fn three_in_a_queue() {
+      builder().check(|| {
+        let lock = Arc::new(McsLock::new((0usize, 0usize)));
+        let ts: Vec<_> = (0..2)
+          .map(|_| {
+            let other = lock.clone();
+            thread::spawn(move || other.with_write(write))
+          })
+          .collect();
+        lock.with_write(write);
+        for t in ts {
+          t.join().unwrap();
+        }
+        assert_eq!(*lock.read(), (3, 3));
+      });
+    }
+  }
+
+  mod aqs {
+    use super::*;
+
+    exclusion!(AqsRWLock);
+
+    #[test]
+    fn readers_share() {
+      builder().check(|| {
+        let lock = Arc::new(AqsRWLock::new(AtomicUsize::new(0)));
+        let other = lock.clone();
+        let enter = |inside: &AtomicUsize| {
+          inside.fetch_add(1, Ordering::SeqCst);
+          while inside.load(Ordering::SeqCst) < 2 {
+            thread::yield_now();
+          }
+        };
+        let t = thread::spawn(move || other.with_read(enter));
+        lock.with_read(enter);
+        t.join().unwrap();
+      });
+    }
+
+    #[test]
+    fn queued_writer() {
+      // A writer and a reader queue behind a writer
+      builder().check(|| {
+        let lock = Arc::new(AqsRWLock::new((0usize, 0usize)));
+        let ts: Vec<_> = (0..2)
+          .map(|i| {
+            let other = lock.clone();
+            thread::spawn(move || {
+              if i == 0 {
+                other.with_write(write);
+              } else {
+                other.with_read(read);
+              }
+            })
+          })
+          .collect();
+        lock.with_write(write);
+        for t in ts {
+          t.join().unwrap();
+        }
+        assert_eq!(*lock.read(), (2, 2));
+      });
+    }
+  }
+}
@@ -0,0 +1,40 @@
+// The MCS lock on real threads, tests/loom.rs covers the interleavings.
+
+#![cfg(not(loom))]
+
+#[macro_use]
+mod common;
+
+use rwlock::McsLock;
+
+#[test]
+fn counters() {
+  counters!(McsLock::new([0usize; 2]), 4, 2_000, 2);
+}
+
+#[test]
+fn try_locks() {
+  let lock = McsLock::new(0);
+  {
+    // Readers exclude each other too
+    let _r = lock.read();
+    assert!(lock.try_read().is_none());
+    assert!(lock.try_write().is_none());
+  }
+  {
+    let _w = lock.try_write().unwrap();
+    assert!(lock.try_read().is_none());
+  }
+  *lock.write() += 1;
+  assert_eq!(lock.with_read(|x| *x), 1);
+}
+
+#[test]
+fn nested() {
+  // A thread holding several locks has a node in each queue
+  let locks: Vec<_> = (0..10).map(McsLock::new).collect();
+  let guards: Vec<_> = locks.iter().map(|l| l.write()).collect();
+  assert_eq!(guards.iter().map(|g| **g).sum::<i32>(), 45);
+  drop(guards);
+  assert!(locks.iter().all(|l| l.try_write().is_some()));
+}
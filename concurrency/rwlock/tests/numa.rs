@@ -0,0 +1,43 @@
+// Topology parsing and the NUMA lock on real threads.
+
+#![cfg(not(loom))]
+
+#[macro_use]
+mod common;
+
+use rwlock::numa::{cpus, Topology};
+use rwlock::NumaRWLock;
+
+#[test]
+fn cpulists() {
+  let topology = Topology::from_cpulists(&["0-3,8-11\n", "4-7,12-15\n", ""]).unwrap();
+  assert_eq!(topology.nodes(), 3);
+  assert_eq!(topology.node_of(2), 0);
+  assert_eq!(topology.node_of(9), 0);
+  assert_eq!(topology.node_of(4), 1);
+  assert_eq!(topology.node_of(15), 1);
+  // Unknown CPUs fall back to node 0
+  assert_eq!(topology.node_of(99), 0);
+
+  assert_eq!(Topology::from_cpulists(&["0,2,5"]).unwrap().nodes(), 1);
+  assert!(Topology::from_cpulists(&["0-x"]).is_err());
+  assert_eq!(Topology::from_cpulists(&[]).unwrap(), Topology::from_cpulists(&[""]).unwrap());
+}
+
+#[test]
+fn detect() {
+  let topology = Topology::detect();
+  assert!(topology.nodes() >= 1);
+  assert!(topology.current_node() < topology.nodes());
+  assert!(cpus() >= 1);
+}
+
+#[test]
+fn counters() {
+  // Pretend every other CPU is on another node, so both slots get used
+  let evens: Vec<String> = (0..cpus()).step_by(2).map(|c| c.to_string()).collect();
+  let odds: Vec<String> = (1..cpus()).step_by(2).map(|c| c.to_string()).collect();
+  let topology = Topology::from_cpulists(&[&evens.join(","), &odds.join(",")]).unwrap();
+
+  counters!(NumaRWLock::with_topology([0usize; 2], topology), 4, 10_000, 4);
+}
@@ -0,0 +1,135 @@
+// The RWLock.dfy state machine, and with `--features protocol` the lock
+// checked against it under stress:
+//
+//   cargo test --release --features protocol --test protocol
+
+#![cfg(not(loom))]
+
+use rwlock::protocol::{Illegal, State, Step};
+
+fn run(steps: &[Step]) -> Result<State, Illegal> {
+  let mut s = State::init();
+  for &step in steps {
+    s = s.next(step)?;
+    assert!(s.invariant(), "{:?} breaks the invariant: {:?}", step, s);
+  }
+  Ok(s)
+}
+
+#[test]
+fn legal_runs() {
+  use Step::*;
+
+  assert!(State::init().invariant());
+  // A writer, readers, a reader backing off a writer, an upgrade
+  let runs: [&[Step]; 4] = [
+    &[AcquireExcPending, AcquireExcFinish{ readers: 0 }, ReleaseExc],
+    &[AcquireSharedPending, AcquireSharedPending, AcquireSharedFinish, AcquireSharedFinish,
+      ReleaseShared, ReleaseShared],
+    &[AcquireExcPending, AcquireSharedPending, AcquireSharedAbort, AcquireExcFinish{ readers: 0 },
+      ReleaseExc],
+    &[AcquireSharedPending, AcquireSharedFinish, AcquireExcPending, AcquireExcFinish{ readers: 1 },
+      AcquireSharedAbort, ReleaseExc],
+  ];
+  for steps in runs.iter() {
+    assert_eq!(run(steps), Ok(State::init()), "{:?}", steps);
+  }
+}
+
+#[test]
+fn illegal_steps() {
+  use Step::*;
+
+  let illegal = |steps: &[Step], why: &'static str| {
+    assert_eq!(run(steps), Err(Illegal(why)), "{:?}", steps);
+  };
+  illegal(&[AcquireExcPending, AcquireExcPending], "exc is already set");
+  illegal(&[AcquireExcFinish{ readers: 0 }], "no writer is pending");
+  // A writer that doesn't wait for readers
+  illegal(&[AcquireSharedPending, AcquireSharedFinish, AcquireExcPending,
+    AcquireExcFinish{ readers: 0 }], "rc isn't what the writer saw");
+  // A reader that doesn't look at exc
+  illegal(&[AcquireExcPending, AcquireSharedPending, AcquireSharedFinish],
+    "exc isn't what the reader saw");
+  illegal(&[AcquireSharedFinish], "no reader is pending");
+  illegal(&[ReleaseShared], "no reader is in");
+  illegal(&[AcquireExcPending, ReleaseExc], "no writer is in");
+}
+
+#[cfg(feature = "protocol")]
+mod checked {
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
+
+  use rwlock::protocol::State;
+  use rwlock::{Policy, RWLock, Wait};
+
+  // Every kind of acquire, from a few threads at once. The monitor
+  // panics on the first bad step.
+  fn stress(policy: Policy, wait: Wait) {
+    const THREADS: usize = 4;
+    const ITERS: usize = 20_000;
+
+    let lock = Arc::new(RWLock::with_options(0usize, policy, wait));
+    let workers: Vec<_> = (0..THREADS)
+      .map(|i| {
+        let lock = lock.clone();
+        thread::spawn(move || {
+          for j in 0..ITERS {
+            match (i + j) % 6 {
+              0 => *lock.write() += 1,
+              1 => drop(lock.read()),
+              2 => {
+                if let Some(mut w) = lock.try_write() {
+                  *w += 1;
+                }
+                drop(lock.try_read());
+              }
+              3 => {
+                if let Some(mut w) = lock.write_timeout(Duration::from_micros(50)) {
+                  *w += 1;
+                }
+                drop(lock.read_timeout(Duration::from_micros(50)));
+              }
+              4 => *lock.upgradable_read().upgrade() += 1,
+              _ => drop(lock.upgradable_read()),
+            }
+          }
+        })
+      })
+      .collect();
+    for t in workers {
+      t.join().unwrap();
+    }
+    assert_eq!(lock.protocol(), State::init(), "{:?} {:?}", policy, wait);
+  }
+
+  #[test]
+  fn reader_preferring() {
+    stress(Policy::ReaderPreferring, Wait::Spin);
+    stress(Policy::ReaderPreferring, Wait::Block);
+  }
+
+  #[test]
+  fn writer_preferring() {
+    stress(Policy::WriterPreferring, Wait::Spin);
+    stress(Policy::WriterPreferring, Wait::Block);
+  }
+
+  #[test]
+  fn steps_are_reported() {
+    let lock = RWLock::new(0);
+    {
+      let _r = lock.read();
+      let s = lock.protocol();
+      assert_eq!((s.phys_rc, s.logical_rc, s.shared_taken_handles), (1, 1, 1));
+    }
+    {
+      let _w = lock.write();
+      let s = lock.protocol();
+      assert!(s.phys_exc && s.logical_exc && s.exc_taken_handle && !s.held_value);
+    }
+    assert_eq!(lock.protocol(), State::init());
+  }
+}
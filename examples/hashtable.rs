@@ -1,38 +1,237 @@
 // compile with `rustc --edition=2018 --test hashtable.rs`
 
-use std::hash::{Hash, Hasher};
-use std::collections::hash_map::DefaultHasher;
+use std::borrow::Borrow;
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
+use std::collections::hash_map::{DefaultHasher, RandomState};
 
-pub enum HashMapItem<V> {
+// hashers for the maps, any `BuildHasher` works
+
+// SipHash with fixed keys, what the maps always used
+pub type DefaultState = BuildHasherDefault<DefaultHasher>;
+
+// multiply-rotate (as in FxHash), fast for integer keys but easy to attack
+#[derive(Clone, Copy, Default)]
+pub struct IntHasher {
+    hash: u64,
+}
+
+impl Hasher for IntHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.write_u64(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.hash = (self.hash.rotate_left(5) ^ i).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+pub type BuildIntHasher = BuildHasherDefault<IntHasher>;
+
+// `hash64` of LinearMutableMapBase.i.dfy (Thomas Wang's 64-bit mix)
+pub fn dafny_hash64(k: u64) -> u64 {
+    let k0 = (!k).wrapping_add(k << 21);
+    let k1 = k0 ^ (k0 >> 24);
+    let k2 = k1.wrapping_add(k1 << 3).wrapping_add(k1 << 8);
+    let k3 = k2 ^ (k2 >> 14);
+    let k4 = k3.wrapping_add(k3 << 2).wrapping_add(k3 << 4);
+    let k5 = k4 ^ (k4 >> 28);
+    k5.wrapping_add(k5 << 31)
+}
+
+// hashes a u64 key to `hash64` of it, so a map of u64 keys puts every key in the same slot as
+// the dafny one does; the dafny maps have no other keys, anything else is folded word by word
+#[derive(Clone, Copy, Default)]
+pub struct DafnyHasher {
+    state: u64,
+    words: u64,
+}
+
+impl Hasher for DafnyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.write_u64(u64::from_le_bytes(word));
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.state = if self.words == 0 { i } else { dafny_hash64(self.state) ^ i };
+        self.words += 1;
+    }
+
+    fn finish(&self) -> u64 {
+        dafny_hash64(self.state)
+    }
+}
+
+pub type BuildDafnyHasher = BuildHasherDefault<DafnyHasher>;
+
+// SipHash with the seed hashed in first, against inputs crafted to collide (HashDoS); the same
+// seed places keys the same way in every run, `random` takes a fresh one from `RandomState`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SeededState {
+    seed: u64,
+}
+
+impl SeededState {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    pub fn random() -> Self {
+        Self::new(RandomState::new().build_hasher().finish())
+    }
+}
+
+impl BuildHasher for SeededState {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        let mut hasher = DefaultState::default().build_hasher();
+        hasher.write_u64(self.seed);
+        hasher
+    }
+}
+
+#[cfg(test)]
+mod hasher_test {
+    use super::*;
+
+    fn hash<S: BuildHasher, T: Hash + ?Sized>(s: &S, t: &T) -> u64 {
+        s.hash_one(t)
+    }
+
+    #[test]
+    fn dafny_slots() {
+        // worked out by hand from hash64 in LinearMutableMapBase.i.dfy
+        let expected = [
+            (0u64, 0x77cfa1eef01bca90u64, 656usize),
+            (1, 0x5bca7c69b794f8ce, 206),
+            (42, 0x0f3db82f1e7b6f7a, 890),
+            (u64::MAX, 0x1f89206e3f8ec794, 916),
+        ];
+        let mut hm = FixedSizeHashMap::with_hasher(1024, BuildDafnyHasher::default());
+        for &(k, h, slot) in expected.iter() {
+            assert_eq!(dafny_hash64(k), h);
+            assert_eq!(hash(&BuildDafnyHasher::default(), &k), h);
+            hm.insert(k, k);
+            match &hm.storage[slot] {
+                HashMapItem::Entry { key, .. } => assert_eq!(*key, k),
+                _ => panic!("{} is not in slot {}", k, slot),
+            }
+        }
+    }
+
+    fn layout<S: BuildHasher>(hash_builder: S) -> Vec<Option<u64>> {
+        let mut hm = FixedSizeHashMap::with_hasher(1024, hash_builder);
+        for k in 0..500u64 {
+            hm.insert(k, k);
+        }
+        for k in 0..500u64 {
+            assert_eq!(hm.get(&k), Some(&k));
+        }
+        hm.storage
+            .iter()
+            .map(|item| match item {
+                HashMapItem::Entry { key, .. } => Some(*key),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seeded() {
+        assert_eq!(layout(SeededState::new(1)), layout(SeededState::new(1)));
+        // other seeds place the keys elsewhere but find all of them
+        layout(SeededState::new(2));
+        layout(SeededState::random());
+        assert_ne!(SeededState::random(), SeededState::random());
+    }
+
+    fn churn<S: BuildHasher>(hash_builder: S) {
+        let mut rhm = ResizingHashMap::with_hasher(16, hash_builder);
+        let mut model = std::collections::HashMap::new();
+        for i in 0..10_000u64 {
+            rhm.insert(i, i);
+            model.insert(i, i);
+            if i % 3 == 0 {
+                assert_eq!(rhm.remove_and_get(&(i / 2)), model.remove(&(i / 2)));
+            }
+        }
+        for i in 0..10_000u64 {
+            assert_eq!(rhm.get(&i), model.get(&i));
+        }
+    }
+
+    #[test]
+    fn every_hasher() {
+        churn(DefaultState::default());
+        churn(BuildIntHasher::default());
+        churn(BuildDafnyHasher::default());
+        churn(SeededState::random());
+    }
+}
+
+pub enum HashMapItem<K, V> {
     Empty,
-    Tombstone { key: u64 },
-    Entry { key: u64, value: V },
+    Tombstone { key: K },
+    Entry { key: K, value: V },
 }
 
-impl<V: Clone> Clone for HashMapItem<V> {
+impl<K: Clone, V: Clone> Clone for HashMapItem<K, V> {
     fn clone(&self) -> Self {
         match self {
             HashMapItem::Empty => HashMapItem::Empty,
-            HashMapItem::Tombstone { key } => HashMapItem::Tombstone { key: *key },
-            HashMapItem::Entry { key, ref value } => HashMapItem::Entry { key: *key, value: value.clone() },
+            HashMapItem::Tombstone { key } => HashMapItem::Tombstone { key: key.clone() },
+            HashMapItem::Entry { key, ref value } => HashMapItem::Entry { key: key.clone(), value: value.clone() },
         }
     }
 }
 
-pub struct FixedSizeHashMap<V> {
-    storage: Vec<HashMapItem<V>>,
+// lookups take any borrowed form `Q` of the key (`&[u8]` for `Vec<u8>`),
+// `Borrow` guarantees it hashes and compares like the key, so it probes the same slots
+//
+// keys go to slot `hash % len`, as in the dafny implementation; `S` builds the hasher, which
+// defaults to the fixed-key SipHash of `DefaultHasher`, see the hashers above for the others
+pub struct FixedSizeHashMap<K, V, S = DefaultState> {
+    storage: Vec<HashMapItem<K, V>>,
+    // slots in use, entries and tombstones (as in the dafny implementation)
     count: usize,
+    // the tombstones among them, `count - tombstones` are live
+    tombstones: usize,
+    hash_builder: S,
 }
 
-impl<V> FixedSizeHashMap<V> {
+impl<K: Hash + Eq, V> FixedSizeHashMap<K, V> {
     pub fn new(size: usize) -> Self {
+        Self::with_hasher(size, DefaultState::default())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> FixedSizeHashMap<K, V, S> {
+    pub fn with_hasher(size: usize, hash_builder: S) -> Self {
         Self {
             storage: (0..size).map(|_| HashMapItem::Empty).collect(),
             count: 0,
+            tombstones: 0,
+            hash_builder,
         }
     }
 
-    pub fn from_storage(storage: Vec<HashMapItem<V>>, count: usize) {
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
+    pub fn from_storage(storage: Vec<HashMapItem<K, V>>, count: usize) {
         if cfg!(debug_assertions) {
             assert_eq!(
                 storage.iter().filter(|s| match s {
@@ -43,10 +242,8 @@ impl<V> FixedSizeHashMap<V> {
         }
     }
 
-    fn slot_for_key(&self, key: u64) -> usize {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let h = hasher.finish();
+    fn slot_for_key<Q: ?Sized + Hash>(&self, key: &Q) -> usize {
+        let h = self.hash_builder.hash_one(key);
         (h as usize) % self.storage.len()
     }
 
@@ -59,36 +256,47 @@ impl<V> FixedSizeHashMap<V> {
         }
     }
 
-    fn probe(&self, key: u64) -> usize {
+    fn probe<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> usize where K: Borrow<Q> {
         let mut slot_idx = self.slot_for_key(key);
         loop {
             match &self.storage[slot_idx] {
                 HashMapItem::Empty => return slot_idx,
-                HashMapItem::Tombstone { key: t_key } if key == *t_key => return slot_idx,
-                HashMapItem::Entry { key: t_key, value: _ } if key == *t_key => return slot_idx,
+                HashMapItem::Tombstone { key: t_key } if key == t_key.borrow() => return slot_idx,
+                HashMapItem::Entry { key: t_key, value: _ } if key == t_key.borrow() => return slot_idx,
                 _ => (),
             }
             slot_idx = self.slot_successor(slot_idx);
         }
     }
 
-    pub fn insert(&mut self, key: u64, value: V) -> Option<V> {
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        // lookups of absent keys stop at an empty slot, don't let tombstones take the last one
+        if self.count + 1 >= self.storage.len() && self.tombstones > 0 {
+            self.rehash();
+        }
         if self.count == self.storage.len() {
             panic!("hashtable is full");
         }
-        let slot_idx = self.probe(key);
+        let slot_idx = self.probe(&key);
         if let HashMapItem::Entry { key: _, value: ref mut old_value } = &mut self.storage[slot_idx] {
             /* return */ Some(std::mem::replace(old_value, value))
         } else {
-            if let HashMapItem::Empty = &self.storage[slot_idx] {
-                self.count += 1;
-            }
-            self.storage[slot_idx] = HashMapItem::Entry { key, value };
+            self.fill_slot(slot_idx, key, value);
             None
         }
     }
 
-    pub fn get<'a>(&'a self, key: u64) -> Option<&'a V> { // note return type
+    // puts a new entry in the empty slot or tombstone `probe` found for `key`
+    fn fill_slot(&mut self, slot_idx: usize, key: K, value: V) {
+        match &self.storage[slot_idx] {
+            HashMapItem::Empty => self.count += 1,
+            HashMapItem::Tombstone { .. } => self.tombstones -= 1,
+            HashMapItem::Entry { .. } => panic!("slot is an Entry"),
+        }
+        self.storage[slot_idx] = HashMapItem::Entry { key, value };
+    }
+
+    pub fn get<'a, Q: ?Sized + Hash + Eq>(&'a self, key: &Q) -> Option<&'a V> where K: Borrow<Q> { // note return type
         let slot_idx = self.probe(key);
         match &self.storage[slot_idx] {
             HashMapItem::Entry { key: _, ref value } => Some(value),
@@ -96,22 +304,48 @@ impl<V> FixedSizeHashMap<V> {
         }
     }
 
-    pub fn remove(&mut self, key: u64) -> Option<V> {
+    pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<V> where K: Borrow<Q> {
         let slot_idx = self.probe(key);
         match &self.storage[slot_idx] {
-            HashMapItem::Entry { .. } => {
-                // non-lexical-lifetime required
-                let entry = std::mem::replace(&mut self.storage[slot_idx], HashMapItem::Tombstone { key: key });
-                if let HashMapItem::Entry { key: _, /* move */ value } = entry {
-                    Some(value)
-                } else {
-                    unreachable!()
-                }
-            },
+            HashMapItem::Entry { .. } => Some(self.empty_slot(slot_idx)),
             _ => None,
         }
     }
 
+    // turns the entry in `slot_idx` into a tombstone
+    fn empty_slot(&mut self, slot_idx: usize) -> V {
+        // non-lexical-lifetime required
+        // the tombstone keeps the stored key, we only have a borrowed one
+        let entry = std::mem::replace(&mut self.storage[slot_idx], HashMapItem::Empty);
+        if let HashMapItem::Entry { /* move */ key, value } = entry {
+            self.storage[slot_idx] = HashMapItem::Tombstone { key };
+            self.tombstones += 1;
+            value
+        } else {
+            panic!("slot is not an Entry");
+        }
+    }
+
+    // clears all tombstones without growing: only the live entries are moved out,
+    // the storage is reused and they're reinserted where probing now puts them
+    pub fn rehash(&mut self) {
+        let mut live = Vec::with_capacity(self.count - self.tombstones);
+        for slot in self.storage.iter_mut() {
+            if let HashMapItem::Entry { .. } = slot {
+                live.push(std::mem::replace(slot, HashMapItem::Empty));
+            } else {
+                *slot = HashMapItem::Empty;
+            }
+        }
+        self.count = 0;
+        self.tombstones = 0;
+        for e in live.into_iter() {
+            if let HashMapItem::Entry { key, value } = e {
+                self.insert(key, value);
+            }
+        }
+    }
+
     pub fn update_by_slot(&mut self, slot_idx: usize, value: V) {
         match &mut self.storage[slot_idx] {
             HashMapItem::Entry { key: _, value: ref mut slot_value } => {
@@ -124,11 +358,13 @@ impl<V> FixedSizeHashMap<V> {
     }
 }
 
-impl<V: Clone> Clone for FixedSizeHashMap<V> {
+impl<K: Clone, V: Clone, S: Clone> Clone for FixedSizeHashMap<K, V, S> {
     fn clone(&self) -> Self {
         Self {
             storage: self.storage.clone(),
             count: self.count,
+            tombstones: self.tombstones,
+            hash_builder: self.hash_builder.clone(),
         }
     }
 }
@@ -144,7 +380,7 @@ mod fixed_size_hash_map_test {
             hm.insert(i, i);
         }
         for i in 0..128 {
-            let v = hm.get(i);
+            let v = hm.get(&i);
             if i % 3 == 0 {
                 assert_eq!(v, Some(&i));
             } else {
@@ -152,10 +388,10 @@ mod fixed_size_hash_map_test {
             }
         }
         for i in (0..128).filter(|x| x % 3 == 0 && x % 2 == 0) {
-            assert_eq!(hm.remove(i), Some(i));
+            assert_eq!(hm.remove(&i), Some(i));
         }
         for i in 0..128 {
-            let v = hm.get(i);
+            let v = hm.get(&i);
             if i % 3 == 0 && i % 2 != 0 {
                 assert_eq!(v, Some(&i));
             } else {
@@ -166,22 +402,97 @@ mod fixed_size_hash_map_test {
             hm.insert(i, i);
         }
     }
+
+    #[test]
+    fn churn() {
+        // far more keys than slots come and go, never more than 4 at once
+        let mut hm = FixedSizeHashMap::new(16);
+        for i in 0..1000u64 {
+            assert_eq!(hm.insert(i, i), None);
+            if i >= 3 {
+                assert_eq!(hm.remove(&(i - 3)), Some(i - 3));
+            }
+            assert_eq!(hm.get(&(i + 1)), None);
+            assert!(hm.count < hm.storage.len());
+        }
+        assert_eq!(hm.count - hm.tombstones, 3);
+        for i in 997..1000 {
+            assert_eq!(hm.get(&i), Some(&i));
+        }
+        // a removed key can come back into its tombstone
+        assert_eq!(hm.remove(&998), Some(998));
+        let tombstones = hm.tombstones;
+        assert_eq!(hm.insert(998, 0), None);
+        assert_eq!(hm.tombstones, tombstones - 1);
+
+        hm.rehash();
+        assert_eq!((hm.count, hm.tombstones), (3, 0));
+        for i in 997..1000 {
+            assert_eq!(hm.get(&i), Some(if i == 998 { &0 } else { &i }));
+        }
+    }
+
+    #[test]
+    fn byte_string_keys() {
+        // keys as in the Dafny spec, looked up by slice
+        let key = |i: usize| format!("key-{}", i).into_bytes();
+        let mut hm: FixedSizeHashMap<Vec<u8>, usize> = FixedSizeHashMap::new(256);
+        for i in 0..128 {
+            assert_eq!(hm.insert(key(i), i), None);
+        }
+        assert_eq!(hm.insert(key(7), 700), Some(7));
+        for i in 0..128 {
+            let k: &[u8] = &key(i);
+            assert_eq!(hm.get(k), Some(if i == 7 { &700 } else { &i }));
+        }
+        assert_eq!(hm.get(&b"key-128"[..]), None);
+        assert_eq!(hm.get(&vec![b'x'; 1024][..]), None);
+
+        // a removed key leaves a tombstone that probing continues past
+        for i in (0..128).filter(|x| x % 2 == 0) {
+            assert_eq!(hm.remove(&key(i)[..]), Some(i));
+        }
+        for i in 0..128 {
+            assert_eq!(hm.get(&key(i)[..]).is_some(), i % 2 != 0);
+        }
+        assert_eq!(hm.insert(key(2), 2), None);
+        assert_eq!(hm.get(&key(2)[..]), Some(&2));
+    }
 }
 
-pub struct ResizingHashMap<V> {
-    underlying: FixedSizeHashMap<V>,
+pub struct ResizingHashMap<K, V, S = DefaultState> {
+    underlying: FixedSizeHashMap<K, V, S>,
     count: usize,
 }
 
-impl<V> ResizingHashMap<V> {
+impl<K: Hash + Eq, V> ResizingHashMap<K, V> {
     pub fn new(size: usize) -> Self {
+        Self::with_hasher(size, DefaultState::default())
+    }
+
+    // a table that takes `capacity` entries before it has to grow
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_capacity_and_hasher(capacity, DefaultState::default())
+    }
+}
+
+impl<K: Hash + Eq, V, S: BuildHasher> ResizingHashMap<K, V, S> {
+    pub fn with_hasher(size: usize, hash_builder: S) -> Self {
         Self {
-            underlying: FixedSizeHashMap::new(size),
+            underlying: FixedSizeHashMap::with_hasher(size, hash_builder),
             count: 0,
         }
     }
 
-    pub fn from_underlying(underlying: FixedSizeHashMap<V>, count: usize) -> Self {
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> Self {
+        Self::with_hasher(Self::size_for(capacity), hash_builder)
+    }
+
+    pub fn hasher(&self) -> &S {
+        self.underlying.hasher()
+    }
+
+    pub fn from_underlying(underlying: FixedSizeHashMap<K, V, S>, count: usize) -> Self {
         if cfg!(debug_assertions) {
             assert_eq!(
                 underlying.storage.iter().filter(|s| if let HashMapItem::Entry { .. } = s { true } else { false }).count(),
@@ -193,11 +504,27 @@ impl<V> ResizingHashMap<V> {
         }
     }
 
+    // the table grows once half its slots are used, so `count` entries need twice the slots
+    fn size_for(count: usize) -> usize {
+        2 * std::cmp::max(count, 1)
+    }
+
+    // the table size after growing or shrinking, a quarter full
+    fn realloc_size(count: usize) -> usize {
+        (128 + count) * 4
+    }
+
     fn realloc(&mut self) {
-        let new_size = (128 + self.count) * 4;
-        // take apart the old underlying
-        let FixedSizeHashMap { storage: old_storage, count: _ } =
-            std::mem::replace(&mut self.underlying, FixedSizeHashMap::new(new_size));
+        self.realloc_to(Self::realloc_size(self.count));
+    }
+
+    fn realloc_to(&mut self, new_size: usize) {
+        debug_assert!(Self::size_for(self.count) <= new_size);
+        // swap in fresh storage, the underlying keeps its hasher
+        let old_storage = std::mem::replace(
+            &mut self.underlying.storage, (0..new_size).map(|_| HashMapItem::Empty).collect());
+        self.underlying.count = 0;
+        self.underlying.tombstones = 0;
         for e in old_storage.into_iter() {
             if let HashMapItem::Entry { key, value } = e {
                 self.underlying.insert(key, value);
@@ -205,10 +532,31 @@ impl<V> ResizingHashMap<V> {
         }
     }
 
-    pub fn insert_and_get_old(&mut self, key: u64, value: V) -> Option<V> {
+    // makes sure an insert leaves the table at most half full, whether or not the key is new
+    fn make_room(&mut self) {
         if self.underlying.storage.len() / 2 <= self.underlying.count {
+            if self.underlying.tombstones >= self.count {
+                // mostly tombstones, clearing them frees at least half the used slots
+                self.underlying.rehash();
+            } else {
+                self.realloc();
+            }
+        }
+    }
+
+    // accounts for an entry gone from the underlying
+    fn removed(&mut self) {
+        self.count -= 1;
+        // shrink once that halves the table at least, which leaves it a quarter full
+        // like growing does: either takes a number of operations proportional to the
+        // size of the table to undo
+        if 2 * Self::realloc_size(self.count) <= self.underlying.storage.len() {
             self.realloc();
         }
+    }
+
+    pub fn insert_and_get_old(&mut self, key: K, value: V) -> Option<V> {
+        self.make_room();
 
         let replaced = self.underlying.insert(key, value);
         if replaced.is_none() {
@@ -217,30 +565,58 @@ impl<V> ResizingHashMap<V> {
         replaced
     }
 
-    pub fn insert(&mut self, key: u64, value: V) {
+    pub fn insert(&mut self, key: K, value: V) {
         let _ = self.insert_and_get_old(key, value);
         // drop the replaced value, if any
     }
 
-    pub fn remove_and_get(&mut self, key: u64) -> Option<V> {
+    pub fn remove_and_get<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<V> where K: Borrow<Q> {
         let removed = self.underlying.remove(key);
         if removed.is_some() {
-            self.count -= 1;
+            self.removed();
         }
         removed
     }
 
-    pub fn remove(&mut self, key: u64) {
+    // makes room for `additional` more entries without growing
+    pub fn reserve(&mut self, additional: usize) {
+        let size = Self::size_for(self.count + additional);
+        if size > self.underlying.storage.len() {
+            self.realloc_to(size);
+        }
+    }
+
+    // the smallest table that holds the current entries, the next insert grows it again
+    pub fn shrink_to_fit(&mut self) {
+        let size = Self::size_for(self.count);
+        if size < self.underlying.storage.len() {
+            self.realloc_to(size);
+        }
+    }
+
+    pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) where K: Borrow<Q> {
         let _ = self.remove_and_get(key);
         // drop removed value, if any
     }
 
-    pub fn get<'a>(&'a self, key: u64) -> Option<&'a V> {
+    pub fn get<'a, Q: ?Sized + Hash + Eq>(&'a self, key: &Q) -> Option<&'a V> where K: Borrow<Q> {
         self.underlying.get(key)
     }
 
+    // read-modify-write with a single probe; like `insert_and_get_old` this makes room first,
+    // so the entry can be filled without growing the table under it
+    pub fn entry<'a>(&'a mut self, key: K) -> Entry<'a, K, V, S> {
+        self.make_room();
+        let slot_idx = self.underlying.probe(&key);
+        if let HashMapItem::Entry { .. } = &self.underlying.storage[slot_idx] {
+            Entry::Occupied(OccupiedEntry { map: self, key, slot_idx })
+        } else {
+            Entry::Vacant(VacantEntry { map: self, key, slot_idx })
+        }
+    }
+
     // idiomatic rust iterator
-    pub fn iter<'a>(&'a self) -> HashMapIter<'a, V> {
+    pub fn iter<'a>(&'a self) -> HashMapIter<'a, K, V, S> {
         HashMapIter {
             map: self,
             slot_idx: 0,
@@ -253,7 +629,7 @@ impl<V> ResizingHashMap<V> {
     // if instead we’re willing to give up the standard Iterator trait,
     // the type system is sufficient, and this is necessary for ergonomics,
     // see safe_iter_mut
-    pub fn iter_mut<'a>(&'a mut self) -> HashMapIterMut<'a, V> {
+    pub fn iter_mut<'a>(&'a mut self) -> HashMapIterMut<'a, K, V, S> {
         HashMapIterMut {
             map: self,
             slot_idx: 0,
@@ -262,7 +638,7 @@ impl<V> ResizingHashMap<V> {
 
     // an unsafe-free, somewhat idiomatic rust iterator with mutable references
     // it can only return a single mutable reference at a time
-    pub fn safe_iter_mut<'a>(&'a mut self) -> HashMapIterOneMut<'a, V> {
+    pub fn safe_iter_mut<'a>(&'a mut self) -> HashMapIterOneMut<'a, K, V, S> {
         HashMapIterOneMut {
             map: self,
             slot_idx: 0,
@@ -272,34 +648,24 @@ impl<V> ResizingHashMap<V> {
     // one can write an iterator without having to define a separate type,
     // just by relying on combinators over the existing iterators (in this case for Vec)
     // no unsafe here, because the unsafe is hidden away in the underlying iterator for Vec
-    pub fn fancy_iter_mut<'a>(&'a mut self) -> impl Iterator<Item=(u64, &'a mut V)> {
+    pub fn fancy_iter_mut<'a>(&'a mut self) -> impl Iterator<Item=(&'a K, &'a mut V)> {
         self.underlying.storage.iter_mut().filter_map(|e| {
             if let HashMapItem::Entry { key, ref mut value } = e {
-                Some((*key, value))
+                Some((&*key, value))
             } else {
                 None
             }
         })
     }
 
-    pub fn max_key(&self) -> u64 {
-        let mut m = 0;
-        for (k, _) in self.iter() {
-            if k > m {
-                m = k;
-            }
-        }
-        m
-    }
-
     // veribetrfs-style iterator (no reference to the map in the iterator object)
-    pub fn veribetrfs_iter_start<'a>(&'a self) -> VeribetrfsHashMapIter<'a, V> {
+    pub fn veribetrfs_iter_start<'a>(&'a self) -> VeribetrfsHashMapIter<'a, K, V> {
         let mut slot_idx = 0;
         while slot_idx < self.underlying.storage.len() {
             if let HashMapItem::Entry { key, ref value } = &self.underlying.storage[slot_idx] {
                 return VeribetrfsHashMapIter {
                     slot_idx,
-                    next: Some((*key, value)),
+                    next: Some((key, value)),
                 };
             }
             slot_idx += 1;
@@ -311,14 +677,14 @@ impl<V> ResizingHashMap<V> {
     }
 
     // veribetrfs-style iterator (no reference to the map in the iterator object)
-    pub fn veribetrfs_iter_inc<'a, 'b>(&'a self, it: VeribetrfsHashMapIter<'b, V>) -> VeribetrfsHashMapIter<'a, V> {
+    pub fn veribetrfs_iter_inc<'a, 'b>(&'a self, it: VeribetrfsHashMapIter<'b, K, V>) -> VeribetrfsHashMapIter<'a, K, V> {
         let VeribetrfsHashMapIter { slot_idx, next: _ } = it;
         let mut slot_idx = slot_idx + 1;
         while slot_idx < self.underlying.storage.len() {
             if let HashMapItem::Entry { key, ref value } = &self.underlying.storage[slot_idx] {
                 return VeribetrfsHashMapIter {
                     slot_idx,
-                    next: Some((*key, value)),
+                    next: Some((key, value)),
                 };
             }
             slot_idx += 1;
@@ -330,7 +696,19 @@ impl<V> ResizingHashMap<V> {
     }
 }
 
-impl<V: Clone> Clone for ResizingHashMap<V> {
+impl<V, S: BuildHasher> ResizingHashMap<u64, V, S> {
+    pub fn max_key(&self) -> u64 {
+        let mut m = 0;
+        for (&k, _) in self.iter() {
+            if k > m {
+                m = k;
+            }
+        }
+        m
+    }
+}
+
+impl<K: Clone, V: Clone, S: Clone> Clone for ResizingHashMap<K, V, S> {
     fn clone(&self) -> Self {
         Self {
             underlying: self.underlying.clone(),
@@ -339,33 +717,33 @@ impl<V: Clone> Clone for ResizingHashMap<V> {
     }
 }
 
-pub struct HashMapIter<'a, V: 'a> {
-    map: &'a ResizingHashMap<V>,
+pub struct HashMapIter<'a, K: 'a, V: 'a, S: 'a = DefaultState> {
+    map: &'a ResizingHashMap<K, V, S>,
     slot_idx: usize,
 }
 
-impl<'a, V: 'a> Iterator for HashMapIter<'a, V> {
-    type Item = (u64, &'a V);
+impl<'a, K: 'a, V: 'a, S: 'a> Iterator for HashMapIter<'a, K, V, S> {
+    type Item = (&'a K, &'a V);
 
     fn next(&mut self) -> Option<Self::Item> {
         while self.slot_idx < self.map.underlying.storage.len() {
             let cur_slot = self.slot_idx;
             self.slot_idx += 1;
             if let HashMapItem::Entry { key, value } = &self.map.underlying.storage[cur_slot] {
-                return Some((*key, value));
+                return Some((key, value));
             }
         }
         return None;
     }
 }
 
-pub struct HashMapIterMut<'a, V: 'a> {
-    map: &'a mut ResizingHashMap<V>,
+pub struct HashMapIterMut<'a, K: 'a, V: 'a, S: 'a = DefaultState> {
+    map: &'a mut ResizingHashMap<K, V, S>,
     slot_idx: usize,
 }
 
-impl<'a, V: 'a> Iterator for HashMapIterMut<'a, V> {
-    type Item = (u64, &'a mut V);
+impl<'a, K: 'a, V: 'a, S: 'a> Iterator for HashMapIterMut<'a, K, V, S> {
+    type Item = (&'a K, &'a mut V);
 
     fn next(&mut self) -> Option<Self::Item> {
         while self.slot_idx < self.map.underlying.storage.len() {
@@ -374,8 +752,10 @@ impl<'a, V: 'a> Iterator for HashMapIterMut<'a, V> {
             if let HashMapItem::Entry { key, ref mut value } = &mut self.map.underlying.storage[cur_slot] {
                 unsafe {
                     // this is safe because we never return the same reference from this iterator
+                    // (and keys are only ever shared)
+                    let elided_key: *const K = key as *const _;
                     let elided: *mut V = value as *mut _;
-                    return Some((*key, &mut *elided));
+                    return Some((&*elided_key, &mut *elided));
                 }
             }
         }
@@ -383,18 +763,18 @@ impl<'a, V: 'a> Iterator for HashMapIterMut<'a, V> {
     }
 }
 
-pub struct HashMapIterOneMut<'a, V: 'a> {
-    map: &'a mut ResizingHashMap<V>,
+pub struct HashMapIterOneMut<'a, K: 'a, V: 'a, S: 'a = DefaultState> {
+    map: &'a mut ResizingHashMap<K, V, S>,
     slot_idx: usize,
 }
 
-impl<'a, V: 'a> HashMapIterOneMut<'a, V> {
+impl<'a, K: 'a, V: 'a, S: 'a> HashMapIterOneMut<'a, K, V, S> {
     // this is now 100% safe, but doesn’t conform to the Iterator interface anymore
     // (which isn’t typically a big deal for mutable iterators)
     // I _think_ that proving the safe-ness of the idiomatic version (with unsafe)
     // is one of the current goals of Prusti; with separation logic, they should be able
     // to unfold the permission to the whole Vec into distinct permissions to all the elements
-    pub fn next<'b>(&'b mut self) -> Option<(u64, &'b mut V)> where 'a: 'b {
+    pub fn next<'b>(&'b mut self) -> Option<(&'b K, &'b mut V)> where 'a: 'b {
         let mut found = None;
         while self.slot_idx < self.map.underlying.storage.len() {
             let cur_slot = self.slot_idx;
@@ -406,7 +786,7 @@ impl<'a, V: 'a> HashMapIterOneMut<'a, V> {
         };
         if let Some(slot_idx) = found {
             if let HashMapItem::Entry { key, ref mut value } = &mut self.map.underlying.storage[slot_idx] {
-                Some((*key, value))
+                Some((&*key, value))
             } else {
                 unreachable!()
             }
@@ -416,19 +796,141 @@ impl<'a, V: 'a> HashMapIterOneMut<'a, V> {
     }
 }
 
-pub struct VeribetrfsHashMapIter<'a, V> {
+pub struct VeribetrfsHashMapIter<'a, K, V> {
     slot_idx: usize,
-    next: Option<(u64, &'a V)>,
+    next: Option<(&'a K, &'a V)>,
 }
 
-impl<'a, V> std::ops::Deref for VeribetrfsHashMapIter<'a, V> {
-    type Target = Option<(u64, &'a V)>;
+impl<'a, K, V> std::ops::Deref for VeribetrfsHashMapIter<'a, K, V> {
+    type Target = Option<(&'a K, &'a V)>;
     
     fn deref(&self) -> &Self::Target {
         &self.next
     }
 }
 
+pub enum Entry<'a, K: 'a, V: 'a, S: 'a = DefaultState> {
+    Occupied(OccupiedEntry<'a, K, V, S>),
+    Vacant(VacantEntry<'a, K, V, S>),
+}
+
+// the slot `probe` found for `key` holds an entry for it
+pub struct OccupiedEntry<'a, K: 'a, V: 'a, S: 'a = DefaultState> {
+    map: &'a mut ResizingHashMap<K, V, S>,
+    // the one passed to `entry`, equal to the stored one
+    key: K,
+    slot_idx: usize,
+}
+
+// the slot `probe` found for `key` is empty or a tombstone
+pub struct VacantEntry<'a, K: 'a, V: 'a, S: 'a = DefaultState> {
+    map: &'a mut ResizingHashMap<K, V, S>,
+    key: K,
+    slot_idx: usize,
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> Entry<'a, K, V, S> {
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(e) => e.key(),
+            Entry::Vacant(e) => e.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(default()),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V where V: Default {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Entry::Occupied(e) = &mut self {
+            f(e.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> OccupiedEntry<'a, K, V, S> {
+    // the stored key
+    pub fn key(&self) -> &K {
+        match &self.map.underlying.storage[self.slot_idx] {
+            HashMapItem::Entry { key, .. } => key,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get(&self) -> &V {
+        match &self.map.underlying.storage[self.slot_idx] {
+            HashMapItem::Entry { value, .. } => value,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        match &mut self.map.underlying.storage[self.slot_idx] {
+            HashMapItem::Entry { value, .. } => value,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        match &mut self.map.underlying.storage[self.slot_idx] {
+            HashMapItem::Entry { value, .. } => value,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        std::mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    // hands back the stored key, the tombstone keeps the (equal) one passed to `entry`
+    pub fn remove_entry(self) -> (K, V) {
+        let OccupiedEntry { map, mut key, slot_idx } = self;
+        let value = map.underlying.empty_slot(slot_idx);
+        if let HashMapItem::Tombstone { key: ref mut stored } = &mut map.underlying.storage[slot_idx] {
+            std::mem::swap(stored, &mut key);
+        }
+        map.removed();
+        (key, value)
+    }
+}
+
+impl<'a, K: Hash + Eq, V, S: BuildHasher> VacantEntry<'a, K, V, S> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        let VacantEntry { map, key, slot_idx } = self;
+        // `entry` made room, so this can't push the table over half full
+        map.underlying.fill_slot(slot_idx, key, value);
+        map.count += 1;
+        match &mut map.underlying.storage[slot_idx] {
+            HashMapItem::Entry { value, .. } => value,
+            _ => unreachable!(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod resizing_hash_map_test {
     use super::*;
@@ -440,7 +942,7 @@ mod resizing_hash_map_test {
             rhm.insert(i, i);
         }
         for i in 0..128 {
-            let v = rhm.get(i);
+            let v = rhm.get(&i);
             if i % 3 == 0 {
                 assert_eq!(v, Some(&i));
             } else {
@@ -449,13 +951,170 @@ mod resizing_hash_map_test {
         }
 
         let iterator = rhm.iter();
-        for (k, v) in iterator {
+        for (&k, v) in iterator {
             assert_eq!(k, *v);
             assert!(k % 3 == 0);
         }
         assert!(rhm.iter().count() == (0..128).filter(|x| x % 3 == 0).count());
     }
 
+    #[test]
+    fn churn_compacts() {
+        let mut rhm = ResizingHashMap::new(1024);
+        for i in 0..100u64 {
+            rhm.insert(i, i);
+        }
+        // mostly tombstones: compacted in place rather than grown
+        for i in 100..100_000u64 {
+            rhm.insert(i, i);
+            rhm.remove(&(i - 100));
+            assert_eq!(rhm.underlying.storage.len(), 1024);
+        }
+        assert_eq!(rhm.count, 100);
+        assert!(rhm.underlying.tombstones < rhm.underlying.count);
+        for i in 99_900..100_000 {
+            assert_eq!(rhm.get(&i), Some(&i));
+        }
+        // mostly live entries: grown
+        for i in 100_000..100_600u64 {
+            rhm.insert(i, i);
+        }
+        assert!(rhm.underlying.storage.len() > 1024);
+        assert_eq!(rhm.iter().count(), 700);
+    }
+
+    #[test]
+    fn shrinks() {
+        let mut rhm = ResizingHashMap::new(1024);
+        for i in 0..100_000u64 {
+            rhm.insert(i, i);
+        }
+        let grown = rhm.underlying.storage.len();
+        assert!(grown >= 200_000);
+
+        // no shrinking until a resize would halve the table
+        for i in 0..50_000u64 {
+            assert_eq!(rhm.remove_and_get(&i), Some(i));
+        }
+        assert_eq!(rhm.underlying.storage.len(), grown);
+        for i in 50_000..100_000u64 {
+            assert_eq!(rhm.remove_and_get(&i), Some(i));
+        }
+        // as small as it gets: shrinking again wouldn't halve it
+        assert!(rhm.underlying.storage.len() < 2 * ResizingHashMap::<u64, u64>::realloc_size(0));
+        assert_eq!(rhm.iter().count(), 0);
+
+        // hysteresis: going back and forth across a resize doesn't resize every time
+        let size = rhm.underlying.storage.len();
+        let mut i = 0u64;
+        while rhm.underlying.storage.len() == size {
+            rhm.insert(i, i);
+            i += 1;
+        }
+        let grown = rhm.underlying.storage.len();
+        for j in 0..1000 {
+            rhm.remove(&(i - 1 + j));
+            rhm.insert(i + j, i + j);
+            assert_eq!(rhm.underlying.storage.len(), grown);
+        }
+    }
+
+    #[test]
+    fn capacity() {
+        let mut rhm = ResizingHashMap::with_capacity(1000);
+        let size = rhm.underlying.storage.len();
+        for i in 0..1000u64 {
+            rhm.insert(i, i);
+        }
+        assert_eq!(rhm.underlying.storage.len(), size);
+
+        rhm.reserve(500);
+        let size = rhm.underlying.storage.len();
+        for i in 1000..1500u64 {
+            rhm.insert(i, i);
+        }
+        assert_eq!(rhm.underlying.storage.len(), size);
+
+        for i in 0..1400u64 {
+            rhm.remove(&i);
+        }
+        rhm.shrink_to_fit();
+        assert_eq!(rhm.underlying.storage.len(), 200);
+        assert_eq!(rhm.underlying.tombstones, 0);
+        for i in 1400..1500u64 {
+            assert_eq!(rhm.get(&i), Some(&i));
+        }
+        rhm.insert(1500, 1500);
+        assert_eq!(rhm.iter().count(), 101);
+    }
+
+    #[test]
+    fn string_keys() {
+        let mut rhm: ResizingHashMap<String, usize> = ResizingHashMap::new(16);
+        // enough to resize a few times
+        for i in 0..1000 {
+            rhm.insert(i.to_string(), i);
+        }
+        for i in 0..1000 {
+            assert_eq!(rhm.get(i.to_string().as_str()), Some(&i));
+        }
+        for i in (0..1000).filter(|x| x % 3 == 0) {
+            assert_eq!(rhm.remove_and_get(i.to_string().as_str()), Some(i));
+        }
+        assert_eq!(rhm.get("0"), None);
+        assert_eq!(rhm.get("1"), Some(&1));
+        for (k, v) in rhm.iter() {
+            assert_eq!(k.parse::<usize>().unwrap(), *v);
+        }
+        assert_eq!(rhm.iter().count(), (0..1000).filter(|x| x % 3 != 0).count());
+    }
+
+    #[test]
+    fn entry() {
+        let mut rhm: ResizingHashMap<String, usize> = ResizingHashMap::new(16);
+        // enough words to resize a few times, each seen i % 4 + 1 times
+        for round in 0..4 {
+            for i in (0..1000).filter(|i| i % 4 >= round) {
+                *rhm.entry(i.to_string()).or_insert(0) += 1;
+            }
+        }
+        for i in 0..1000 {
+            assert_eq!(rhm.get(i.to_string().as_str()), Some(&(i % 4 + 1)));
+        }
+        assert_eq!(rhm.count, 1000);
+
+        rhm.entry("1".to_string()).and_modify(|v| *v *= 10).or_insert_with(|| panic!("present"));
+        assert_eq!(rhm.get("1"), Some(&20));
+        rhm.entry("new".to_string()).and_modify(|_| panic!("absent")).or_insert(7);
+        assert_eq!(rhm.get("new"), Some(&7));
+        assert_eq!(*rhm.entry("default".to_string()).or_default(), 0);
+
+        match rhm.entry("2".to_string()) {
+            Entry::Occupied(mut e) => {
+                assert_eq!(e.insert(30), 3);
+                assert_eq!(e.remove_entry(), ("2".to_string(), 30));
+            }
+            Entry::Vacant(_) => panic!("2 is there"),
+        }
+        assert_eq!(rhm.get("2"), None);
+        let tombstones = rhm.underlying.tombstones;
+        match rhm.entry("2".to_string()) {
+            // back in its own tombstone
+            Entry::Vacant(e) => { e.insert(2); }
+            Entry::Occupied(_) => panic!("2 is gone"),
+        }
+        assert_eq!(rhm.underlying.tombstones, tombstones - 1);
+
+        // removing through entries shrinks like `remove` does
+        for i in (0..1000).filter(|i| *i != 2) {
+            if let Entry::Occupied(e) = rhm.entry(i.to_string()) {
+                e.remove();
+            }
+        }
+        assert_eq!(rhm.count, 3);
+        assert!(rhm.underlying.storage.len() < 2 * ResizingHashMap::<String, usize>::realloc_size(0));
+    }
+
     // no clone
     struct LinearV { value: u64 }
 
@@ -466,7 +1125,7 @@ mod resizing_hash_map_test {
             rhm.insert(i, LinearV { value: i });
         }
         for i in 0..128 {
-            let v = rhm.get(i);
+            let v = rhm.get(&i);
             if i % 3 == 0 {
                 match v {
                     Some(LinearV { value }) => assert_eq!(*value, i),
@@ -478,7 +1137,7 @@ mod resizing_hash_map_test {
         }
 
         let mut iterator = rhm.iter();
-        while let Some((k, v)) = iterator.next() {
+        while let Some((&k, v)) = iterator.next() {
             assert_eq!(k, v.value);
             assert!(k % 3 == 0);
         }
@@ -490,7 +1149,7 @@ mod resizing_hash_map_test {
         }
 
         let mut iterator = rhm.iter();
-        while let Some((k, v)) = iterator.next() {
+        while let Some((&k, v)) = iterator.next() {
             assert_eq!(k * 2, v.value);
             assert!(k % 3 == 0);
         }
@@ -498,7 +1157,7 @@ mod resizing_hash_map_test {
         let mut v_iterator = rhm.veribetrfs_iter_start();
         use std::ops::Deref;
         let mut count = 0;
-        while let Some((k, ref v)) = v_iterator.deref() {
+        while let Some((&k, v)) = *v_iterator.deref() {
             count += 1;
             assert_eq!(k * 2, v.value);
             assert!(k % 3 == 0);
@@ -513,14 +1172,14 @@ mod resizing_hash_map_test {
         }
 
         let mut iterator = rhm.iter();
-        while let Some((k, v)) = iterator.next() {
+        while let Some((&k, v)) = iterator.next() {
             assert_eq!(k, v.value);
             assert!(k % 3 == 0);
         }
 
         {
             let mut fancy_mut_iterator = rhm.fancy_iter_mut();
-            while let Some((k, ref mut v)) = fancy_mut_iterator.next() {
+            while let Some((&k, ref mut v)) = fancy_mut_iterator.next() {
                 (*v).value *= 2;
                 assert_eq!(k * 2, v.value);
             }